@@ -0,0 +1,223 @@
+use core::convert::TryFrom;
+use core::fmt;
+use core::str::FromStr;
+
+/// The largest number of decimal places a `Fixed` may have.
+pub const MAX_SCALE: u32 = 18;
+
+/// A decimal number stored as an integer mantissa and a number of decimal places, so that
+/// `Fixed::new(12345, 2)` is 123.45. Arithmetic is done on integers only, making it safe for
+/// aggregating prices on chain, where floating-point results may differ between validators.
+///
+/// Results take the larger scale of the two operands, rounding half away from zero, and are
+/// None if they do not fit in an `i64` mantissa. Equality compares the representation, so
+/// 1.0 and 1.00 are not equal; rescale both sides first to compare values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fixed {
+    mantissa: i64,
+    scale: u32,
+}
+
+/// The error returned when parsing a `Fixed` from a string fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseFixedError;
+
+impl fmt::Display for ParseFixedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid fixed-point decimal")
+    }
+}
+
+fn pow10(exp: u32) -> i128 {
+    10i128.pow(exp)
+}
+
+// Divides rounding half away from zero. `d` must not be zero.
+fn round_div(n: i128, d: i128) -> i128 {
+    let (q, r) = (n / d, n % d);
+    if 2 * r.abs() >= d.abs() {
+        q + n.signum() * d.signum()
+    } else {
+        q
+    }
+}
+
+impl Fixed {
+    /// Returns the number `mantissa / 10^scale`, or None if `scale` exceeds `MAX_SCALE`.
+    pub fn new(mantissa: i64, scale: u32) -> Option<Self> {
+        if scale > MAX_SCALE {
+            return None;
+        }
+        Some(Fixed { mantissa, scale })
+    }
+
+    pub fn mantissa(&self) -> i64 {
+        self.mantissa
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    fn from_wide(mantissa: i128, scale: u32) -> Option<Self> {
+        Some(Fixed { mantissa: i64::try_from(mantissa).ok()?, scale })
+    }
+
+    // Returns the mantissa of the same value with `scale` decimal places, which must not be
+    // fewer than the current ones.
+    fn widen(&self, scale: u32) -> i128 {
+        self.mantissa as i128 * pow10(scale - self.scale)
+    }
+
+    /// Returns the same value with `scale` decimal places, rounding half away from zero when
+    /// dropping places.
+    pub fn rescale(&self, scale: u32) -> Option<Self> {
+        if scale > MAX_SCALE {
+            return None;
+        }
+        if scale >= self.scale {
+            Self::from_wide(self.widen(scale), scale)
+        } else {
+            Self::from_wide(round_div(self.mantissa as i128, pow10(self.scale - scale)), scale)
+        }
+    }
+
+    pub fn checked_add(&self, rhs: &Self) -> Option<Self> {
+        let scale = self.scale.max(rhs.scale);
+        Self::from_wide(self.widen(scale) + rhs.widen(scale), scale)
+    }
+
+    pub fn checked_sub(&self, rhs: &Self) -> Option<Self> {
+        let scale = self.scale.max(rhs.scale);
+        Self::from_wide(self.widen(scale) - rhs.widen(scale), scale)
+    }
+
+    pub fn checked_mul(&self, rhs: &Self) -> Option<Self> {
+        let scale = self.scale.max(rhs.scale);
+        // The product of two i64 mantissas always fits in an i128.
+        let product = self.mantissa as i128 * rhs.mantissa as i128;
+        Self::from_wide(round_div(product, pow10(self.scale + rhs.scale - scale)), scale)
+    }
+
+    /// Returns None if `rhs` is zero, on top of when the result does not fit.
+    pub fn checked_div(&self, rhs: &Self) -> Option<Self> {
+        if rhs.mantissa == 0 {
+            return None;
+        }
+        let scale = self.scale.max(rhs.scale);
+        let numerator =
+            (self.mantissa as i128).checked_mul(pow10(scale + rhs.scale - self.scale))?;
+        Self::from_wide(round_div(numerator, rhs.mantissa as i128), scale)
+    }
+}
+
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let abs = (self.mantissa as i128).abs();
+        if self.scale == 0 {
+            return write!(f, "{}{}", sign, abs);
+        }
+        let unit = pow10(self.scale);
+        write!(f, "{}{}.{:0width$}", sign, abs / unit, abs % unit, width = self.scale as usize)
+    }
+}
+
+impl FromStr for Fixed {
+    type Err = ParseFixedError;
+
+    /// Parses a plain decimal such as `-12.345`, keeping as many decimal places as it has.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let (int, frac) = match digits.find('.') {
+            Some(idx) => (&digits[..idx], &digits[idx + 1..]),
+            None => (digits, ""),
+        };
+        if int.is_empty() || !int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit()) {
+            return Err(ParseFixedError);
+        }
+        if digits.contains('.') && frac.is_empty() {
+            return Err(ParseFixedError);
+        }
+        let scale = u32::try_from(frac.len()).map_err(|_| ParseFixedError)?;
+        if scale > MAX_SCALE {
+            return Err(ParseFixedError);
+        }
+        let mut mantissa: i64 = 0;
+        for b in int.bytes().chain(frac.bytes()) {
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|m| m.checked_add((b - b'0') as i64))
+                .ok_or(ParseFixedError)?;
+        }
+        Ok(Fixed { mantissa: if negative { -mantissa } else { mantissa }, scale })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed(s: &str) -> Fixed {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(fixed("123.45"), Fixed::new(12345, 2).unwrap());
+        assert_eq!(fixed("-0.05"), Fixed::new(-5, 2).unwrap());
+        assert_eq!(fixed("42"), Fixed::new(42, 0).unwrap());
+        assert_eq!(fixed("-0.05").to_string(), "-0.05");
+        assert_eq!(fixed("7.000").to_string(), "7.000");
+        assert_eq!(fixed("42").to_string(), "42");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for s in ["", "-", ".5", "1.", "1.2.3", "1e5", "+1", "12a", "0.1234567890123456789"] {
+            assert_eq!(s.parse::<Fixed>(), Err(ParseFixedError), "{}", s);
+        }
+        assert_eq!("9223372036854775808".parse::<Fixed>(), Err(ParseFixedError));
+        assert_eq!(Fixed::new(1, 19), None);
+    }
+
+    #[test]
+    fn test_add_sub() {
+        assert_eq!(fixed("1.5").checked_add(&fixed("2.25")), Some(fixed("3.75")));
+        assert_eq!(fixed("1.5").checked_sub(&fixed("2.25")), Some(fixed("-0.75")));
+        assert_eq!(fixed("9223372036854775807").checked_add(&fixed("1")), None);
+        assert_eq!(fixed("-9223372036854775807").checked_sub(&fixed("2")), None);
+    }
+
+    #[test]
+    fn test_mul_rounding() {
+        assert_eq!(fixed("1.5").checked_mul(&fixed("0.5")), Some(fixed("0.8")));
+        assert_eq!(fixed("-1.5").checked_mul(&fixed("0.5")), Some(fixed("-0.8")));
+        assert_eq!(fixed("1.4").checked_mul(&fixed("0.5")), Some(fixed("0.7")));
+        assert_eq!(fixed("0.12").checked_mul(&fixed("100")), Some(fixed("12.00")));
+        assert_eq!(fixed("4294967296").checked_mul(&fixed("4294967296")), None);
+    }
+
+    #[test]
+    fn test_div_rounding() {
+        assert_eq!(fixed("1.00").checked_div(&fixed("3")), Some(fixed("0.33")));
+        assert_eq!(fixed("2.00").checked_div(&fixed("3")), Some(fixed("0.67")));
+        assert_eq!(fixed("-2.00").checked_div(&fixed("3")), Some(fixed("-0.67")));
+        assert_eq!(fixed("1").checked_div(&fixed("0.25")), Some(fixed("4.00")));
+        assert_eq!(fixed("1").checked_div(&fixed("0.00")), None);
+        assert_eq!(fixed("9223372036854775807").checked_div(&fixed("0.5")), None);
+    }
+
+    #[test]
+    fn test_rescale() {
+        assert_eq!(fixed("1.25").rescale(1), Some(fixed("1.3")));
+        assert_eq!(fixed("-1.25").rescale(1), Some(fixed("-1.3")));
+        assert_eq!(fixed("1.24").rescale(0), Some(fixed("1")));
+        assert_eq!(fixed("1.2").rescale(3), Some(fixed("1.200")));
+        assert_eq!(fixed("92233720368").rescale(9), None);
+        assert_eq!(fixed("1").rescale(19), None);
+    }
+}
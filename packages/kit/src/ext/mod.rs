@@ -2,6 +2,7 @@
 use crate::oei;
 
 pub mod cmp;
+pub mod fixed;
 pub mod stats;
 
 /// Returns an iterator of raw reports for the given external ID with nonzero status.
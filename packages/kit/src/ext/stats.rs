@@ -59,6 +59,25 @@ where
     median_by(data, cmp::fcmp)
 }
 
+/// Returns the average of the given values after discarding the `trim_fraction` smallest and
+/// the `trim_fraction` largest of them, rounding the count discarded at each end down, so
+/// that outliers do not skew the result. Returns None if there are no values or if
+/// `trim_fraction` is not in `[0, 0.5)`.
+pub fn trimmed_mean<T>(values: &[T], trim_fraction: T) -> Option<T>
+where
+    T: Float + NumCast,
+{
+    let half = NumCast::from(0.5).unwrap();
+    if !(trim_fraction >= T::zero() && trim_fraction < half) {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(cmp::fcmp);
+    let len: T = NumCast::from(sorted.len()).unwrap();
+    let trim: usize = NumCast::from((len * trim_fraction).floor()).unwrap();
+    average(sorted[trim..sorted.len() - trim].to_vec())
+}
+
 /// Returns the majority value of the given data set, or None if there is no majority.
 pub fn majority<T>(mut data: Vec<T>) -> Option<T>
 where
@@ -96,6 +115,66 @@ where
     }
 }
 
+/// Returns the most frequent value of the given data set, or None if data is empty. Ties go
+/// to the value that appears first.
+pub fn mode<T>(mut data: Vec<T>) -> Option<T>
+where
+    T: PartialEq,
+{
+    let mut best = None;
+    let mut best_count = 0;
+    for idx in 0..data.len() {
+        let count = data.iter().filter(|v| **v == data[idx]).count();
+        if count > best_count {
+            best = Some(idx);
+            best_count = count;
+        }
+    }
+    best.map(|idx| data.swap_remove(idx))
+}
+
+/// A strategy for combining the values reported by validators into a single result.
+pub trait Aggregator<T> {
+    /// Returns the aggregated value, or None if the values have none.
+    fn aggregate(&self, values: &[T]) -> Option<T>;
+}
+
+/// Aggregates values into their average.
+pub struct Mean;
+
+impl<T: Num + Clone> Aggregator<T> for Mean {
+    fn aggregate(&self, values: &[T]) -> Option<T> {
+        average(values.to_vec())
+    }
+}
+
+/// Aggregates integer values into their median.
+pub struct Median;
+
+impl<T: Ord + Num + NumCast + Clone> Aggregator<T> for Median {
+    fn aggregate(&self, values: &[T]) -> Option<T> {
+        median_integer(values.to_vec())
+    }
+}
+
+/// Aggregates float values into their median, ordering NaN after every number.
+pub struct MedianFloat;
+
+impl<T: Float + NumCast> Aggregator<T> for MedianFloat {
+    fn aggregate(&self, values: &[T]) -> Option<T> {
+        median_float(values.to_vec())
+    }
+}
+
+/// Aggregates values into the most frequent one, see `mode`.
+pub struct Mode;
+
+impl<T: PartialEq + Clone> Aggregator<T> for Mode {
+    fn aggregate(&self, values: &[T]) -> Option<T> {
+        mode(values.to_vec())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -180,6 +259,36 @@ mod tests {
         assert_eq!(median_float(vals), Some(24.6));
     }
 
+    #[test]
+    fn test_trimmed_mean_no_trim() {
+        let vals = [3.0_f32, 2.0, 5.0, 7.0, 2.0, 9.0, 1.0];
+        assert_eq!(trimmed_mean(&vals, 0.0), average(vals.to_vec()));
+        // Too few values to discard any at each end.
+        assert_eq!(trimmed_mean(&vals, 0.1), average(vals.to_vec()));
+    }
+
+    #[test]
+    fn test_trimmed_mean_symmetric() {
+        let vals = [100.0_f32, 2.0, 4.0, 3.0, -50.0, 5.0, 1.0, 6.0, 7.0, 1000.0];
+        // One value at each end goes: -50 and 1000.
+        assert_eq!(
+            trimmed_mean(&vals, 0.1),
+            average(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 100.0])
+        );
+        assert_eq!(trimmed_mean(&vals, 0.2), Some(4.5));
+        assert_eq!(trimmed_mean(&vals, 0.49), Some(4.5));
+    }
+
+    #[test]
+    fn test_trimmed_mean_invalid() {
+        let empty: [f32; 0] = [];
+        assert_eq!(trimmed_mean(&empty, 0.0), None);
+        assert_eq!(trimmed_mean(&empty, 0.25), None);
+        assert_eq!(trimmed_mean(&[1.0_f32, 2.0], 0.5), None);
+        assert_eq!(trimmed_mean(&[1.0_f32, 2.0], -0.1), None);
+        assert_eq!(trimmed_mean(&[1.0_f32, 2.0], f32::NAN), None);
+    }
+
     #[test]
     fn test_majority_int() {
         let vals = vec![1, 2, 3, 1, 3, 1, 1];
@@ -251,4 +360,41 @@ mod tests {
         let vals = vec![String::from("mumu"), String::from("momo")];
         assert_eq!(majority(vals), None);
     }
+    #[test]
+    fn test_mode() {
+        assert_eq!(mode(vec![3, 1, 2, 1, 3, 1]), Some(1));
+        assert_eq!(mode(vec!['a']), Some('a'));
+        assert_eq!(mode::<i64>(vec![]), None);
+    }
+
+    #[test]
+    fn test_mode_tie_goes_to_first() {
+        assert_eq!(mode(vec![2, 5, 5, 2, 7]), Some(2));
+        assert_eq!(mode(vec![5, 2, 2, 5, 7]), Some(5));
+        assert_eq!(mode(vec![String::from("b"), String::from("a")]), Some(String::from("b")));
+    }
+
+    #[test]
+    fn test_aggregators() {
+        let vals = [3, 2, 5, 7, 2, 9, 1];
+        assert_eq!(Mean.aggregate(&vals), Some(4));
+        assert_eq!(Median.aggregate(&vals), Some(3));
+        assert_eq!(Mode.aggregate(&vals), Some(2));
+
+        let vals = [3.0, 2.0, f64::NAN, 7.0];
+        assert_eq!(MedianFloat.aggregate(&vals), Some(5.0));
+        assert_eq!(Mean.aggregate(&vals[..2]), Some(2.5));
+
+        let empty: [i64; 0] = [];
+        assert_eq!(Mean.aggregate(&empty), None);
+        assert_eq!(Median.aggregate(&empty), None);
+        assert_eq!(Mode.aggregate(&empty), None);
+    }
+
+    #[test]
+    fn test_aggregator_as_trait_object() {
+        let strategies: Vec<Box<dyn Aggregator<i64>>> = vec![Box::new(Mean), Box::new(Median)];
+        let results: Vec<_> = strategies.iter().map(|s| s.aggregate(&[1, 2, 9])).collect();
+        assert_eq!(results, vec![Some(4), Some(2)]);
+    }
 }
@@ -1,5 +1,13 @@
+use core::convert::TryFrom;
+
 mod raw;
 
+/// Returns the version of the host functions provided by the VM. Imports added later than
+/// the version a script targets must only be used after checking this value.
+pub fn get_abi_version() -> i64 {
+    unsafe { raw::get_abi_version() }
+}
+
 /// Returns the number of validators to asked to report data from raw requests.
 pub fn get_ask_count() -> i64 {
     unsafe { raw::get_ask_count() }
@@ -27,6 +35,82 @@ pub fn get_ans_count() -> i64 {
     unsafe { raw::get_ans_count() }
 }
 
+/// The error returned by the typed getters below when the VM hands out a value their type
+/// cannot hold, such as a negative count. It carries the raw value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OeiError(pub i64);
+
+fn count(value: i64) -> Result<u32, OeiError> {
+    u32::try_from(value).map_err(|_| OeiError(value))
+}
+
+/// Same as `get_ask_count`, as a count. Panics if the VM reports a value that is not one.
+///
+/// The typed getters save scripts from handling raw `i64`s, e.g. in an `execute` body:
+///
+/// ```no_run
+/// use owasm_kit::oei;
+///
+/// fn execute() {
+///     let reports = oei::ans_count().unwrap();
+///     if reports < oei::min_count() || oei::execute_time().unwrap() < oei::prepare_time() {
+///         return;
+///     }
+///     let ratio = reports * 100 / oei::ask_count();
+///     oei::save_return_data(&ratio.to_le_bytes());
+/// }
+/// ```
+pub fn ask_count() -> u32 {
+    count(get_ask_count()).expect("ask count out of range")
+}
+
+/// Same as `get_min_count`, as a count. Panics if the VM reports a value that is not one.
+pub fn min_count() -> u32 {
+    count(get_min_count()).expect("min count out of range")
+}
+
+/// Same as `get_ans_count`, as a count, failing on a value that is not one. Must only be
+/// called during execution phase.
+pub fn ans_count() -> Result<u32, OeiError> {
+    count(get_ans_count())
+}
+
+/// Same as `get_prepare_time`.
+pub fn prepare_time() -> i64 {
+    get_prepare_time()
+}
+
+/// Same as `get_execute_time`, failing on a time before the Unix epoch, which no chain
+/// produces. Must only be called during execution phase.
+pub fn execute_time() -> Result<i64, OeiError> {
+    match get_execute_time() {
+        time if time < 0 => Err(OeiError(time)),
+        time => Ok(time),
+    }
+}
+
+/// Returns the chain's gas price for the oracle request, for scripts adapting their external
+/// data requests to network congestion. Requires ABI version 8, and a chain providing the
+/// price; see `get_abi_version`.
+pub fn get_gas_price() -> i64 {
+    unsafe { raw::get_gas_price() }
+}
+
+/// Returns the IDs of the data sources the oracle script is configured with for the request,
+/// in order. Requires ABI version 9, and a chain providing them; see `get_abi_version`.
+pub fn get_data_source_ids() -> Vec<i64> {
+    unsafe {
+        (0..raw::get_data_source_count()).map(|index| raw::get_data_source_id(index)).collect()
+    }
+}
+
+/// Returns the external data IDs the request asked data for, in the order they were asked in.
+/// Must only be called during execution phase. Requires ABI version 16, and a chain providing
+/// them; see `get_abi_version`.
+pub fn get_external_ids() -> Vec<i64> {
+    unsafe { (0..raw::get_external_id_count()).map(|index| raw::get_external_id(index)).collect() }
+}
+
 /// Returns the raw calldata as specified when the oracle request is submitted.
 pub fn get_calldata() -> Vec<u8> {
     unsafe {
@@ -37,12 +121,95 @@ pub fn get_calldata() -> Vec<u8> {
     }
 }
 
+/// Returns the random seed of the oracle request, derived by the chain from its entropy.
+/// Scripts needing randomness, e.g. for sampling, must derive it deterministically from
+/// this seed, for instance by hashing it with a counter, so every validator gets the same
+/// result.
+pub fn get_random_seed() -> Vec<u8> {
+    unsafe {
+        let mut seed = Vec::with_capacity(raw::get_span_size() as usize);
+        let len = raw::get_random_seed(seed.as_mut_ptr() as i64);
+        seed.set_len(len as usize);
+        seed
+    }
+}
+
+/// Returns the value of the given key in the auxiliary chain state available to the request,
+/// or None if the key is absent. Requires ABI version 11; see `get_abi_version`.
+pub fn get_storage(key: &[u8]) -> Option<Vec<u8>> {
+    unsafe {
+        let mut value = Vec::with_capacity(raw::get_span_size() as usize);
+        let len =
+            raw::get_storage(key.as_ptr() as i64, key.len() as i64, value.as_mut_ptr() as i64);
+        if len < 0 {
+            return None;
+        }
+        value.set_len(len as usize);
+        Some(value)
+    }
+}
+
+/// Sets the value of the given key in a map the VM keeps for the rest of the run, e.g. for a
+/// lookup table keyed by symbol. A run may hold at most 1024 keys, and setting a new key past
+/// that fails the script. Requires ABI version 13; see `get_abi_version`.
+pub fn map_set(key: &[u8], value: &[u8]) {
+    unsafe {
+        raw::map_set(
+            key.as_ptr() as i64,
+            key.len() as i64,
+            value.as_ptr() as i64,
+            value.len() as i64,
+        )
+    }
+}
+
+/// Returns the value last set for the given key with `map_set` during the run, or None if it
+/// was never set. Requires ABI version 13; see `get_abi_version`.
+pub fn map_get(key: &[u8]) -> Option<Vec<u8>> {
+    unsafe {
+        let mut value = Vec::with_capacity(raw::get_span_size() as usize);
+        let len = raw::map_get(key.as_ptr() as i64, key.len() as i64, value.as_mut_ptr() as i64);
+        if len < 0 {
+            return None;
+        }
+        value.set_len(len as usize);
+        Some(value)
+    }
+}
+
+/// Counts one more iteration of a loop, returning the count so far in the run, including this
+/// one. Fails the script once the count goes past the limit the VM runs it with, if any, for
+/// scripts bounding their loops without keeping a counter themselves. Requires ABI version
+/// 15; see `get_abi_version`.
+pub fn tick() -> u64 {
+    unsafe { raw::tick() as u64 }
+}
+
 /// Saves the given data as the result of the oracle execution. Must only be called
 /// during execution phase and must be called exactly once.
 pub fn save_return_data(data: &[u8]) {
     unsafe { raw::set_return_data(data.as_ptr() as i64, data.len() as i64) }
 }
 
+/// Appends the given data to the result of the oracle execution, which the VM saves once
+/// the execution finishes. The total appended data must fit in the maximum return size, which
+/// is the span size unless the chain caps return data separately. Calling
+/// `save_return_data` afterwards discards whatever was appended before, while appending after
+/// it extends the saved data.
+pub fn append_return_data(data: &[u8]) {
+    unsafe { raw::append_return_data(data.as_ptr() as i64, data.len() as i64) }
+}
+
+/// Saves the given failure as the result of the oracle execution instead of trapping, so that
+/// the chain records why the request could not be resolved. The return data becomes the code
+/// as an 8-byte little-endian integer followed by the message, and the VM reports the failure
+/// separately. Must only be called during execution phase, in place of `save_return_data`,
+/// and nothing may be saved or appended after it. Requires ABI version 14; see
+/// `get_abi_version`.
+pub fn fail_with(code: i64, message: &str) {
+    unsafe { raw::fail_with(code, message.as_ptr() as i64, message.len() as i64) }
+}
+
 /// Issues a new raw request to the host environement using the specified data
 /// source ID and calldata, and assigns it to the given external ID. Must only be
 /// called during preparation phase.
@@ -50,6 +217,67 @@ pub fn ask_external_data(eid: i64, did: i64, calldata: &[u8]) {
     unsafe { raw::ask_external_data(eid, did, calldata.as_ptr() as i64, calldata.len() as i64) }
 }
 
+/// Same as `ask_external_data`, but returns the index of the request among those the script
+/// issued so far, counting from zero. Requires ABI version 10; see `get_abi_version`.
+pub fn ask_external_data_indexed(eid: i64, did: i64, calldata: &[u8]) -> i64 {
+    unsafe {
+        raw::ask_external_data_indexed(eid, did, calldata.as_ptr() as i64, calldata.len() as i64)
+    }
+}
+
+/// The status of the data a validator reports for an external data ID, as returned by
+/// `external_data_status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataStatus {
+    /// The validator reported data with zero return status.
+    Available,
+    /// The validator has not reported, which the chain tells with status -1.
+    NotReported,
+    /// The validator reported, but its data source exited with the carried nonzero status.
+    Failed(i64),
+}
+
+impl DataStatus {
+    /// Returns the status for the given raw status code.
+    pub fn from_code(code: i64) -> DataStatus {
+        match code {
+            0 => DataStatus::Available,
+            -1 => DataStatus::NotReported,
+            code => DataStatus::Failed(code),
+        }
+    }
+
+    /// Returns whether the data can be read.
+    pub fn is_available(self) -> bool {
+        self == DataStatus::Available
+    }
+
+    /// Returns whether waiting for the report may still make the data available. A failed
+    /// data source returns the same status when asked again, so it is not worth retrying.
+    pub fn should_retry(self) -> bool {
+        self == DataStatus::NotReported
+    }
+}
+
+/// Same as `get_external_data_status` on the raw imports, as a `DataStatus`. Must only be
+/// called during execution phase.
+pub fn external_data_status(eid: i64, vid: i64) -> DataStatus {
+    DataStatus::from_code(unsafe { raw::get_external_data_status(eid, vid) })
+}
+
+/// Returns whether the given validator reported data for the given external data ID with
+/// zero return status. Must only be called during execution phase.
+pub fn is_available(eid: i64, vid: i64) -> bool {
+    external_data_status(eid, vid).is_available()
+}
+
+/// Returns whether the given validator has not reported yet for the given external data ID,
+/// so that waiting for it may make the data available. Must only be called during execution
+/// phase.
+pub fn should_retry(eid: i64, vid: i64) -> bool {
+    external_data_status(eid, vid).should_retry()
+}
+
 /// Returns the data reported from the given validator index for the given external
 /// data ID. Result is OK if the validator reports data with zero return status, and
 /// Err otherwise. Must only be called during execution phase.
@@ -67,6 +295,114 @@ pub fn get_external_data(eid: i64, vid: i64) -> Result<String, i64> {
     }
 }
 
+/// Same as `get_external_data`, but has the VM write the data into pages it appends to the
+/// memory, instead of into a buffer of the span size, for scripts with little memory to spare.
+/// The pages stay in place after the call. Requires ABI version 12; see `get_abi_version`.
+#[cfg(target_arch = "wasm32")]
+pub fn get_external_data_grow(eid: i64, vid: i64) -> Result<Vec<u8>, i64> {
+    unsafe {
+        let status = raw::get_external_data_status(eid, vid);
+        if status != 0 {
+            return Err(status);
+        }
+        // The allocator grows the memory itself when it needs more, so it never hands out
+        // what lies past the current end.
+        let offset = core::arch::wasm32::memory_size::<0>() * 65536;
+        let len = raw::read_external_data_grow(eid, vid, offset as i64);
+        Ok(core::slice::from_raw_parts(offset as *const u8, len as usize).to_vec())
+    }
+}
+
+/// Returns the data reported for the given external data ID by every validator with zero
+/// return status, in validator index order. Must only be called during execution phase.
+pub fn get_all_external_data(eid: i64) -> Vec<Vec<u8>> {
+    let data = unsafe {
+        let mut data = Vec::with_capacity(raw::get_span_size() as usize);
+        let len = raw::read_all_external_data(eid, data.as_mut_ptr() as i64);
+        data.set_len(len as usize);
+        data
+    };
+    split_reports(&data)
+}
+
+// Splits the output of `read_all_external_data`, where each report is prefixed with its length
+// as an 8-byte little-endian integer. Stops at the first length that does not fit in what is
+// left, rather than trusting it.
+fn split_reports(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut reports = vec![];
+    let mut rest = data;
+    while rest.len() >= 8 {
+        let (len_bytes, tail) = rest.split_at(8);
+        let mut len = [0u8; 8];
+        len.copy_from_slice(len_bytes);
+        let report = usize::try_from(i64::from_le_bytes(len)).ok().and_then(|len| tail.get(..len));
+        match report {
+            Some(report) => {
+                reports.push(report.to_vec());
+                rest = &tail[report.len()..];
+            }
+            None => break,
+        }
+    }
+    reports
+}
+
+/// Returns the data reported with zero return status for each external data ID the request
+/// asked data for by each validator, as `(eid, vid, data)`. The order is deterministic: the IDs
+/// in the order of `get_external_ids`, each with its validators in index order, from zero up to
+/// the ask count. Reports with another status are skipped. Must only be called during execution
+/// phase. Requires ABI version 16; see `get_abi_version`.
+pub fn external_data_iter() -> impl Iterator<Item = (i64, i64, Vec<u8>)> {
+    matrix_iter(get_external_ids(), get_ask_count(), |eid, vid| {
+        get_external_data(eid, vid).map(String::into_bytes)
+    })
+}
+
+// Walks the reports of `read` over the given IDs and the validators below `ask_count`, the
+// way `external_data_iter` does.
+fn matrix_iter<F>(
+    eids: Vec<i64>,
+    ask_count: i64,
+    mut read: F,
+) -> impl Iterator<Item = (i64, i64, Vec<u8>)>
+where
+    F: FnMut(i64, i64) -> Result<Vec<u8>, i64>,
+{
+    eids.into_iter()
+        .flat_map(move |eid| (0..ask_count).map(move |vid| (eid, vid)))
+        .filter_map(move |(eid, vid)| read(eid, vid).ok().map(|data| (eid, vid, data)))
+}
+
+/// Returns the length of the data reported from the given validator index for the given
+/// external data ID, without reading it. Must only be called during execution phase.
+pub fn get_external_data_len(eid: i64, vid: i64) -> i64 {
+    unsafe { raw::peek_external_data_len(eid, vid) }
+}
+
+/// Returns whether the SHA-256 digest of the data reported from the given validator index for
+/// the given external data ID is `expected`, guarding against corrupted reports. Must only be
+/// called during execution phase.
+pub fn verify_external_data_hash(eid: i64, vid: i64, expected: &[u8; 32]) -> bool {
+    unsafe { raw::verify_external_data_hash(eid, vid, expected.as_ptr() as i64) == 1 }
+}
+
+/// Returns `len` bytes of the data reported from the given validator index for the given
+/// external data ID, starting at byte `start`. Result is OK if the validator reports data
+/// with zero return status, and Err otherwise. Must only be called during execution phase.
+pub fn get_external_data_range(eid: i64, vid: i64, start: i64, len: i64) -> Result<Vec<u8>, i64> {
+    unsafe {
+        let status = raw::get_external_data_status(eid, vid);
+        if status != 0 {
+            Err(status)
+        } else {
+            let mut data = Vec::with_capacity(len as usize);
+            let len = raw::read_external_data_range(eid, vid, start, data.as_mut_ptr() as i64, len);
+            data.set_len(len as usize);
+            Ok(data)
+        }
+    }
+}
+
 /// Return the verification result of ecvrf given a pubkey, a vrf proof, and the
 /// corresponding result.
 pub fn ecvrf_verify(y: &[u8], pi: &[u8], alpha: &[u8]) -> Result<bool, u32> {
@@ -85,3 +421,205 @@ pub fn ecvrf_verify(y: &[u8], pi: &[u8], alpha: &[u8]) -> Result<bool, u32> {
         }
     }
 }
+
+/// Sorts the given values in ascending order, using the VM instead of code shipped with the
+/// script.
+pub fn sort_u64(values: &mut [u64]) {
+    unsafe { raw::sort_u64(values.as_mut_ptr() as i64, values.len() as i64) }
+}
+
+/// Returns whether the two given byte strings are equal. The comparison takes the same
+/// time regardless of where the inputs differ, making it safe for checking hashes and MACs.
+pub fn const_time_eq(a: &[u8], b: &[u8]) -> bool {
+    unsafe {
+        raw::const_time_eq(a.as_ptr() as i64, a.len() as i64, b.as_ptr() as i64, b.len() as i64)
+            == 1
+    }
+}
+
+/// Returns the padded base64 encoding of the given data, using the URL-safe alphabet
+/// instead of the standard one if `url_safe` is set.
+pub fn base64_encode(data: &[u8], url_safe: bool) -> Vec<u8> {
+    unsafe {
+        let mut encoded = Vec::with_capacity(data.len().div_ceil(3) * 4);
+        let len = raw::base64_encode(
+            data.as_ptr() as i64,
+            data.len() as i64,
+            encoded.as_mut_ptr() as i64,
+            url_safe as i64,
+        );
+        encoded.set_len(len as usize);
+        encoded
+    }
+}
+
+/// Returns the data decoded from the given base64 input, with or without padding. The
+/// execution fails if the input is not valid base64 in the selected alphabet.
+pub fn base64_decode(data: &[u8], url_safe: bool) -> Vec<u8> {
+    unsafe {
+        // The host writes at most 3 bytes per group of 4 input characters, plus up to 2 bytes
+        // for a final unpadded group, so this capacity always fits the decoded data.
+        let mut decoded = Vec::with_capacity(data.len() / 4 * 3 + 3);
+        let len = raw::base64_decode(
+            data.as_ptr() as i64,
+            data.len() as i64,
+            decoded.as_mut_ptr() as i64,
+            url_safe as i64,
+        );
+        decoded.set_len(len as usize);
+        decoded
+    }
+}
+
+/// Returns the lower-case hex encoding of the given data, which must be at most half the span
+/// size long.
+pub fn hex_encode(data: &[u8]) -> Vec<u8> {
+    unsafe {
+        let mut encoded = Vec::with_capacity(data.len() * 2);
+        let len =
+            raw::hex_encode(data.as_ptr() as i64, data.len() as i64, encoded.as_mut_ptr() as i64);
+        encoded.set_len(len as usize);
+        encoded
+    }
+}
+
+/// Returns the data decoded from the given hex input, in either case and without a `0x`
+/// prefix. Err holds the status: -1 if the input has an odd length, and -2 if it contains a
+/// character that is not a hex digit.
+pub fn hex_decode(data: &[u8]) -> Result<Vec<u8>, i64> {
+    unsafe {
+        let mut decoded = Vec::with_capacity(data.len() / 2);
+        match raw::hex_decode(data.as_ptr() as i64, data.len() as i64, decoded.as_mut_ptr() as i64)
+        {
+            status if status < 0 => Err(status),
+            len => {
+                decoded.set_len(len as usize);
+                Ok(decoded)
+            }
+        }
+    }
+}
+
+fn json_extract(json: &[u8], path: &str) -> Result<[u8; 8], i64> {
+    unsafe {
+        let mut result = [0u8; 8];
+        match raw::json_extract_f64(
+            json.as_ptr() as i64,
+            json.len() as i64,
+            path.as_ptr() as i64,
+            path.len() as i64,
+            result.as_mut_ptr() as i64,
+        ) {
+            0 => Ok(result),
+            status => Err(status),
+        }
+    }
+}
+
+/// Returns the number found at the given dotted path (e.g. `data.0.price`) in the JSON
+/// document. Err holds the status: 1 if the path is missing, 2 if the value is not a number,
+/// 3 if the document is malformed, and 4 if the number does not fit. Must not be used when
+/// the VM runs in deterministic mode; see `json_extract_fixed` instead.
+pub fn json_extract_f64(json: &[u8], path: &str) -> Result<f64, i64> {
+    json_extract(json, path).map(f64::from_le_bytes)
+}
+
+/// Same as `json_extract_f64`, but for VMs running in deterministic mode, where the number
+/// comes back as fixed-point with 9 decimal places, truncated toward zero.
+pub fn json_extract_fixed(json: &[u8], path: &str) -> Result<i64, i64> {
+    json_extract(json, path).map(i64::from_le_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_status() {
+        assert_eq!(DataStatus::from_code(0), DataStatus::Available);
+        assert_eq!(DataStatus::from_code(-1), DataStatus::NotReported);
+        assert_eq!(DataStatus::from_code(1), DataStatus::Failed(1));
+        assert_eq!(DataStatus::from_code(-2), DataStatus::Failed(-2));
+        assert_eq!(DataStatus::from_code(i64::MAX), DataStatus::Failed(i64::MAX));
+
+        // Only available data can be read, and only missing reports are worth waiting for.
+        let decisions = |code| {
+            let status = DataStatus::from_code(code);
+            (status.is_available(), status.should_retry())
+        };
+        assert_eq!(decisions(0), (true, false));
+        assert_eq!(decisions(-1), (false, true));
+        assert_eq!(decisions(1), (false, false));
+        assert_eq!(decisions(126), (false, false));
+        assert_eq!(decisions(i64::MIN), (false, false));
+    }
+
+    #[test]
+    fn test_split_reports() {
+        let report = |data: &[u8]| [&(data.len() as i64).to_le_bytes()[..], data].concat();
+        let data = [report(b"beeb"), report(b""), report(b"42")].concat();
+        assert_eq!(split_reports(&data), vec![b"beeb".to_vec(), vec![], b"42".to_vec()]);
+        assert_eq!(split_reports(&[]), Vec::<Vec<u8>>::new());
+
+        // Malformed lengths end the reports instead of panicking.
+        let longer = [report(b"beeb"), 5_i64.to_le_bytes().to_vec(), b"42".to_vec()].concat();
+        assert_eq!(split_reports(&longer), vec![b"beeb".to_vec()]);
+        let negative = [(-1_i64).to_le_bytes().to_vec(), b"42".to_vec()].concat();
+        assert_eq!(split_reports(&negative), Vec::<Vec<u8>>::new());
+        assert_eq!(split_reports(&u64::MAX.to_le_bytes()), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn test_matrix_iter() {
+        // Validator 1 has no report for external ID 20.
+        let read = |eid: i64, vid: i64| match (eid, vid) {
+            (20, 1) => Err(1),
+            _ => Ok(vec![eid as u8, vid as u8]),
+        };
+        let entries: Vec<_> = matrix_iter(vec![20, 10], 2, read).collect();
+        assert_eq!(entries, vec![(20, 0, vec![20, 0]), (10, 0, vec![10, 0]), (10, 1, vec![10, 1])]);
+
+        assert_eq!(matrix_iter(vec![], 2, read).count(), 0);
+        assert_eq!(matrix_iter(vec![10], 0, read).count(), 0);
+    }
+
+    // Stand-ins for the host functions `external_data_iter` calls, serving a request that
+    // asked external IDs 20 and 10 of two validators, where validator 1 failed on ID 20.
+    #[no_mangle]
+    extern "C" fn get_external_id_count() -> i64 {
+        2
+    }
+    #[no_mangle]
+    extern "C" fn get_external_id(index: i64) -> i64 {
+        [20, 10][index as usize]
+    }
+    #[no_mangle]
+    extern "C" fn get_ask_count() -> i64 {
+        2
+    }
+    #[no_mangle]
+    extern "C" fn get_span_size() -> i64 {
+        2
+    }
+    #[no_mangle]
+    extern "C" fn get_external_data_status(eid: i64, vid: i64) -> i64 {
+        if (eid, vid) == (20, 1) {
+            1
+        } else {
+            0
+        }
+    }
+    #[no_mangle]
+    extern "C" fn read_external_data(eid: i64, vid: i64, offset: i64) -> i64 {
+        let data = [eid as u8, vid as u8];
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), offset as *mut u8, data.len()) };
+        2
+    }
+
+    #[test]
+    fn test_external_data_iter() {
+        assert_eq!(get_external_ids(), vec![20, 10]);
+        let entries: Vec<_> = external_data_iter().collect();
+        assert_eq!(entries, vec![(20, 0, vec![20, 0]), (10, 0, vec![10, 0]), (10, 1, vec![10, 1])]);
+    }
+}
@@ -1,15 +1,35 @@
 extern "C" {
+    pub fn get_abi_version() -> i64;
     pub fn get_span_size() -> i64;
     pub fn get_ask_count() -> i64;
     pub fn get_min_count() -> i64;
     pub fn get_prepare_time() -> i64;
     pub fn get_execute_time() -> i64;
     pub fn get_ans_count() -> i64;
+    pub fn get_gas_price() -> i64;
+    pub fn get_data_source_count() -> i64;
+    pub fn get_data_source_id(index: i64) -> i64;
+    pub fn get_external_id_count() -> i64;
+    pub fn get_external_id(index: i64) -> i64;
     pub fn read_calldata(offset: i64) -> i64;
+    pub fn get_random_seed(offset: i64) -> i64;
     pub fn set_return_data(offset: i64, len: i64);
+    pub fn append_return_data(offset: i64, len: i64);
+    pub fn fail_with(code: i64, offset: i64, len: i64);
+    pub fn tick() -> i64;
     pub fn ask_external_data(eid: i64, did: i64, offset: i64, len: i64);
+    pub fn ask_external_data_indexed(eid: i64, did: i64, offset: i64, len: i64) -> i64;
+    pub fn get_storage(key_offset: i64, key_len: i64, offset: i64) -> i64;
+    pub fn map_set(key_offset: i64, key_len: i64, value_offset: i64, value_len: i64);
+    pub fn map_get(key_offset: i64, key_len: i64, offset: i64) -> i64;
     pub fn get_external_data_status(eid: i64, vid: i64) -> i64;
     pub fn read_external_data(eid: i64, vid: i64, offset: i64) -> i64;
+    #[cfg(target_arch = "wasm32")]
+    pub fn read_external_data_grow(eid: i64, vid: i64, offset: i64) -> i64;
+    pub fn read_all_external_data(eid: i64, offset: i64) -> i64;
+    pub fn peek_external_data_len(eid: i64, vid: i64) -> i64;
+    pub fn verify_external_data_hash(eid: i64, vid: i64, expected_offset: i64) -> i64;
+    pub fn read_external_data_range(eid: i64, vid: i64, start: i64, offset: i64, len: i64) -> i64;
     pub fn ecvrf_verify(
         y_offset: i64,
         y_len: i64,
@@ -18,4 +38,17 @@ extern "C" {
         alpha_offset: i64,
         alpha_len: i64,
     ) -> u32;
+    pub fn sort_u64(offset: i64, count: i64);
+    pub fn const_time_eq(a_offset: i64, a_len: i64, b_offset: i64, b_len: i64) -> i64;
+    pub fn base64_encode(offset: i64, len: i64, out_offset: i64, url_safe: i64) -> i64;
+    pub fn base64_decode(offset: i64, len: i64, out_offset: i64, url_safe: i64) -> i64;
+    pub fn hex_encode(offset: i64, len: i64, out_offset: i64) -> i64;
+    pub fn hex_decode(offset: i64, len: i64, out_offset: i64) -> i64;
+    pub fn json_extract_f64(
+        json_offset: i64,
+        json_len: i64,
+        path_offset: i64,
+        path_len: i64,
+        result_offset: i64,
+    ) -> i64;
 }
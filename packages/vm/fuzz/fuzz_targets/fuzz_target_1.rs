@@ -44,6 +44,9 @@ impl Querier for MockQuerier {
     fn get_external_data(&self, _: i64, _: i64) -> Result<Vec<u8>, Error> {
         Ok(vec![1])
     }
+    fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+        Ok(vec![0xab; 32])
+    }
 }
 
 fn wat2wasm(wat: impl AsRef<[u8]>) -> Vec<u8> {
@@ -235,6 +238,142 @@ fuzz_target!(|data: [u64; 30]| {
             data[22], data[23], data[24], data[25], data[26], data[27], data[28], data[29]
         ),
     ));
+    imported_wat.push((
+        "get_random_seed",
+        format!(
+            r#"(type (func (param i64) (result i64)))
+                (import "env" "get_random_seed" (func (type 0)))
+                (func
+                    (i64.mul (i64.const {}) (i64.const {}))
+                    call 0
+                    drop
+                )"#,
+            data[0], data[1],
+        ),
+    ));
+    imported_wat.push((
+        "const_time_eq",
+        format!(
+            r#"(type (func (param i64 i64 i64 i64) (result i64)))
+                (import "env" "const_time_eq" (func (type 0)))
+                (func
+                    (i64.mul (i64.const {}) (i64.const {}))
+                    (i64.add (i64.const {}) (i64.const {}))
+                    (i64.sub (i64.const {}) (i64.const {}))
+                    (i64.add (i64.const {}) (i64.const 0))
+                    call 0
+                    drop
+                )"#,
+            data[2], data[3], data[4], data[5], data[6], data[7], data[8],
+        ),
+    ));
+    imported_wat.push((
+        "base64_encode",
+        format!(
+            r#"(type (func (param i64 i64 i64 i64) (result i64)))
+                (import "env" "base64_encode" (func (type 0)))
+                (func
+                    (i64.mul (i64.const {}) (i64.const {}))
+                    (i64.add (i64.const {}) (i64.const 0))
+                    (i64.sub (i64.const {}) (i64.const {}))
+                    (i64.const {})
+                    call 0
+                    drop
+                )"#,
+            data[9], data[10], data[11], data[12], data[13], data[14],
+        ),
+    ));
+    imported_wat.push((
+        "base64_decode",
+        format!(
+            r#"(type (func (param i64 i64 i64 i64) (result i64)))
+                (import "env" "base64_decode" (func (type 0)))
+                (func
+                    (i64.add (i64.const {}) (i64.const 0))
+                    (i64.mul (i64.const {}) (i64.const {}))
+                    (i64.sub (i64.const {}) (i64.const {}))
+                    (i64.const {})
+                    call 0
+                    drop
+                )"#,
+            data[15], data[16], data[17], data[18], data[19], data[20],
+        ),
+    ));
+    imported_wat.push((
+        "read_external_data_range",
+        format!(
+            r#"(type (func (param i64 i64 i64 i64 i64) (result i64)))
+                (import "env" "read_external_data_range" (func (type 0)))
+                (func
+                    (i64.add (i64.const {}) (i64.const 0))
+                    (i64.add (i64.const {}) (i64.const 0))
+                    (i64.sub (i64.const {}) (i64.const {}))
+                    (i64.mul (i64.const {}) (i64.const {}))
+                    (i64.add (i64.const {}) (i64.const 0))
+                    call 0
+                    drop
+                )"#,
+            data[21], data[22], data[23], data[24], data[25], data[26], data[27],
+        ),
+    ));
+    imported_wat.push((
+        "json_extract_f64",
+        format!(
+            r#"(type (func (param i64 i64 i64 i64 i64) (result i64)))
+                (import "env" "json_extract_f64" (func (type 0)))
+                (func
+                    (i64.mul (i64.const {}) (i64.const {}))
+                    (i64.add (i64.const {}) (i64.const 0))
+                    (i64.sub (i64.const {}) (i64.const {}))
+                    (i64.add (i64.const {}) (i64.const 0))
+                    (i64.mul (i64.const {}) (i64.const {}))
+                    call 0
+                    drop
+                )"#,
+            data[28], data[29], data[0], data[2], data[4], data[6], data[8], data[10],
+        ),
+    ));
+    imported_wat.push((
+        "append_return_data",
+        format!(
+            r#"(type (func (param i64 i64) (result)))
+                (import "env" "append_return_data" (func (type 0)))
+                (func
+                    (i64.mul (i64.const {}) (i64.const {}))
+                    (i64.sub (i64.const {}) (i64.const {}))
+                    call 0
+                )"#,
+            data[1], data[3], data[5], data[7],
+        ),
+    ));
+    imported_wat.push((
+        "read_all_external_data",
+        format!(
+            r#"(type (func (param i64 i64) (result i64)))
+                (import "env" "read_all_external_data" (func (type 0)))
+                (func
+                    (i64.add (i64.const {}) (i64.const 0))
+                    (i64.mul (i64.const {}) (i64.const {}))
+                    call 0
+                    drop
+                )"#,
+            data[9], data[11], data[13],
+        ),
+    ));
+    imported_wat.push((
+        "peek_external_data_len",
+        format!(
+            r#"(type (func (param i64 i64) (result i64)))
+                (import "env" "peek_external_data_len" (func (type 0)))
+                (func
+                    (i64.add (i64.const {}) (i64.const 0))
+                    (i64.sub (i64.const {}) (i64.const {}))
+                    call 0
+                    drop
+                )"#,
+            data[15], data[17], data[19],
+        ),
+    ));
     for (_func, wat) in &imported_wat {
         // println!("======================");
         // println!("{:?}", func);
@@ -0,0 +1,236 @@
+use crate::error::Error;
+use crate::vm::Querier;
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+/// Same as `Querier`, with every method returning a future. Meant for off-chain simulators
+/// fetching real external data; the chain itself must keep using a plain `Querier`.
+pub trait AsyncQuerier {
+    /// Returns the maximum span size value.
+    fn get_span_size(&self) -> impl Future<Output = i64>;
+    /// Returns user calldata, or returns error from VM runner.
+    fn get_calldata(&self) -> impl Future<Output = Result<Vec<u8>, Error>>;
+    /// Sends the desired return `data` to VM runner, or returns error from VM runner.
+    fn set_return_data(&self, data: &[u8]) -> impl Future<Output = Result<(), Error>>;
+    /// Returns the current "ask count" value.
+    fn get_ask_count(&self) -> impl Future<Output = i64>;
+    /// Returns the current "min count" value.
+    fn get_min_count(&self) -> impl Future<Output = i64>;
+    /// Returns the prepare block time of the request.
+    fn get_prepare_time(&self) -> impl Future<Output = i64>;
+    /// Returns the execute block time of the request, or error from VM runner if called on wrong period.
+    fn get_execute_time(&self) -> impl Future<Output = Result<i64, Error>>;
+    /// Returns the current "ans count" value, or error from VM runner if called on wrong period.
+    fn get_ans_count(&self) -> impl Future<Output = Result<i64, Error>>;
+    /// Issues a new external data request to VM runner, with the specified ids and calldata.
+    fn ask_external_data(
+        &self,
+        eid: i64,
+        did: i64,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), Error>>;
+    /// Returns external data status for data id `eid` from validator index `vid`.
+    fn get_external_data_status(
+        &self,
+        eid: i64,
+        vid: i64,
+    ) -> impl Future<Output = Result<i64, Error>>;
+    /// Returns data span with the data id `eid` from validator index `vid`.
+    fn get_external_data(&self, eid: i64, vid: i64)
+        -> impl Future<Output = Result<Vec<u8>, Error>>;
+    /// Returns the random seed of the request, or error from VM runner.
+    fn get_random_seed(&self) -> impl Future<Output = Result<Vec<u8>, Error>>;
+}
+
+/// Runs futures to completion on behalf of `BlockingQuerier`. Simulators built on an async
+/// runtime implement it by handing the future to their runtime handle, e.g. tokio's
+/// `Handle::block_on`.
+pub trait Executor {
+    fn block_on<F: Future>(&self, future: F) -> F::Output;
+}
+
+/// An executor polling the future on the calling thread, parking it while the future waits.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ThreadExecutor;
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+impl Executor for ThreadExecutor {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+}
+
+/// Adapts an `AsyncQuerier` to the sync `Querier` that host functions call, blocking on each
+/// call with the given executor.
+pub struct BlockingQuerier<A, E> {
+    querier: A,
+    executor: E,
+}
+
+impl<A: AsyncQuerier, E: Executor> BlockingQuerier<A, E> {
+    pub fn new(querier: A, executor: E) -> Self {
+        Self { querier, executor }
+    }
+}
+
+impl<A: AsyncQuerier, E: Executor> Querier for BlockingQuerier<A, E> {
+    fn get_span_size(&self) -> i64 {
+        self.executor.block_on(self.querier.get_span_size())
+    }
+    fn get_calldata(&self) -> Result<Vec<u8>, Error> {
+        self.executor.block_on(self.querier.get_calldata())
+    }
+    fn set_return_data(&self, data: &[u8]) -> Result<(), Error> {
+        self.executor.block_on(self.querier.set_return_data(data))
+    }
+    fn get_ask_count(&self) -> i64 {
+        self.executor.block_on(self.querier.get_ask_count())
+    }
+    fn get_min_count(&self) -> i64 {
+        self.executor.block_on(self.querier.get_min_count())
+    }
+    fn get_prepare_time(&self) -> i64 {
+        self.executor.block_on(self.querier.get_prepare_time())
+    }
+    fn get_execute_time(&self) -> Result<i64, Error> {
+        self.executor.block_on(self.querier.get_execute_time())
+    }
+    fn get_ans_count(&self) -> Result<i64, Error> {
+        self.executor.block_on(self.querier.get_ans_count())
+    }
+    fn ask_external_data(&self, eid: i64, did: i64, data: &[u8]) -> Result<(), Error> {
+        self.executor.block_on(self.querier.ask_external_data(eid, did, data))
+    }
+    fn get_external_data_status(&self, eid: i64, vid: i64) -> Result<i64, Error> {
+        self.executor.block_on(self.querier.get_external_data_status(eid, vid))
+    }
+    fn get_external_data(&self, eid: i64, vid: i64) -> Result<Vec<u8>, Error> {
+        self.executor.block_on(self.querier.get_external_data(eid, vid))
+    }
+    fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+        self.executor.block_on(self.querier.get_random_seed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{Cache, CacheOptions};
+    use crate::calls::run_with_env;
+    use crate::compile::compile;
+    use crate::testing::wat_to_wasm;
+    use crate::vm::Environment;
+
+    use std::pin::Pin;
+
+    // A future that is pending on its first poll and wakes itself from another thread, the
+    // way a future waiting on I/O would.
+    struct Fetch<T>(Option<T>, bool);
+
+    impl<T: Unpin> Future for Fetch<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<T> {
+            if !self.1 {
+                self.1 = true;
+                let waker = context.waker().clone();
+                thread::spawn(move || waker.wake());
+                return Poll::Pending;
+            }
+            Poll::Ready(self.0.take().unwrap())
+        }
+    }
+
+    fn fetch<T>(value: T) -> Fetch<T> {
+        Fetch(Some(value), false)
+    }
+
+    pub struct AsyncMockQuerier {}
+
+    impl AsyncQuerier for AsyncMockQuerier {
+        async fn get_span_size(&self) -> i64 {
+            300
+        }
+        async fn get_calldata(&self) -> Result<Vec<u8>, Error> {
+            Ok(vec![1])
+        }
+        async fn set_return_data(&self, _: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn get_ask_count(&self) -> i64 {
+            10
+        }
+        async fn get_min_count(&self) -> i64 {
+            8
+        }
+        async fn get_prepare_time(&self) -> i64 {
+            100_000
+        }
+        async fn get_execute_time(&self) -> Result<i64, Error> {
+            Ok(100_000)
+        }
+        async fn get_ans_count(&self) -> Result<i64, Error> {
+            Ok(8)
+        }
+        async fn ask_external_data(&self, _: i64, _: i64, _: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn get_external_data_status(&self, _: i64, _: i64) -> Result<i64, Error> {
+            Ok(0)
+        }
+        async fn get_external_data(&self, eid: i64, vid: i64) -> Result<Vec<u8>, Error> {
+            Ok(fetch(format!("price-{}-{}", eid, vid).into_bytes()).await)
+        }
+        async fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+            Ok(vec![0xab; 32])
+        }
+    }
+
+    #[test]
+    fn test_blocking_querier() {
+        let querier = BlockingQuerier::new(AsyncMockQuerier {}, ThreadExecutor);
+        assert_eq!(querier.get_span_size(), 300);
+        assert_eq!(querier.get_external_data(1, 2), Ok(b"price-1-2".to_vec()));
+
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64) (result i64)))
+            (type (func (param i64 i64) (result)))
+            (import "env" "read_external_data" (func (type 0)))
+            (import "env" "set_return_data" (func (type 1)))
+            (func
+              )
+            (func (;"execute": Returns the data of validator 2 for external ID 1;)
+              (call 1 (i64.const 1024) (call 0 (i64.const 1) (i64.const 2) (i64.const 1024)))
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 2))
+            (export "execute" (func 3)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let owasm_env = Environment::new(querier);
+        run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        assert_eq!(owasm_env.finalize().return_data, Some(b"price-1-2".to_vec()));
+    }
+}
@@ -1,10 +1,15 @@
 use std::{
     borrow::BorrowMut,
-    sync::{Arc, RwLock},
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
 };
 
 use crate::checksum::Checksum;
+use crate::compile::compile;
 use crate::error::Error;
+use crate::imports::create_import_object;
+use crate::store::{make_store_with_backend, Backend};
+use crate::vm::{Environment, Querier};
 
 use clru::CLruCache;
 use wasmer::{Instance, Module, Store};
@@ -27,6 +32,16 @@ impl InMemoryCache {
     pub fn load(&mut self, checksum: &Checksum) -> Option<Module> {
         self.modules.get(checksum).cloned()
     }
+
+    /// Returns whether a module is cached, without counting it as used
+    pub fn contains(&self, checksum: &Checksum) -> bool {
+        self.modules.peek(checksum).is_some()
+    }
+
+    /// Returns the checksums of the cached modules, most recently used first
+    pub fn checksums(&self) -> Vec<Checksum> {
+        self.modules.iter().map(|(checksum, _)| *checksum).collect()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -34,10 +49,100 @@ pub struct CacheOptions {
     pub cache_size: u32,
 }
 
+impl CacheOptions {
+    pub fn builder() -> CacheOptionsBuilder {
+        CacheOptionsBuilder::default()
+    }
+}
+
+/// Builds validated `CacheOptions`, rejecting a cache that could never hold a module.
+#[derive(Clone, Debug, Default)]
+pub struct CacheOptionsBuilder {
+    cache_size: u32,
+}
+
+impl CacheOptionsBuilder {
+    /// Sets the maximum number of compiled modules kept in memory.
+    pub fn cache_size(mut self, cache_size: u32) -> Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    pub fn build(self) -> Result<CacheOptions, Error> {
+        if self.cache_size < 1 {
+            return Err(Error::InvalidCacheSize);
+        }
+        Ok(CacheOptions { cache_size: self.cache_size })
+    }
+}
+
 pub struct Cache {
     memory_cache: Arc<RwLock<InMemoryCache>>,
 }
 
+// The module `Cache::self_test` compiles, in binary form so that no WAT parser is needed:
+// (module
+//   (import "env" "get_span_size" (func (result i64)))
+//   (func) (func)
+//   (memory 1)
+//   (export "memory" (memory 0))
+//   (export "prepare" (func 1))
+//   (export "execute" (func 2)))
+const SELF_TEST_WASM: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic and version
+    0x01, 0x08, 0x02, 0x60, 0x00, 0x01, 0x7e, 0x60, 0x00, 0x00, // types
+    0x02, 0x15, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x0d, 0x67, 0x65, 0x74, 0x5f, 0x73, 0x70, 0x61, 0x6e,
+    0x5f, 0x73, 0x69, 0x7a, 0x65, 0x00, 0x00, // imports
+    0x03, 0x03, 0x02, 0x01, 0x01, // functions
+    0x05, 0x03, 0x01, 0x00, 0x01, // memory
+    0x07, 0x1e, 0x03, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x07, 0x70, 0x72, 0x65,
+    0x70, 0x61, 0x72, 0x65, 0x00, 0x01, 0x07, 0x65, 0x78, 0x65, 0x63, 0x75, 0x74, 0x65, 0x00,
+    0x02, // exports
+    0x0a, 0x07, 0x02, 0x02, 0x00, 0x0b, 0x02, 0x00, 0x0b, // code
+];
+
+// Answers nothing, as the self-test module never runs.
+struct SelfTestQuerier;
+
+impl Querier for SelfTestQuerier {
+    fn get_span_size(&self) -> i64 {
+        0
+    }
+    fn get_calldata(&self) -> Result<Vec<u8>, Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+    fn set_return_data(&self, _: &[u8]) -> Result<(), Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+    fn get_ask_count(&self) -> i64 {
+        0
+    }
+    fn get_min_count(&self) -> i64 {
+        0
+    }
+    fn get_prepare_time(&self) -> i64 {
+        0
+    }
+    fn get_execute_time(&self) -> Result<i64, Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+    fn get_ans_count(&self) -> Result<i64, Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+    fn ask_external_data(&self, _: i64, _: i64, _: &[u8]) -> Result<(), Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+    fn get_external_data_status(&self, _: i64, _: i64) -> Result<i64, Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+    fn get_external_data(&self, _: i64, _: i64) -> Result<Vec<u8>, Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+    fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+}
+
 impl Cache {
     pub fn new(options: CacheOptions) -> Self {
         let CacheOptions { cache_size } = options;
@@ -45,15 +150,46 @@ impl Cache {
         Self { memory_cache: Arc::new(RwLock::new(InMemoryCache::new(cache_size))) }
     }
 
+    /// Compiles a tiny built-in script, links it against the host functions and instantiates
+    /// it twice through a cache of its own, once compiling it and once from the cache, without
+    /// running it. Validators can call it at startup to check that the VM works on the machine.
+    pub fn self_test() -> Result<(), Error> {
+        let code = compile(SELF_TEST_WASM)?;
+        let store = make_store_with_backend(Backend::default());
+        let import_object = create_import_object(&store, Environment::new(SelfTestQuerier));
+        let mut cache = Cache::new(CacheOptions { cache_size: 1 });
+        for expect_hit in [false, true] {
+            let (_, is_hit) = cache.get_instance(&code, &store, &import_object)?;
+            if is_hit != expect_hit {
+                return Err(Error::InternalError);
+            }
+        }
+        Ok(())
+    }
+
     fn with_in_memory_cache<C, R>(&mut self, callback: C) -> R
     where
         C: FnOnce(&mut InMemoryCache) -> R,
     {
-        let mut guard = self.memory_cache.as_ref().write().unwrap();
+        let mut guard = self.memory_cache.as_ref().write().unwrap_or_else(PoisonError::into_inner);
         let in_memory_cache = guard.borrow_mut();
         callback(in_memory_cache)
     }
 
+    /// Returns whether the module for the given code is cached. It never compiles the code and
+    /// leaves the eviction order untouched.
+    pub fn is_cached(&self, wasm: &[u8]) -> bool {
+        let checksum = Checksum::generate(wasm);
+        self.memory_cache.read().unwrap_or_else(PoisonError::into_inner).contains(&checksum)
+    }
+
+    /// Returns the SHA-256 hashes of the cached codes, from the most to the least recently
+    /// used, i.e. in reverse eviction order. Like `is_cached`, it leaves that order untouched.
+    pub fn cached_hashes(&self) -> Vec<[u8; 32]> {
+        let in_memory_cache = self.memory_cache.read().unwrap_or_else(PoisonError::into_inner);
+        in_memory_cache.checksums().into_iter().map(Into::into).collect()
+    }
+
     pub fn get_instance(
         &mut self,
         wasm: &[u8],
@@ -64,7 +200,9 @@ impl Cache {
         self.with_in_memory_cache(|in_memory_cache| {
             // lookup cache
             if let Some(module) = in_memory_cache.load(&checksum) {
-                return Ok((Instance::new(&module, &import_object).unwrap(), true));
+                let instance = Instance::new(&module, &import_object)
+                    .map_err(|_| Error::InstantiationError)?;
+                return Ok((instance, true));
             }
 
             // recompile
@@ -77,31 +215,49 @@ impl Cache {
             Ok((instance, false))
         })
     }
+
+    /// Same as `get_instance`, for each of the given codes in order. Each distinct code is
+    /// looked up and compiled at most once per batch, however many times it appears, even if
+    /// the batch holds more distinct codes than the cache does.
+    pub fn get_instances(
+        &mut self,
+        codes: &[&[u8]],
+        store: &Store,
+        import_object: &wasmer::ImportObject,
+    ) -> Vec<Result<Instance, Error>> {
+        let checksums: Vec<Checksum> = codes.iter().map(|code| Checksum::generate(code)).collect();
+        self.with_in_memory_cache(|in_memory_cache| {
+            let mut modules: HashMap<Checksum, Result<Module, Error>> = HashMap::new();
+            codes
+                .iter()
+                .zip(checksums)
+                .map(|(code, checksum)| {
+                    let module = modules.entry(checksum).or_insert_with(|| {
+                        if let Some(module) = in_memory_cache.load(&checksum) {
+                            return Ok(module);
+                        }
+                        let module =
+                            Module::new(store, code).map_err(|_| Error::InstantiationError)?;
+                        in_memory_cache.store(&checksum, module.clone());
+                        Ok(module)
+                    });
+                    let module = module.as_ref().map_err(|err| *err)?;
+                    Instance::new(module, import_object).map_err(|_| Error::InstantiationError)
+                })
+                .collect()
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::{Read, Write};
-    use std::process::Command;
-    use tempfile::NamedTempFile;
+    use crate::testing::wat_to_wasm;
     use wasmer::{imports, Singlepass, Store, Universal};
 
-    fn wat2wasm(wat: impl AsRef<[u8]>) -> Vec<u8> {
-        let mut input_file = NamedTempFile::new().unwrap();
-        let mut output_file = NamedTempFile::new().unwrap();
-        input_file.write_all(wat.as_ref()).unwrap();
-        Command::new("wat2wasm")
-            .args(&[
-                input_file.path().to_str().unwrap(),
-                "-o",
-                output_file.path().to_str().unwrap(),
-            ])
-            .output()
-            .unwrap();
-        let mut wasm = Vec::new();
-        output_file.read_to_end(&mut wasm).unwrap();
-        wasm
+    #[test]
+    fn test_self_test() {
+        assert_eq!(Cache::self_test(), Ok(()));
     }
 
     fn get_instance_without_err(cache: &mut Cache, wasm: &[u8]) -> (wasmer::Instance, bool) {
@@ -118,14 +274,14 @@ mod tests {
     #[test]
     fn test_cache_catch() {
         let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
                 (func $execute (export "execute"))
                 (func $prepare (export "prepare"))
               )"#,
         );
 
-        let wasm2 = wat2wasm(
+        let wasm2 = wat_to_wasm(
             r#"(module
                 (func $execute (export "execute"))
                 (func $prepare (export "prepare"))
@@ -161,10 +317,109 @@ mod tests {
         assert_eq!(ser1, ser2);
     }
 
+    #[test]
+    fn test_get_instances() {
+        let mut cache = Cache::new(CacheOptions { cache_size: 1 });
+        let wasm1 = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+              )"#,
+        );
+        let wasm2 = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+                (func $foo2 (export "foo2"))
+              )"#,
+        );
+        let store = Store::new(&Universal::new(Singlepass::new()).engine());
+        let import_object = imports! {};
+
+        let instances =
+            cache.get_instances(&[&wasm1, &wasm2, &wasm1, b"beeb"], &store, &import_object);
+        assert_eq!(instances.len(), 4);
+        let exports = |instance: &Result<Instance, Error>| -> Vec<String> {
+            instance.as_ref().unwrap().exports.iter().map(|(name, _)| name.clone()).collect()
+        };
+        assert_eq!(exports(&instances[0]), vec!["execute", "prepare"]);
+        assert_eq!(exports(&instances[1]), vec!["execute", "prepare", "foo2"]);
+        assert_eq!(exports(&instances[2]), vec!["execute", "prepare"]);
+        assert_eq!(instances[3].as_ref().err(), Some(&Error::InstantiationError));
+        // The second code evicted the first, which the batch served from its own module.
+        assert_eq!(cache.cached_hashes(), vec![Into::<[u8; 32]>::into(Checksum::generate(&wasm2))]);
+    }
+
+    #[test]
+    fn test_is_cached() {
+        let mut cache = Cache::new(CacheOptions { cache_size: 2 });
+        let wasm1 = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+              )"#,
+        );
+        let wasm2 = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+                (func $foo2 (export "foo2"))
+              )"#,
+        );
+        let wasm3 = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+                (func $foo3 (export "foo3"))
+              )"#,
+        );
+
+        assert!(!cache.is_cached(&wasm1));
+        get_instance_without_err(&mut cache, &wasm1);
+        assert!(cache.is_cached(&wasm1));
+
+        // [2 1]: checking 1 must not make it the most recently used.
+        get_instance_without_err(&mut cache, &wasm2);
+        assert!(cache.is_cached(&wasm1));
+        get_instance_without_err(&mut cache, &wasm3);
+        assert!(!cache.is_cached(&wasm1));
+        assert!(cache.is_cached(&wasm2));
+        assert!(cache.is_cached(&wasm3));
+    }
+
+    #[test]
+    fn test_cached_hashes() {
+        let mut cache = Cache::new(CacheOptions { cache_size: 3 });
+        let wasms: Vec<Vec<u8>> = ["foo1", "foo2", "foo3"]
+            .iter()
+            .map(|name| {
+                wat_to_wasm(format!(
+                    r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+                (func (export "{}"))
+              )"#,
+                    name
+                ))
+            })
+            .collect();
+        let hash = |wasm: &[u8]| -> [u8; 32] { Checksum::generate(wasm).into() };
+
+        assert_eq!(cache.cached_hashes(), Vec::<[u8; 32]>::new());
+        for wasm in &wasms {
+            get_instance_without_err(&mut cache, wasm);
+        }
+        assert_eq!(cache.cached_hashes(), vec![hash(&wasms[2]), hash(&wasms[1]), hash(&wasms[0])]);
+
+        // Using the oldest module makes it the most recent one.
+        get_instance_without_err(&mut cache, &wasms[0]);
+        assert_eq!(cache.cached_hashes(), vec![hash(&wasms[0]), hash(&wasms[2]), hash(&wasms[1])]);
+    }
+
     #[test]
     fn test_cache_size() {
         let mut cache = Cache::new(CacheOptions { cache_size: 2 });
-        let wasm1 = wat2wasm(
+        let wasm1 = wat_to_wasm(
             r#"(module
                 (func $execute (export "execute"))
                 (func $prepare (export "prepare"))
@@ -172,7 +427,7 @@ mod tests {
               )"#,
         );
 
-        let wasm2 = wat2wasm(
+        let wasm2 = wat_to_wasm(
             r#"(module
                 (func $execute (export "execute"))
                 (func $prepare (export "prepare"))
@@ -180,7 +435,7 @@ mod tests {
               )"#,
         );
 
-        let wasm3 = wat2wasm(
+        let wasm3 = wat_to_wasm(
             r#"(module
                 (func $execute (export "execute"))
                 (func $prepare (export "prepare"))
@@ -224,4 +479,16 @@ mod tests {
         let (_, is_hit) = get_instance_without_err(&mut cache, &wasm1);
         assert_eq!(false, is_hit);
     }
+
+    #[test]
+    fn test_cache_options_builder() {
+        let options = CacheOptions::builder().cache_size(10000).build().unwrap();
+        assert_eq!(options.cache_size, 10000);
+
+        assert_eq!(
+            CacheOptions::builder().cache_size(0).build().unwrap_err(),
+            Error::InvalidCacheSize
+        );
+        assert_eq!(CacheOptions::builder().build().unwrap_err(), Error::InvalidCacheSize);
+    }
 }
@@ -1,11 +1,28 @@
 use crate::cache::Cache;
+use crate::compile::{imported_memory_type, instantiation_size};
 use crate::error::Error;
-use crate::imports::create_import_object;
-use crate::store::make_store;
+use crate::imports::{add_imported_memory, create_import_object};
+use crate::instructions::{get_instruction_count, reset_instruction_count};
+use crate::store::{make_counting_store, make_store_with_backend, make_unmetered_store, Backend};
 use crate::vm::{Environment, Querier};
 
-use std::ptr::NonNull;
+use std::panic::{self, AssertUnwindSafe};
+use wasmer::{wasmparser, Instance, Memory, Module, RuntimeError, Store};
 use wasmer_middlewares::metering::{get_remaining_points, MeteringPoints};
+use wasmer_types::TrapCode;
+
+// Flat cost of instantiating a module, on top of the per-byte compilation cost.
+const INSTANTIATION_GAS: u64 = 2_500_000_000;
+// Cost of compiling each byte of code, the same as executing one basic operation.
+const COMPILATION_GAS_PER_BYTE: u64 = 650_000;
+
+/// Returns the floor gas cost of compiling and instantiating the given code, before any of
+/// it runs. A metered run with a lower gas limit is rejected up front.
+pub fn minimum_gas(code: &[u8]) -> Result<u64, Error> {
+    wasmparser::validate(code).map_err(|_| Error::ValidationError)?;
+    let code_len: u64 = code.len().try_into().map_err(|_| Error::ConvertTypeOutOfBound)?;
+    Ok(INSTANTIATION_GAS.saturating_add(code_len.saturating_mul(COMPILATION_GAS_PER_BYTE)))
+}
 
 pub fn run<Q>(
     cache: &mut Cache,
@@ -17,15 +34,385 @@ pub fn run<Q>(
 where
     Q: Querier + 'static,
 {
-    let owasm_env = Environment::new(querier);
-    let store = make_store();
-    let import_object = create_import_object(&store, owasm_env.clone());
+    run_with_env(cache, code, gas_limit, is_prepare, Environment::new(querier))
+}
+
+/// Same as `run`, but the script reads the given calldata instead of asking the querier for
+/// it, so callers with the calldata at hand need not serve it from their querier.
+pub fn run_with_calldata<Q>(
+    cache: &mut Cache,
+    code: &[u8],
+    gas_limit: u64,
+    is_prepare: bool,
+    calldata: Vec<u8>,
+    querier: Q,
+) -> Result<u64, Error>
+where
+    Q: Querier + 'static,
+{
+    run(cache, code, gas_limit, is_prepare, CalldataQuerier { querier, calldata })
+}
+
+/// Same as `run`, but with a caller-provided environment. An unmetered environment ignores
+/// `gas_limit` and reports zero gas used. Its modules bypass the cache, since they are
+/// compiled without metering and must never be served to metered runs.
+pub fn run_with_env<Q>(
+    cache: &mut Cache,
+    code: &[u8],
+    gas_limit: u64,
+    is_prepare: bool,
+    owasm_env: Environment<Q>,
+) -> Result<u64, Error>
+where
+    Q: Querier + 'static,
+{
+    run_and_inspect(cache, None, code, gas_limit, is_prepare, owasm_env, |_| ())
+}
+
+/// Same as `run_with_env`, but never panics. A panic anywhere in the run, such as a lifecycle
+/// bug in the VM or a panicking querier, is caught and reported as `Error::InternalError`, so
+/// a validator can fail the request instead of going down with it.
+pub fn run_safe<Q>(
+    cache: &mut Cache,
+    code: &[u8],
+    gas_limit: u64,
+    is_prepare: bool,
+    owasm_env: Environment<Q>,
+) -> Result<u64, Error>
+where
+    Q: Querier + 'static,
+{
+    // The cache and environment are only ever updated under their locks, so they stay usable
+    // after an unwind; later calls recover the poisoned locks.
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        run_with_env(cache, code, gas_limit, is_prepare, owasm_env)
+    }))
+    .unwrap_or(Err(Error::InternalError))
+}
+
+/// Same as `run_with_env`, but also returns up to `max_len` bytes of the linear memory as it
+/// was when the script finished.
+#[cfg(feature = "debug")]
+pub fn run_with_memory_dump<Q>(
+    cache: &mut Cache,
+    code: &[u8],
+    gas_limit: u64,
+    is_prepare: bool,
+    owasm_env: Environment<Q>,
+    max_len: usize,
+) -> Result<(u64, Vec<u8>), Error>
+where
+    Q: Querier + 'static,
+{
+    let mut dump = vec![];
+    let gas_used = run_and_inspect(cache, None, code, gas_limit, is_prepare, owasm_env, |env| {
+        if let Ok(memory) = env.memory() {
+            dump = memory.view::<u8>().iter().take(max_len).map(|cell| cell.get()).collect();
+        }
+    })?;
+    Ok((gas_used, dump))
+}
+
+// Runs the script, calling `inspect` once the entry point returns, while the environment
+// still points to the instance. Metered runs compile with `metered_store` if given, so that
+// callers running many scripts can share one store, or with a fresh default store otherwise.
+pub(crate) fn run_and_inspect<Q, F>(
+    cache: &mut Cache,
+    metered_store: Option<&Store>,
+    code: &[u8],
+    gas_limit: u64,
+    is_prepare: bool,
+    owasm_env: Environment<Q>,
+    inspect: F,
+) -> Result<u64, Error>
+where
+    Q: Querier + 'static,
+    F: FnOnce(&Environment<Q>),
+{
+    if owasm_env.is_metered() && gas_limit < minimum_gas(code)? {
+        return Err(Error::InsufficientGas);
+    }
+    let instance = instantiate(cache, metered_store, code, &owasm_env)?;
+    // The environment may outlive this call, so it must only point to the instance while the
+    // guard lives.
+    let _bound = owasm_env.bind_instance(&instance);
+    let instantiation_gas = instantiation_gas(code, &owasm_env)?;
+    let result = run_entry(&instance, is_prepare, gas_limit, instantiation_gas, &owasm_env);
+    inspect(&owasm_env);
+    result
+}
+
+/// Runs `prepare` and then, if it succeeds, `execute` on a single instance of the code,
+/// saving the cost of instantiating it a second time. Returns the gas used by each phase.
+///
+/// Gas, return data and the external data read so far are reset between the phases, and the
+/// external data requests of `prepare` stay in the outcome. The querier answers both phases,
+/// so it must track the phase itself, e.g. by switching to execution once its external data
+/// is available. The memory and globals `prepare` leaves behind are still there when
+/// `execute` starts, so this must only be used for scripts whose `execute` does not depend
+/// on starting from a fresh instance.
+pub fn run_prepare_and_execute<Q>(
+    cache: &mut Cache,
+    code: &[u8],
+    prepare_gas_limit: u64,
+    execute_gas_limit: u64,
+    owasm_env: Environment<Q>,
+) -> Result<(u64, u64), Error>
+where
+    Q: Querier + 'static,
+{
+    if owasm_env.is_metered() && prepare_gas_limit.min(execute_gas_limit) < minimum_gas(code)? {
+        return Err(Error::InsufficientGas);
+    }
+    let instance = instantiate(cache, None, code, &owasm_env)?;
+    let _bound = owasm_env.bind_instance(&instance);
+    // The instance is shared, so only `prepare` pays for instantiating it.
+    let instantiation_gas = instantiation_gas(code, &owasm_env)?;
+    run_entry(&instance, true, prepare_gas_limit, instantiation_gas, &owasm_env).and_then(
+        |prepare| {
+            owasm_env.clear_return_data();
+            Ok((prepare, run_entry(&instance, false, execute_gas_limit, 0, &owasm_env)?))
+        },
+    )
+}
+
+// Instantiates the code with host functions bound to `owasm_env`, as described for
+// `run_and_inspect`.
+fn instantiate<Q>(
+    cache: &mut Cache,
+    metered_store: Option<&Store>,
+    code: &[u8],
+    owasm_env: &Environment<Q>,
+) -> Result<Instance, Error>
+where
+    Q: Querier + 'static,
+{
+    let metered = owasm_env.is_metered();
+    let counting = owasm_env.counts_instructions();
+    let store = match metered_store {
+        _ if counting => make_counting_store(metered),
+        Some(store) if metered => store.clone(),
+        _ if metered => make_store_with_backend(Backend::default()),
+        _ => make_unmetered_store(),
+    };
+    let mut import_object = create_import_object(&store, owasm_env.clone());
+    let imported_memory = match imported_memory_type(code)? {
+        Some(memory_type) => {
+            Some(Memory::new(&store, memory_type).map_err(|_| Error::InstantiationError)?)
+        }
+        None => None,
+    };
+    if let Some(memory) = &imported_memory {
+        add_imported_memory(&mut import_object, memory.clone());
+    }
+    owasm_env.set_imported_memory(imported_memory);
+
+    // Counting modules carry an extra global, so they must never be served from the cache.
+    if metered && !counting {
+        Ok(cache.get_instance(code, &store, &import_object)?.0)
+    } else {
+        let module = Module::new(&store, code).map_err(|_| Error::InstantiationError)?;
+        Instance::new(&module, &import_object).map_err(|_| Error::InstantiationError)
+    }
+}
+
+// Returns the gas the environment charges for instantiating the code.
+fn instantiation_gas<Q>(code: &[u8], owasm_env: &Environment<Q>) -> Result<u64, Error>
+where
+    Q: Querier + 'static,
+{
+    let (memory_pages, functions) = instantiation_size(code)?;
+    Ok(owasm_env.get_instantiation_cost().gas(memory_pages, functions))
+}
+
+// Runs one entry point of the instance and records the run in the environment, which must be
+// bound to the instance. `instantiation_gas` is charged before the entry point starts, using
+// up the whole gas limit if the limit does not cover it.
+fn run_entry<Q>(
+    instance: &Instance,
+    is_prepare: bool,
+    gas_limit: u64,
+    instantiation_gas: u64,
+    owasm_env: &Environment<Q>,
+) -> Result<u64, Error>
+where
+    Q: Querier + 'static,
+{
+    let metered = owasm_env.is_metered();
+    owasm_env.try_set_gas_left(gas_limit)?;
+    owasm_env.clear_external_data_cache();
+    owasm_env.clear_map();
+    owasm_env.reset_io_used();
+    owasm_env.reset_ticks();
+    owasm_env.clear_host_gas();
+    reset_instruction_count(instance);
+
+    let charged = owasm_env.decrease_gas_left(instantiation_gas).inspect_err(|_| {
+        let _ = owasm_env.try_set_gas_left(0);
+    });
+    let mut trap_message = None;
+    let result = charged.and_then(|()| {
+        call_entry(instance, is_prepare, metered, gas_limit, |trap| {
+            trap_message = owasm_env.format_trap(trap)
+        })
+    });
+    // The querier is only sent the return data of runs that succeed, but the outcome keeps
+    // whatever the script saved before failing.
+    let return_data = owasm_env.take_return_data();
+    let result = result.and_then(|gas_used| match &return_data {
+        Some(data) => owasm_env
+            .with_querier_from_context(|querier| querier.set_return_data(data))
+            .map(|()| gas_used),
+        None => Ok(gas_used),
+    });
+    if let Some(data) = return_data {
+        owasm_env.record_return_data(data);
+    }
+    let gas_used = match result {
+        Ok(gas_used) => gas_used,
+        Err(_) if metered => gas_limit.saturating_sub(owasm_env.get_gas_left()),
+        Err(_) => 0,
+    };
+    owasm_env.record_run(gas_used, result.err(), trap_message);
+    owasm_env.record_instructions(get_instruction_count(instance));
+    // Observe the final memory size, which the script may have grown since the last host call.
+    let _ = owasm_env.memory();
+    result
+}
+
+// Serves the given calldata instead of asking the querier for it.
+pub(crate) struct CalldataQuerier<Q> {
+    pub(crate) querier: Q,
+    pub(crate) calldata: Vec<u8>,
+}
+
+impl<Q: Querier> Querier for CalldataQuerier<Q> {
+    fn get_span_size(&self) -> i64 {
+        self.querier.get_span_size()
+    }
+    fn get_calldata(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.calldata.clone())
+    }
+    fn set_return_data(&self, data: &[u8]) -> Result<(), Error> {
+        self.querier.set_return_data(data)
+    }
+    fn get_ask_count(&self) -> i64 {
+        self.querier.get_ask_count()
+    }
+    fn get_min_count(&self) -> i64 {
+        self.querier.get_min_count()
+    }
+    fn get_prepare_time(&self) -> i64 {
+        self.querier.get_prepare_time()
+    }
+    fn get_execute_time(&self) -> Result<i64, Error> {
+        self.querier.get_execute_time()
+    }
+    fn get_ans_count(&self) -> Result<i64, Error> {
+        self.querier.get_ans_count()
+    }
+    fn ask_external_data(&self, eid: i64, did: i64, data: &[u8]) -> Result<(), Error> {
+        self.querier.ask_external_data(eid, did, data)
+    }
+    fn get_external_data_status(&self, eid: i64, vid: i64) -> Result<i64, Error> {
+        self.querier.get_external_data_status(eid, vid)
+    }
+    fn get_external_data(&self, eid: i64, vid: i64) -> Result<Vec<u8>, Error> {
+        self.querier.get_external_data(eid, vid)
+    }
+    fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+        self.querier.get_random_seed()
+    }
+    fn get_external_data_size(&self, eid: i64, vid: i64) -> Result<i64, Error> {
+        self.querier.get_external_data_size(eid, vid)
+    }
+    fn get_all_external_data(&self, eid: i64) -> Result<Vec<Vec<u8>>, Error> {
+        self.querier.get_all_external_data(eid)
+    }
+    fn get_gas_price(&self) -> Result<i64, Error> {
+        self.querier.get_gas_price()
+    }
+    fn get_requested_data_sources(&self) -> Result<Vec<i64>, Error> {
+        self.querier.get_requested_data_sources()
+    }
+    fn get_requested_external_ids(&self) -> Result<Vec<i64>, Error> {
+        self.querier.get_requested_external_ids()
+    }
+    fn get_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.querier.get_storage(key)
+    }
+    fn get_max_return_size(&self) -> i64 {
+        self.querier.get_max_return_size()
+    }
+}
+
+/// Resource usage of a successful run.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RunReport {
+    pub gas_used: u64,
+    /// The largest size the memory reached during the run, in Wasm pages.
+    pub peak_memory_pages: u32,
+    /// The Wasm operators the run executed, if the environment counts them; see
+    /// `Environment::set_count_instructions`.
+    pub instructions: Option<u64>,
+}
+
+impl RunReport {
+    /// Returns how this report changed relative to `baseline`, positive meaning more usage.
+    pub fn diff(&self, baseline: &RunReport) -> RunReportDiff {
+        RunReportDiff {
+            gas_used: self.gas_used as i128 - baseline.gas_used as i128,
+            peak_memory_pages: self.peak_memory_pages as i64 - baseline.peak_memory_pages as i64,
+        }
+    }
+}
 
-    let (instance, _) = cache.get_instance(code, &store, &import_object)?;
-    let instance_ptr = NonNull::from(&instance);
-    owasm_env.set_wasmer_instance(Some(instance_ptr));
-    owasm_env.set_gas_left(gas_limit);
+/// The signed change of each field between two run reports.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RunReportDiff {
+    pub gas_used: i128,
+    pub peak_memory_pages: i64,
+}
+
+impl RunReportDiff {
+    /// Returns whether the run got worse: it used more than `threshold` extra gas, or it
+    /// reached a higher peak memory.
+    pub fn regressed(&self, threshold: u64) -> bool {
+        self.gas_used > threshold as i128 || self.peak_memory_pages > 0
+    }
+}
+
+/// Same as `run_with_env`, but also reports the memory high-water mark of the run.
+pub fn run_with_report<Q>(
+    cache: &mut Cache,
+    code: &[u8],
+    gas_limit: u64,
+    is_prepare: bool,
+    owasm_env: Environment<Q>,
+) -> Result<RunReport, Error>
+where
+    Q: Querier + 'static,
+{
+    let gas_used = run_with_env(cache, code, gas_limit, is_prepare, owasm_env.clone())?;
+    Ok(RunReport {
+        gas_used,
+        peak_memory_pages: owasm_env.peak_memory_pages(),
+        instructions: owasm_env.instruction_count(),
+    })
+}
 
+// Calls the entry point, passing the trap to `on_trap` if it traps, whether the trap comes
+// from the script or from a host function.
+fn call_entry<F>(
+    instance: &Instance,
+    is_prepare: bool,
+    metered: bool,
+    gas_limit: u64,
+    on_trap: F,
+) -> Result<u64, Error>
+where
+    F: FnOnce(&RuntimeError),
+{
     // get function and exec
     let entry = if is_prepare { "prepare" } else { "execute" };
     let function = instance
@@ -36,17 +423,29 @@ where
         .map_err(|_| Error::BadEntrySignatureError)?;
 
     function.call().map_err(|runtime_err| {
-        if let Ok(err) = runtime_err.downcast::<Error>() {
-            return err;
+        on_trap(&runtime_err);
+        let runtime_err = match runtime_err.downcast::<Error>() {
+            Ok(err) => return err,
+            Err(runtime_err) => runtime_err,
+        };
+        // The metering middleware traps as unreachable when gas runs out, so check it first.
+        if metered && get_remaining_points(instance) == MeteringPoints::Exhausted {
+            return Error::OutOfGasError;
         }
 
-        match get_remaining_points(&instance) {
-            MeteringPoints::Remaining(_) => Error::RuntimeError,
-            MeteringPoints::Exhausted => Error::OutOfGasError,
+        match runtime_err.to_trap() {
+            Some(TrapCode::IntegerDivisionByZero) => Error::IntegerDivisionByZero,
+            Some(TrapCode::IntegerOverflow) => Error::IntegerOverflow,
+            Some(TrapCode::UnreachableCodeReached) => Error::Unreachable,
+            Some(TrapCode::HeapAccessOutOfBounds) => Error::MemoryAccessOutOfBounds,
+            _ => Error::RuntimeError,
         }
     })?;
 
-    match get_remaining_points(&instance) {
+    if !metered {
+        return Ok(0);
+    }
+    match get_remaining_points(instance) {
         MeteringPoints::Remaining(count) => Ok(gas_limit.saturating_sub(count)),
         MeteringPoints::Exhausted => Err(Error::OutOfGasError),
     }
@@ -55,12 +454,13 @@ where
 #[cfg(test)]
 mod tests {
     use crate::cache::CacheOptions;
+    use crate::testing::wat_to_wasm;
 
     use super::*;
     use crate::compile::compile;
-    use std::io::{Read, Write};
-    use std::process::Command;
-    use tempfile::NamedTempFile;
+    use crate::vm::{ExternalRequest, InstantiationCost, RunOutcome, ScriptFailure};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
 
     pub struct MockQuerier {}
 
@@ -98,28 +498,35 @@ mod tests {
         fn get_external_data(&self, _: i64, _: i64) -> Result<Vec<u8>, Error> {
             Ok(vec![1])
         }
-    }
-
-    fn wat2wasm(wat: impl AsRef<[u8]>) -> Vec<u8> {
-        let mut input_file = NamedTempFile::new().unwrap();
-        let mut output_file = NamedTempFile::new().unwrap();
-        input_file.write_all(wat.as_ref()).unwrap();
-        Command::new("wat2wasm")
-            .args(&[
-                input_file.path().to_str().unwrap(),
-                "-o",
-                output_file.path().to_str().unwrap(),
-            ])
-            .output()
-            .unwrap();
-        let mut wasm = Vec::new();
-        output_file.read_to_end(&mut wasm).unwrap();
-        wasm
+        fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+            Ok(vec![0xab; 32])
+        }
+        fn get_external_data_size(&self, _: i64, _: i64) -> Result<i64, Error> {
+            Ok(4)
+        }
+        fn get_all_external_data(&self, _: i64) -> Result<Vec<Vec<u8>>, Error> {
+            Ok(vec![b"beeb".to_vec(), b"beeb".to_vec()])
+        }
+        fn get_gas_price(&self) -> Result<i64, Error> {
+            Ok(25)
+        }
+        fn get_requested_data_sources(&self) -> Result<Vec<i64>, Error> {
+            Ok(vec![3, 7, 11])
+        }
+        fn get_requested_external_ids(&self) -> Result<Vec<i64>, Error> {
+            Ok(vec![1, 2])
+        }
+        fn get_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+            Ok(Some(key.to_vec()))
+        }
+        fn get_max_return_size(&self) -> i64 {
+            100
+        }
     }
 
     #[test]
     fn test_simple_gas_used() {
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
             (type (func (param i64 i64 i64 i64) (result)))
             (func
@@ -149,7 +556,7 @@ mod tests {
 
     #[test]
     fn test_ask_external_data_gas_used() {
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
                 (type (func (param i64 i64 i64 i64) (result)))
                 (import "env" "ask_external_data" (func (type 0)))
@@ -188,7 +595,7 @@ mod tests {
     #[test]
     #[cfg(not(tarpaulin))]
     fn test_out_of_gas() {
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
             (type (func (param i64 i64 i64 i64) (result)))
             (func
@@ -212,7 +619,1121 @@ mod tests {
         let code = compile(&wasm).unwrap();
         let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
         let querier = MockQuerier {};
-        let out_of_gas_err = run(&mut cache, &code, 10, true, querier).unwrap_err();
+        let gas_limit = minimum_gas(&code).unwrap();
+        let out_of_gas_err = run(&mut cache, &code, gas_limit, true, querier).unwrap_err();
+        assert_eq!(out_of_gas_err, Error::OutOfGasError);
+    }
+
+    #[test]
+    fn test_unmetered_run_never_runs_out_of_gas() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64 i64) (result)))
+            (import "env" "ask_external_data" (func (type 0)))
+            (func
+              (local $idx i32)
+              (i64.const 1)
+              (i64.const 1)
+              (i64.const 1048576)
+              (i64.const 4)
+              call 0
+              (local.set $idx (i32.const 0))
+              (block
+                  (loop
+                    (local.set $idx (local.get $idx) (i32.const 1) (i32.add) )
+                    (br_if 0 (i32.lt_u (local.get $idx) (i32.const 10000000)))
+                  )
+                )
+            )
+            (func (;"execute": Resolves with result "beeb";)
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beeb") (;str = "beeb";)
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let owasm_env = Environment::new_unmetered(MockQuerier {});
+        assert_eq!(Ok(0), run_with_env(&mut cache, &code, 10, true, owasm_env.clone()));
+        assert_eq!(u64::MAX, owasm_env.get_gas_left());
+
+        // The metered run of the same code must not pick up the unmetered module.
+        let gas_limit = minimum_gas(&code).unwrap();
+        let out_of_gas_err = run(&mut cache, &code, gas_limit, true, MockQuerier {}).unwrap_err();
         assert_eq!(out_of_gas_err, Error::OutOfGasError);
     }
+
+    fn run_prepare_body(body: &str) -> Result<u64, Error> {
+        let wasm = wat_to_wasm(format!(
+            r#"(module
+            (func {}
+              )
+            (func (;"execute": Resolves with result "beeb";)
+              )
+            (memory 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+            body
+        ));
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        run(&mut cache, &code, u64::MAX, true, MockQuerier {})
+    }
+
+    #[test]
+    fn test_trap_errors() {
+        assert_eq!(
+            run_prepare_body("(drop (i64.div_s (i64.const 1) (i64.const 0)))"),
+            Err(Error::IntegerDivisionByZero)
+        );
+        assert_eq!(
+            run_prepare_body("(drop (i64.div_s (i64.const -9223372036854775808) (i64.const -1)))"),
+            Err(Error::IntegerOverflow)
+        );
+        assert_eq!(run_prepare_body("unreachable"), Err(Error::Unreachable));
+        assert_eq!(
+            run_prepare_body("(drop (i64.load (i32.const -1)))"),
+            Err(Error::MemoryAccessOutOfBounds)
+        );
+    }
+
+    #[test]
+    fn test_io_budget() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64) (result)))
+            (import "env" "sort_u64" (func (type 0)))
+            (func (;"prepare": Sorts the same 256 bytes 100 times;)
+              (local $idx i32)
+              (local.set $idx (i32.const 0))
+              (block
+                  (loop
+                    (call 0 (i64.const 1024) (i64.const 32))
+                    (local.set $idx (local.get $idx) (i32.const 1) (i32.add) )
+                    (br_if 0 (i32.lt_u (local.get $idx) (i32.const 100)))
+                  )
+                )
+              )
+            (func
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let mut run_with_budget = |budget: Option<u64>| {
+            let owasm_env = Environment::new(MockQuerier {});
+            if let Some(budget) = budget {
+                owasm_env.set_io_budget(budget);
+            }
+            run_with_env(&mut cache, &code, u64::MAX, true, owasm_env)
+        };
+
+        // Each sort reads and writes 256 bytes.
+        assert!(run_with_budget(None).is_ok());
+        assert!(run_with_budget(Some(100 * 512)).is_ok());
+        assert_eq!(run_with_budget(Some(100 * 512 - 1)), Err(Error::IoBudgetExceeded));
+        assert_eq!(run_with_budget(Some(10_000)), Err(Error::IoBudgetExceeded));
+    }
+
+    #[test]
+    fn test_view_forbids_external_data_requests() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64 i64) (result)))
+            (import "env" "ask_external_data" (func (type 0)))
+            (func
+              (call 0 (i64.const 1) (i64.const 2) (i64.const 1048576) (i64.const 4))
+              )
+            (func
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beeb")
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(MockQuerier {});
+        owasm_env.set_view(true);
+        let result = run_with_env(&mut cache, &code, u64::MAX, true, owasm_env.clone());
+        assert_eq!(result, Err(Error::RequestsNotAllowedInView));
+        assert_eq!(owasm_env.finalize().external_requests, vec![]);
+
+        let owasm_env = Environment::new(MockQuerier {});
+        assert!(run_with_env(&mut cache, &code, u64::MAX, true, owasm_env.clone()).is_ok());
+        assert_eq!(owasm_env.finalize().external_requests.len(), 1);
+    }
+
+    #[test]
+    fn test_trap_formatter() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              unreachable)
+            (func
+              )
+            (memory 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(MockQuerier {});
+        owasm_env.set_trap_formatter(Box::new(|trap| match trap.clone().to_trap() {
+            Some(TrapCode::UnreachableCodeReached) => "the script gave up".to_string(),
+            _ => "the script failed".to_string(),
+        }));
+        let result = run_with_env(&mut cache, &code, u64::MAX, true, owasm_env.clone());
+        assert_eq!(result, Err(Error::Unreachable));
+        let outcome = owasm_env.clone().finalize();
+        assert_eq!(outcome.trap, Some(Error::Unreachable));
+        assert_eq!(outcome.trap_message, Some("the script gave up".to_string()));
+
+        // Runs that complete record no message.
+        run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        assert_eq!(owasm_env.finalize().trap_message, None);
+
+        // Without a formatter, traps keep being reported as errors only.
+        let owasm_env = Environment::new(MockQuerier {});
+        let result = run_with_env(&mut cache, &code, u64::MAX, true, owasm_env.clone());
+        assert_eq!(result, Err(Error::Unreachable));
+        assert_eq!(owasm_env.finalize().trap_message, None);
+    }
+
+    #[test]
+    fn test_host_gas_breakdown() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param) (result i64)))
+            (type (func (param i64 i64) (result)))
+            (import "env" "get_span_size" (func (type 0)))
+            (import "env" "set_return_data" (func (type 1)))
+            (func
+              )
+            (func
+              (drop (call 0))
+              (drop (call 0))
+              (call 1 (i64.const 1048576) (i64.const 4))
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beeb")
+            (export "prepare" (func 2))
+            (export "execute" (func 3)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(MockQuerier {});
+        let gas_used = run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        let breakdown = owasm_env.host_gas_breakdown();
+        let mut names: Vec<_> = breakdown.keys().copied().collect();
+        names.sort();
+        assert_eq!(names, ["get_span_size", "set_return_data"]);
+        assert!(breakdown.values().all(|&gas| gas > 0));
+        // Both calls to the same import add up in one entry, at the flat host call cost each.
+        assert_eq!(breakdown["get_span_size"], 2 * 750_000_000);
+        // Reading the return data costs more than the flat cost.
+        assert!(breakdown["set_return_data"] > 750_000_000);
+        assert!(breakdown.values().sum::<u64>() <= gas_used);
+
+        // Unmetered runs charge no gas, so they have nothing to break down.
+        let owasm_env = Environment::new_unmetered(MockQuerier {});
+        run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        assert!(owasm_env.host_gas_breakdown().is_empty());
+    }
+
+    #[test]
+    fn test_context_id() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              )
+            (func
+              unreachable)
+            (memory (export "memory") 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(MockQuerier {});
+        assert_eq!(owasm_env.context_id(), None);
+        owasm_env.set_context_id(42);
+        assert_eq!(owasm_env.context_id(), Some(42));
+        run_with_env(&mut cache, &code, u64::MAX, true, owasm_env.clone()).unwrap();
+        assert_eq!(owasm_env.finalize().context_id, Some(42));
+
+        // Failed runs carry the ID too.
+        let owasm_env = Environment::new(MockQuerier {});
+        owasm_env.set_context_id(43);
+        let result = run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone());
+        assert_eq!(result, Err(Error::Unreachable));
+        let outcome = owasm_env.finalize();
+        assert_eq!(outcome.trap, Some(Error::Unreachable));
+        assert_eq!(outcome.context_id, Some(43));
+
+        let owasm_env = Environment::new(MockQuerier {});
+        run_with_env(&mut cache, &code, u64::MAX, true, owasm_env.clone()).unwrap();
+        assert_eq!(owasm_env.finalize().context_id, None);
+    }
+
+    #[test]
+    fn test_set_then_append_return_data() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64) (result)))
+            (import "env" "set_return_data" (func (type 0)))
+            (import "env" "append_return_data" (func (type 0)))
+            (func
+              )
+            (func (;"execute": Resolves with result "beebbeeb" in two parts;)
+              (call 0 (i64.const 1048576) (i64.const 4))
+              (call 1 (i64.const 1048576) (i64.const 4))
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beeb")
+            (export "prepare" (func 2))
+            (export "execute" (func 3)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(CountingQuerier::default());
+        run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        // The querier is sent the return data once, when the run ends.
+        assert_eq!(
+            owasm_env.with_querier_from_context(|querier| querier
+                .return_data
+                .lock()
+                .unwrap()
+                .clone()),
+            vec![b"beebbeeb".to_vec()]
+        );
+        assert_eq!(owasm_env.finalize().return_data, Some(b"beebbeeb".to_vec()));
+    }
+
+    #[test]
+    fn test_fail_with() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64) (result)))
+            (type (func (param i64 i64) (result)))
+            (import "env" "fail_with" (func (type 0)))
+            (import "env" "append_return_data" (func (type 1)))
+            (func
+              )
+            (func
+              (call 1 (i64.const 1048576) (i64.const 4))
+              (call 0 (i64.const 7) (i64.const 1048580) (i64.const 9))
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beebno quorum")
+            (export "prepare" (func 2))
+            (export "execute" (func 3)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(CountingQuerier::default());
+        run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        let failure = ScriptFailure { code: 7, message: b"no quorum".to_vec() };
+        assert_eq!(
+            owasm_env.with_querier_from_context(|querier| querier
+                .return_data
+                .lock()
+                .unwrap()
+                .clone()),
+            vec![failure.to_return_data()]
+        );
+        let outcome = owasm_env.finalize();
+        // The failure discards the data appended before it and is not a trap.
+        assert_eq!(outcome.trap, None);
+        assert_eq!(outcome.return_data, Some(failure.to_return_data()));
+        assert_eq!(outcome.failure, Some(failure.clone()));
+        let mut expected = 7_i64.to_le_bytes().to_vec();
+        expected.extend_from_slice(b"no quorum");
+        assert_eq!(outcome.return_data, Some(expected));
+
+        // Nothing may be appended after the failure.
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64) (result)))
+            (type (func (param i64 i64) (result)))
+            (import "env" "fail_with" (func (type 0)))
+            (import "env" "append_return_data" (func (type 1)))
+            (func
+              )
+            (func
+              (call 0 (i64.const 7) (i64.const 1048580) (i64.const 9))
+              (call 1 (i64.const 1048576) (i64.const 4))
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beebno quorum")
+            (export "prepare" (func 2))
+            (export "execute" (func 3)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let owasm_env = Environment::new(CountingQuerier::default());
+        assert_eq!(
+            run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()),
+            Err(Error::RepeatSetReturnDataError)
+        );
+        assert!(owasm_env.with_querier_from_context(|querier| querier
+            .return_data
+            .lock()
+            .unwrap()
+            .is_empty()));
+        let outcome = owasm_env.finalize();
+        assert_eq!(outcome.return_data, Some(failure.to_return_data()));
+        assert_eq!(outcome.failure, Some(failure));
+    }
+
+    #[test]
+    fn test_run_with_report_peak_memory() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              (drop (memory.grow (i32.const 3)))
+              )
+            (func (;"execute": Resolves with result "beeb";)
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(MockQuerier {});
+        let report = run_with_report(&mut cache, &code, u64::MAX, true, owasm_env).unwrap();
+        assert!(report.gas_used > 0);
+        assert_eq!(report.peak_memory_pages, 20);
+
+        let owasm_env = Environment::new(MockQuerier {});
+        let report = run_with_report(&mut cache, &code, u64::MAX, false, owasm_env).unwrap();
+        assert_eq!(report.peak_memory_pages, 17);
+    }
+
+    #[test]
+    fn test_run_with_report_peak_memory_with_host_call() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64) (result)))
+            (import "env" "set_return_data" (func (type 0)))
+            (func
+              (drop (memory.grow (i32.const 2)))
+              (call 0 (i64.const 1048576) (i64.const 4))
+              )
+            (func (;"execute": Resolves with result "beeb";)
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beeb")
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(MockQuerier {});
+        let report = run_with_report(&mut cache, &code, u64::MAX, true, owasm_env).unwrap();
+        assert_eq!(report.peak_memory_pages, 19);
+    }
+
+    #[test]
+    fn test_run_with_report_instructions() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              (local i32)
+              (loop
+                (local.set 0 (i32.add (local.get 0) (i32.const 1)))
+                (br_if 0 (i32.lt_u (local.get 0) (i32.const 100))))
+              )
+            (func (;"execute": Resolves with result "beeb";)
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let run = |cache: &mut Cache, count_instructions: bool| {
+            let owasm_env = Environment::new(MockQuerier {});
+            owasm_env.set_count_instructions(count_instructions);
+            run_with_report(cache, &code, u64::MAX, true, owasm_env).unwrap()
+        };
+        let uncounted = run(&mut cache, false);
+        assert_eq!(uncounted.instructions, None);
+
+        let first = run(&mut cache, true);
+        let second = run(&mut cache, true);
+        // Seven operators run in each of the 100 iterations.
+        assert!(first.instructions.unwrap() > 700);
+        assert_eq!(first, second);
+        // Counting never changes the gas charged.
+        assert_eq!(first.gas_used, uncounted.gas_used);
+    }
+
+    #[test]
+    #[cfg(feature = "debug")]
+    fn test_run_with_memory_dump() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              )
+            (func (;"execute": Resolves with result "beeb";)
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beeb") (;str = "beeb";)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(MockQuerier {});
+        let (_, dump) =
+            run_with_memory_dump(&mut cache, &code, u64::MAX, false, owasm_env, 2_000_000).unwrap();
+        assert_eq!(dump.len(), 17 * 65536);
+        assert_eq!(&dump[1048576..1048580], b"beeb");
+
+        let owasm_env = Environment::new(MockQuerier {});
+        let (_, dump) =
+            run_with_memory_dump(&mut cache, &code, u64::MAX, false, owasm_env, 16).unwrap();
+        assert_eq!(dump, vec![0; 16]);
+    }
+
+    #[test]
+    fn test_finalize_outcome() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64 i64) (result)))
+            (type (func (param i64 i64) (result)))
+            (import "env" "ask_external_data" (func (type 0)))
+            (import "env" "set_return_data" (func (type 1)))
+            (func
+              (call 0 (i64.const 1) (i64.const 2) (i64.const 1048576) (i64.const 4))
+              (call 0 (i64.const 3) (i64.const 4) (i64.const 1048576) (i64.const 2))
+              )
+            (func (;"execute": Resolves with result "beeb";)
+              (call 1 (i64.const 1048576) (i64.const 4))
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beeb") (;str = "beeb";)
+            (export "prepare" (func 2))
+            (export "execute" (func 3)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(MockQuerier {});
+        let gas_used = run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        assert_eq!(
+            owasm_env.finalize(),
+            RunOutcome {
+                return_data: Some(b"beeb".to_vec()),
+                gas_used,
+                external_requests: vec![],
+                trap: None,
+                trap_message: None,
+                peak_memory_pages: 17,
+                refundable_gas: 0,
+                failure: None,
+                context_id: None,
+            }
+        );
+
+        let owasm_env = Environment::new(MockQuerier {});
+        run_with_env(&mut cache, &code, u64::MAX, true, owasm_env.clone()).unwrap();
+        let outcome = owasm_env.finalize();
+        assert_eq!(
+            outcome.external_requests,
+            vec![
+                ExternalRequest { eid: 1, did: 2, calldata: b"beeb".to_vec() },
+                ExternalRequest { eid: 3, did: 4, calldata: b"be".to_vec() },
+            ]
+        );
+        assert_eq!(outcome.return_data, None);
+        assert_eq!(outcome.trap, None);
+    }
+
+    #[test]
+    fn test_refund_external_data_gas() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64 i64) (result)))
+            (import "env" "ask_external_data" (func (type 0)))
+            (func
+              (call 0 (i64.const 1) (i64.const 2) (i64.const 1048576) (i64.const 4))
+              (call 0 (i64.const 3) (i64.const 4) (i64.const 1048576) (i64.const 4))
+              )
+            (func
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beeb")
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let owasm_env = Environment::new(MockQuerier {});
+        let gas_used = run_with_env(&mut cache, &code, u64::MAX, true, owasm_env.clone()).unwrap();
+
+        // Each request is charged 750_000_000 for the host call plus 1_006_000_000 for reading
+        // its 4 bytes of calldata. The chain refunds the second one, as the first fetches the
+        // same data.
+        let request_gas = 1_756_000_000;
+        assert_eq!(owasm_env.apply_refund(request_gas), Ok(gas_used - request_gas));
+        assert_eq!(owasm_env.apply_refund(request_gas + 1), Err(Error::InvalidRefund));
+        let outcome = owasm_env.finalize();
+        assert_eq!(outcome.gas_used, gas_used - request_gas);
+        assert_eq!(outcome.refundable_gas, request_gas);
+    }
+
+    #[test]
+    fn test_run_prepare_and_execute() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64 i64) (result)))
+            (type (func (param i64 i64 i64) (result i64)))
+            (type (func (param i64 i64) (result)))
+            (import "env" "ask_external_data" (func (type 0)))
+            (import "env" "read_external_data" (func (type 1)))
+            (import "env" "set_return_data" (func (type 2)))
+            (func
+              (call 0 (i64.const 1) (i64.const 2) (i64.const 1048576) (i64.const 4))
+              )
+            (func (;"execute": Returns the data of validator 0 for external ID 1;)
+              (call 2 (i64.const 1024) (call 1 (i64.const 1) (i64.const 0) (i64.const 1024)))
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beeb")
+            (export "prepare" (func 3))
+            (export "execute" (func 4)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(MockQuerier {});
+        let prepare_gas = run_with_env(&mut cache, &code, u64::MAX, true, owasm_env.clone());
+        let execute_env = Environment::new(MockQuerier {});
+        let execute_gas = run_with_env(&mut cache, &code, u64::MAX, false, execute_env.clone());
+        let two_instances = (owasm_env.finalize(), execute_env.finalize());
+
+        let owasm_env = Environment::new(MockQuerier {});
+        let gas = run_prepare_and_execute(&mut cache, &code, u64::MAX, u64::MAX, owasm_env.clone());
+        assert_eq!(gas, Ok((prepare_gas.unwrap(), execute_gas.unwrap())));
+        let outcome = owasm_env.finalize();
+        assert_eq!(outcome.external_requests, two_instances.0.external_requests);
+        assert_eq!(outcome.return_data, two_instances.1.return_data);
+        assert_eq!(outcome.return_data, Some(vec![1]));
+        assert_eq!(outcome.gas_used, two_instances.1.gas_used);
+    }
+
+    #[test]
+    fn test_imported_memory() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64) (result)))
+            (import "env" "memory" (memory 1))
+            (import "env" "set_return_data" (func (type 0)))
+            (func
+              )
+            (func (;"execute": Resolves with result "beeb";)
+              (call 0 (i64.const 1024) (i64.const 4))
+              )
+            (data (i32.const 1024) "beeb") (;str = "beeb";)
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(MockQuerier {});
+        run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        let outcome = owasm_env.finalize();
+        assert_eq!(outcome.return_data, Some(b"beeb".to_vec()));
+        assert_eq!(outcome.peak_memory_pages, 1);
+    }
+
+    // Behaves like `MockQuerier`, counting the calls to the methods the tests watch and
+    // recording the return data it is sent. Serves `span_size` as the span size if set.
+    #[derive(Default)]
+    pub struct CountingQuerier {
+        span_size: Option<i64>,
+        get_span_size_calls: AtomicUsize,
+        get_external_data_calls: AtomicUsize,
+        return_data: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl Querier for CountingQuerier {
+        fn get_span_size(&self) -> i64 {
+            self.get_span_size_calls.fetch_add(1, Ordering::Relaxed);
+            self.span_size.unwrap_or_else(|| MockQuerier {}.get_span_size())
+        }
+        fn get_calldata(&self) -> Result<Vec<u8>, Error> {
+            MockQuerier {}.get_calldata()
+        }
+        fn set_return_data(&self, data: &[u8]) -> Result<(), Error> {
+            self.return_data.lock().unwrap().push(data.to_vec());
+            MockQuerier {}.set_return_data(data)
+        }
+        fn get_ask_count(&self) -> i64 {
+            MockQuerier {}.get_ask_count()
+        }
+        fn get_min_count(&self) -> i64 {
+            MockQuerier {}.get_min_count()
+        }
+        fn get_prepare_time(&self) -> i64 {
+            MockQuerier {}.get_prepare_time()
+        }
+        fn get_execute_time(&self) -> Result<i64, Error> {
+            MockQuerier {}.get_execute_time()
+        }
+        fn get_ans_count(&self) -> Result<i64, Error> {
+            MockQuerier {}.get_ans_count()
+        }
+        fn ask_external_data(&self, eid: i64, did: i64, data: &[u8]) -> Result<(), Error> {
+            MockQuerier {}.ask_external_data(eid, did, data)
+        }
+        fn get_external_data_status(&self, eid: i64, vid: i64) -> Result<i64, Error> {
+            MockQuerier {}.get_external_data_status(eid, vid)
+        }
+        fn get_external_data(&self, eid: i64, vid: i64) -> Result<Vec<u8>, Error> {
+            self.get_external_data_calls.fetch_add(1, Ordering::Relaxed);
+            MockQuerier {}.get_external_data(eid, vid)
+        }
+        fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+            MockQuerier {}.get_random_seed()
+        }
+        fn get_max_return_size(&self) -> i64 {
+            MockQuerier {}.get_max_return_size()
+        }
+    }
+
+    #[test]
+    fn test_external_data_read_once_per_run() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64) (result i64)))
+            (import "env" "read_external_data" (func (type 0)))
+            (func
+              )
+            (func (;"execute": Reads the same external data twice;)
+              (drop (call 0 (i64.const 1) (i64.const 2) (i64.const 1024)))
+              (drop (call 0 (i64.const 1) (i64.const 2) (i64.const 2048)))
+              (drop (call 0 (i64.const 1) (i64.const 3) (i64.const 2048)))
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let owasm_env = Environment::new(CountingQuerier::default());
+        let calls = || {
+            owasm_env.with_querier_from_context(|querier| {
+                querier.get_external_data_calls.load(Ordering::Relaxed)
+            })
+        };
+
+        run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        assert_eq!(calls(), 2);
+        // The next run with the same environment does not reuse the data.
+        run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        assert_eq!(calls(), 4);
+    }
+
+    #[test]
+    fn test_span_size_read_once_per_run() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param) (result i64)))
+            (type (func (param i64 i64 i64) (result i64)))
+            (import "env" "get_span_size" (func (type 0)))
+            (import "env" "read_external_data" (func (type 1)))
+            (func
+              )
+            (func (;"execute": Sizes its buffers with the span size, then reads into them;)
+              (drop (call 0))
+              (drop (call 0))
+              (drop (call 0))
+              (drop (call 1 (i64.const 1) (i64.const 2) (i64.const 1024)))
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 2))
+            (export "execute" (func 3)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let owasm_env = Environment::new(CountingQuerier::default());
+        let calls = || {
+            owasm_env.with_querier_from_context(|querier| {
+                querier.get_span_size_calls.load(Ordering::Relaxed)
+            })
+        };
+
+        assert_eq!(calls(), 1);
+        run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        assert_eq!(calls(), 1);
+    }
+
+    #[test]
+    fn test_run_with_calldata() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64) (result i64)))
+            (import "env" "read_calldata" (func (type 0)))
+            (func (;"prepare": Traps unless the calldata is "beeb";)
+              (block
+                (br_if 0
+                  (i32.and
+                    (i64.eq (call 0 (i64.const 1024)) (i64.const 4))
+                    (i32.eq (i32.load8_u (i32.const 1027)) (i32.const 98))))
+                unreachable)
+              )
+            (func
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let calldata = b"beeb".to_vec();
+        assert!(
+            run_with_calldata(&mut cache, &code, u64::MAX, true, calldata, MockQuerier {}).is_ok()
+        );
+        assert_eq!(
+            run_with_calldata(&mut cache, &code, u64::MAX, true, b"bee".to_vec(), MockQuerier {}),
+            Err(Error::Unreachable)
+        );
+        assert_eq!(run(&mut cache, &code, u64::MAX, true, MockQuerier {}), Err(Error::Unreachable));
+    }
+
+    #[test]
+    fn test_calldata_querier_forwards_optional_methods() {
+        let querier = CalldataQuerier { querier: MockQuerier {}, calldata: b"beeb".to_vec() };
+        assert_eq!(querier.get_calldata(), Ok(b"beeb".to_vec()));
+        assert_eq!(querier.get_external_data_size(1, 0), Ok(4));
+        assert_eq!(querier.get_all_external_data(1), Ok(vec![b"beeb".to_vec(), b"beeb".to_vec()]));
+        assert_eq!(querier.get_gas_price(), Ok(25));
+        assert_eq!(querier.get_requested_data_sources(), Ok(vec![3, 7, 11]));
+        assert_eq!(querier.get_requested_external_ids(), Ok(vec![1, 2]));
+        assert_eq!(querier.get_storage(b"price"), Ok(Some(b"price".to_vec())));
+        assert_eq!(querier.get_max_return_size(), 100);
+    }
+
+    #[test]
+    fn test_inspect_querier_after_run() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64) (result i64)))
+            (import "env" "read_external_data" (func (type 0)))
+            (func
+              )
+            (func
+              (drop (call 0 (i64.const 1) (i64.const 2) (i64.const 1024)))
+              (drop (call 0 (i64.const 1) (i64.const 3) (i64.const 1024)))
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let owasm_env = Environment::new(CountingQuerier::default());
+        run_with_env(&mut cache, &code, u64::MAX, false, owasm_env.clone()).unwrap();
+        assert_eq!(
+            owasm_env.with_querier_from_context(|querier| {
+                querier.get_external_data_calls.load(Ordering::Relaxed)
+            }),
+            2
+        );
+    }
+
+    #[test]
+    fn test_host_call_multiplier() {
+        let io_heavy = compile(&wat_to_wasm(
+            r#"(module
+            (type (func (param) (result i64)))
+            (import "env" "get_ask_count" (func (type 0)))
+            (func
+              (drop (call 0))
+              (drop (call 0))
+              (drop (call 0))
+              (drop (call 0))
+              )
+            (func
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        ))
+        .unwrap();
+        let compute_heavy = compile(&wat_to_wasm(
+            r#"(module
+            (func
+              (local $idx i32)
+              (local.set $idx (i32.const 0))
+              (block
+                  (loop
+                    (local.set $idx (local.get $idx) (i32.const 1) (i32.add) )
+                    (br_if 0 (i32.lt_u (local.get $idx) (i32.const 1000)))
+                  )
+                )
+              )
+            (func
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        ))
+        .unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let mut gas_used = |code: &[u8], multiplier: u64| {
+            let owasm_env = Environment::new(MockQuerier {});
+            owasm_env.set_host_call_multiplier(multiplier);
+            run_with_env(&mut cache, code, u64::MAX, true, owasm_env).unwrap()
+        };
+
+        // Each of the 4 host calls charges its 750_000_000 flat cost 3 more times.
+        assert_eq!(gas_used(&io_heavy, 3), gas_used(&io_heavy, 0) + 4 * 3 * 750_000_000);
+        assert_eq!(gas_used(&compute_heavy, 3), gas_used(&compute_heavy, 0));
+    }
+
+    #[test]
+    fn test_instantiation_cost() {
+        let module_with_pages = |pages: u32| {
+            compile(&wat_to_wasm(&format!(
+                r#"(module
+                (func
+                  )
+                (func
+                  )
+                (memory (export "memory") {})
+                (export "prepare" (func 0))
+                (export "execute" (func 1)))
+              "#,
+                pages
+            )))
+            .unwrap()
+        };
+        let silly = module_with_pages(100);
+        let large = module_with_pages(512);
+        let cost = InstantiationCost { per_memory_page: 100_000_000, per_function: 1_000 };
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let mut run_with_cost = |code: &[u8], gas_limit: u64, cost: InstantiationCost| {
+            let owasm_env = Environment::new(MockQuerier {});
+            owasm_env.set_instantiation_cost(cost);
+            run_with_env(&mut cache, code, gas_limit, true, owasm_env)
+        };
+
+        let silly_free = run_with_cost(&silly, u64::MAX, InstantiationCost::default()).unwrap();
+        let large_free = run_with_cost(&large, u64::MAX, InstantiationCost::default()).unwrap();
+        assert_eq!(silly_free, large_free);
+        // Compiling adds functions of its own, such as stack height checks for the entry points.
+        let (_, functions) = instantiation_size(&silly).unwrap();
+        assert!(functions > 2);
+        assert_eq!(instantiation_size(&large), Ok((512, functions)));
+        let silly_gas = 100 * 100_000_000 + functions * 1_000;
+        let large_gas = 512 * 100_000_000 + functions * 1_000;
+        assert_eq!(run_with_cost(&silly, u64::MAX, cost), Ok(silly_free + silly_gas));
+        assert_eq!(run_with_cost(&large, u64::MAX, cost), Ok(large_free + large_gas));
+
+        // The charge comes out of the gas limit, failing runs it does not fit in.
+        let gas_limit = large_free + large_gas;
+        assert_eq!(run_with_cost(&large, gas_limit, cost), Ok(gas_limit));
+        assert_eq!(run_with_cost(&large, gas_limit - 1, cost), Err(Error::OutOfGasError));
+        assert_eq!(run_with_cost(&silly, gas_limit - 1, cost), Ok(silly_free + silly_gas));
+    }
+
+    // A querier with a bug, panicking when asked for the prepare time.
+    pub struct PanickingQuerier {}
+
+    impl Querier for PanickingQuerier {
+        fn get_span_size(&self) -> i64 {
+            300
+        }
+        fn get_calldata(&self) -> Result<Vec<u8>, Error> {
+            Ok(vec![1])
+        }
+        fn set_return_data(&self, _: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_ask_count(&self) -> i64 {
+            10
+        }
+        fn get_min_count(&self) -> i64 {
+            8
+        }
+        fn get_prepare_time(&self) -> i64 {
+            panic!("prepare time is not available")
+        }
+        fn get_execute_time(&self) -> Result<i64, Error> {
+            Ok(100_000)
+        }
+        fn get_ans_count(&self) -> Result<i64, Error> {
+            Ok(8)
+        }
+        fn ask_external_data(&self, _: i64, _: i64, _: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_external_data_status(&self, _: i64, _: i64) -> Result<i64, Error> {
+            Ok(1)
+        }
+        fn get_external_data(&self, _: i64, _: i64) -> Result<Vec<u8>, Error> {
+            Ok(vec![1])
+        }
+        fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+            Ok(vec![0xab; 32])
+        }
+    }
+
+    #[test]
+    fn test_run_safe() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param) (result i64)))
+            (import "env" "get_prepare_time" (func (type 0)))
+            (func
+              (drop (call 0))
+              )
+            (func
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let owasm_env = Environment::new(PanickingQuerier {});
+        assert_eq!(
+            run_safe(&mut cache, &code, u64::MAX, true, owasm_env),
+            Err(Error::InternalError)
+        );
+        // The cache keeps working after the panic.
+        let owasm_env = Environment::new(MockQuerier {});
+        assert!(run_safe(&mut cache, &code, u64::MAX, true, owasm_env).is_ok());
+    }
+
+    #[test]
+    fn test_finalize_outcome_trap() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              unreachable
+              )
+            (func (;"execute": Resolves with result "beeb";)
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let owasm_env = Environment::new(MockQuerier {});
+        let err = run_with_env(&mut cache, &code, u64::MAX, true, owasm_env.clone()).unwrap_err();
+        let outcome = owasm_env.finalize();
+        assert_eq!(outcome.trap, Some(err));
+        assert!(outcome.gas_used > 0);
+    }
+
+    #[test]
+    fn test_minimum_gas() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              )
+            (func (;"execute": Resolves with result "beeb";)
+              )
+            (memory 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let floor = minimum_gas(&code).unwrap();
+        assert_eq!(floor, INSTANTIATION_GAS + code.len() as u64 * COMPILATION_GAS_PER_BYTE);
+
+        assert_eq!(
+            run(&mut cache, &code, floor - 1, true, MockQuerier {}),
+            Err(Error::InsufficientGas)
+        );
+        assert!(run(&mut cache, &code, floor, true, MockQuerier {}).is_ok());
+        assert!(run(&mut cache, &code, floor + 1_000_000_000, true, MockQuerier {}).is_ok());
+        // Unmetered runs have no gas limit to check.
+        let owasm_env = Environment::new_unmetered(MockQuerier {});
+        assert_eq!(run_with_env(&mut cache, &code, 0, true, owasm_env), Ok(0));
+
+        assert_eq!(minimum_gas(b"beeb"), Err(Error::ValidationError));
+    }
+
+    #[test]
+    fn test_run_report_diff() {
+        let baseline = RunReport { gas_used: 1_000, peak_memory_pages: 17, instructions: None };
+
+        let improved =
+            RunReport { gas_used: 800, peak_memory_pages: 17, instructions: None }.diff(&baseline);
+        assert_eq!(improved, RunReportDiff { gas_used: -200, peak_memory_pages: 0 });
+        assert!(!improved.regressed(0));
+
+        let regressed = RunReport { gas_used: 1_500, peak_memory_pages: 17, instructions: None }
+            .diff(&baseline);
+        assert_eq!(regressed, RunReportDiff { gas_used: 500, peak_memory_pages: 0 });
+        assert!(regressed.regressed(499));
+        assert!(!regressed.regressed(500));
+        let grown =
+            RunReport { gas_used: 900, peak_memory_pages: 18, instructions: None }.diff(&baseline);
+        assert_eq!(grown, RunReportDiff { gas_used: -100, peak_memory_pages: 1 });
+        assert!(grown.regressed(u64::MAX));
+
+        let unchanged = baseline.diff(&baseline);
+        assert_eq!(unchanged, RunReportDiff { gas_used: 0, peak_memory_pages: 0 });
+        assert!(!unchanged.regressed(0));
+
+        let extreme =
+            RunReport { gas_used: 0, peak_memory_pages: 0, instructions: None }.diff(&RunReport {
+                gas_used: u64::MAX,
+                peak_memory_pages: u32::MAX,
+                instructions: None,
+            });
+        assert_eq!(extreme.gas_used, -(u64::MAX as i128));
+        assert_eq!(extreme.peak_memory_pages, -(u32::MAX as i64));
+    }
 }
@@ -30,6 +30,12 @@ impl From<[u8; 32]> for Checksum {
     }
 }
 
+impl From<Checksum> for [u8; 32] {
+    fn from(checksum: Checksum) -> Self {
+        checksum.0
+    }
+}
+
 impl TryFrom<&[u8]> for Checksum {
     type Error = Error;
 
@@ -1,17 +1,28 @@
+use crate::store::{make_store_with_backend, Backend};
 use crate::Error;
 
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use wasm_instrument::parity_wasm::{
     builder,
-    elements::{deserialize_buffer, serialize, External, MemoryType, Module},
+    elements::{deserialize_buffer, serialize, External, Internal, MemoryType, Module},
 };
-use wasmer::wasmparser;
+use wasmer::{wasmparser, Module as WasmerModule};
 
 // inspired by https://github.com/CosmWasm/cosmwasm/issues/81
 // 512 pages = 32mb
-static MEMORY_LIMIT: u32 = 512; // in pages
+pub(crate) static MEMORY_LIMIT: u32 = 512; // in pages
 static MAX_STACK_HEIGHT: u32 = 16 * 1024; // 16Kib of stack.
 
 static REQUIRED_EXPORTS: &[&str] = &["prepare", "execute"];
+// The only import that may be something other than a function: the linear memory, for
+// toolchains that import it from the host instead of declaring it.
+static IMPORTED_MEMORY: &str = "env.memory";
 static SUPPORTED_IMPORTS: &[&str] = &[
     "env.get_span_size",
     "env.read_calldata",
@@ -24,10 +35,188 @@ static SUPPORTED_IMPORTS: &[&str] = &[
     "env.ask_external_data",
     "env.get_external_data_status",
     "env.read_external_data",
+    "env.read_external_data_range",
     "env.ecvrf_verify",
+    "env.const_time_eq",
+    "env.base64_encode",
+    "env.base64_decode",
+    "env.json_extract_f64",
+    "env.append_return_data",
+    "env.get_abi_version",
+    "env.get_random_seed",
+    "env.read_all_external_data",
+    "env.peek_external_data_len",
+    "env.sort_u64",
+    "env.verify_external_data_hash",
+    "env.hex_encode",
+    "env.hex_decode",
+    "env.get_gas_price",
+    "env.get_data_source_count",
+    "env.get_data_source_id",
+    "env.ask_external_data_indexed",
+    "env.get_storage",
+    "env.read_external_data_grow",
+    "env.map_set",
+    "env.map_get",
+    "env.fail_with",
+    "env.tick",
+    "env.get_external_id_count",
+    "env.get_external_id",
 ];
 
+/// The kind of an item exported by a Wasm module.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExportKind {
+    Function,
+    Table,
+    Memory,
+    Global,
+}
+
+/// A Wasm proposal whose behavior may differ between platforms or that the chain may not want
+/// scripts to rely on.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum WasmFeature {
+    Simd,
+    BulkMemory,
+    ReferenceTypes,
+    Threads,
+}
+
+/// Limits on the modules `compile_with_options` accepts, bounding the time the compilation can
+/// take and the features scripts may use.
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    /// The most functions the module may define.
+    pub max_functions: u32,
+    /// The most elements each table of the module may hold.
+    pub max_table_size: u32,
+    /// The features the module must not use, none by default.
+    pub forbidden_features: Vec<WasmFeature>,
+    /// The longest the compilation may take, including a trial native compilation with the
+    /// default backend, or None for no limit, the default. Timing depends on the machine, so
+    /// validators may disagree on code close to the limit. A compilation that times out keeps
+    /// running in the background, and until it finishes any other compilation with a timeout
+    /// fails with `CompileTimeout` right away, so that abandoned compilations cannot pile up.
+    pub compile_timeout: Option<Duration>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        // Far above what an oracle script needs, even a large one built with a full toolchain.
+        CompileOptions {
+            max_functions: 100_000,
+            max_table_size: 100_000,
+            forbidden_features: vec![],
+            compile_timeout: None,
+        }
+    }
+}
+
 pub fn compile(code: &[u8]) -> Result<Vec<u8>, Error> {
+    compile_with_options(code, &CompileOptions::default())
+}
+
+/// Same as `compile`, but reads the code from `reader`, failing with `CodeTooLarge` as soon as
+/// more than `max_len` bytes come in, without reading the rest.
+pub fn compile_from_reader<R: Read>(reader: R, max_len: usize) -> Result<Vec<u8>, Error> {
+    let limit = u64::try_from(max_len).map_err(|_| Error::ConvertTypeOutOfBound)?;
+    let mut code = vec![];
+    // One byte past the limit is enough to tell that the code is too large.
+    reader
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut code)
+        .map_err(|_| Error::DeserializationError)?;
+    if code.len() > max_len {
+        return Err(Error::CodeTooLarge);
+    }
+    compile(&code)
+}
+
+/// Returns the SHA-256 hash of the native artifact the default backend compiles the given
+/// code to, where the code is what `compile` returns. Validators compiling the same code to
+/// different fingerprints on the same platform have nondeterministic compilers.
+pub fn compile_fingerprint(code: &[u8]) -> Result<[u8; 32], Error> {
+    let store = make_store_with_backend(Backend::default());
+    let module = WasmerModule::new(&store, code).map_err(|_| Error::InstantiationError)?;
+    let artifact = module.serialize().map_err(|_| Error::SerializationError)?;
+    Ok(Sha256::digest(&artifact).into())
+}
+
+/// Same as `compile`, but with the given limits instead of the default ones.
+pub fn compile_with_options(code: &[u8], options: &CompileOptions) -> Result<Vec<u8>, Error> {
+    let timeout = match options.compile_timeout {
+        Some(timeout) => timeout,
+        None => return instrument(code, options),
+    };
+    let code = code.to_vec();
+    let options = options.clone();
+    run_with_timeout(timeout, move || {
+        let compiled = instrument(&code, &options)?;
+        let store = make_store_with_backend(Backend::default());
+        WasmerModule::new(&store, &compiled).map_err(|_| Error::InstantiationError)?;
+        Ok(compiled)
+    })
+}
+
+// The most workers that may still be running after their compilation timed out.
+const MAX_ABANDONED_WORKERS: usize = 1;
+static ABANDONED_WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+// The states of a worker, which it and the caller waiting on it race to update.
+const WORKER_RUNNING: u8 = 0;
+const WORKER_DONE: u8 = 1;
+const WORKER_ABANDONED: u8 = 2;
+
+// Runs `work` on a worker thread, so that work taking too long can be given up on. The worker
+// cannot be stopped, so it runs to completion in the background, and no more work is started
+// while too many such workers are still running.
+fn run_with_timeout<T, F>(timeout: Duration, work: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    if ABANDONED_WORKERS.load(Ordering::SeqCst) >= MAX_ABANDONED_WORKERS {
+        return Err(Error::CompileTimeout);
+    }
+    let (sender, receiver) = mpsc::channel();
+    let state = Arc::new(AtomicU8::new(WORKER_RUNNING));
+    let worker_state = state.clone();
+    thread::spawn(move || {
+        let _ = sender.send(work());
+        if worker_state.swap(WORKER_DONE, Ordering::SeqCst) == WORKER_ABANDONED {
+            ABANDONED_WORKERS.fetch_sub(1, Ordering::SeqCst);
+        }
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => {
+            // Counted before the worker can see it abandoned, so that it never uncounts itself
+            // first.
+            ABANDONED_WORKERS.fetch_add(1, Ordering::SeqCst);
+            let abandoned = state.compare_exchange(
+                WORKER_RUNNING,
+                WORKER_ABANDONED,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+            if abandoned.is_err() {
+                ABANDONED_WORKERS.fetch_sub(1, Ordering::SeqCst);
+            }
+            Err(Error::CompileTimeout)
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(Error::InternalError),
+    }
+}
+
+// Checks the code against the options and instruments it, with no time limit.
+fn instrument(code: &[u8], options: &CompileOptions) -> Result<Vec<u8>, Error> {
+    // Reject oversized modules from their section headers, before validating the bodies.
+    check_wasm_complexity(code, options)?;
+    if find_forbidden_feature(code, &options.forbidden_features)?.is_some() {
+        return Err(Error::ForbiddenFeature);
+    }
+
     // Check that the given Wasm code is indeed a valid Wasm.
     wasmparser::validate(code).map_err(|_| Error::ValidationError)?;
 
@@ -42,6 +231,353 @@ pub fn compile(code: &[u8]) -> Result<Vec<u8>, Error> {
     serialize(module).map_err(|_| Error::SerializationError)
 }
 
+/// Returns the names of the host functions scripts may import from the `env` module, in the
+/// order they were added to the VM. Tooling can check the imports of a script against it
+/// before deploying the script.
+pub fn supported_host_functions() -> Vec<&'static str> {
+    SUPPORTED_IMPORTS.iter().filter_map(|name| name.strip_prefix("env.")).collect()
+}
+
+/// Returns the name and kind of every export of the given Wasm code, in declaration order.
+pub fn module_exports(code: &[u8]) -> Result<Vec<(String, ExportKind)>, Error> {
+    let module: Module = deserialize_buffer(code).map_err(|_| Error::DeserializationError)?;
+    Ok(module.export_section().map_or(vec![], |export_section| {
+        export_section
+            .entries()
+            .iter()
+            .map(|entry| {
+                let kind = match entry.internal() {
+                    Internal::Function(_) => ExportKind::Function,
+                    Internal::Table(_) => ExportKind::Table,
+                    Internal::Memory(_) => ExportKind::Memory,
+                    Internal::Global(_) => ExportKind::Global,
+                };
+                (entry.field().to_string(), kind)
+            })
+            .collect()
+    }))
+}
+
+/// Returns the payload of the first custom section with the given name in the Wasm code, or
+/// None if there is no such section. The code is only parsed, never compiled or instantiated,
+/// so this is cheap enough for registries displaying script metadata.
+pub fn read_custom_section(code: &[u8], name: &str) -> Result<Option<Vec<u8>>, Error> {
+    for payload in wasmparser::Parser::new(0).parse_all(code) {
+        if let wasmparser::Payload::CustomSection { name: section_name, data, .. } =
+            payload.map_err(|_| Error::DeserializationError)?
+        {
+            if section_name == name {
+                return Ok(Some(data.to_vec()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// What a function body does that affects how far the memory can grow.
+#[derive(Default)]
+struct GrowSummary {
+    // The pages added by its constant grows outside loops, or None if it has other grows.
+    pages: Option<u64>,
+    // The functions it calls directly, with whether the call is inside a loop.
+    calls: Vec<(usize, bool)>,
+    // Whether it calls functions through a table.
+    calls_indirect: bool,
+}
+
+// Returns the pages the given function, run at most once, may add, or None if unbounded.
+fn pages_added(
+    index: usize,
+    summaries: &[GrowSummary],
+    may_grow: &[bool],
+    references: &[usize],
+    depth: usize,
+) -> Option<u64> {
+    let summary = summaries.get(index)?;
+    if depth > summaries.len() || summary.calls_indirect && may_grow[index] {
+        return None;
+    }
+    let mut pages = summary.pages?;
+    for &(callee, in_loop) in &summary.calls {
+        if may_grow.get(callee) == Some(&false) {
+            continue;
+        }
+        if in_loop || references.get(callee) != Some(&1) {
+            return None;
+        }
+        pages =
+            pages.saturating_add(pages_added(callee, summaries, may_grow, references, depth + 1)?);
+    }
+    Some(pages)
+}
+
+/// Returns a conservative upper bound, in Wasm pages, on the memory the given code can reach
+/// in a single run.
+///
+/// The bound starts from the initial size of the memory and adds the constant arguments of the
+/// `memory.grow` instructions that run at most once: those outside loops, in `prepare` or
+/// `execute`, or in a function called only once from such code. Only one entry point runs, so
+/// only the larger of the two totals counts. If any other `memory.grow` may run, the declared
+/// maximum is returned instead. The result never exceeds the declared maximum, or the memory
+/// limit if there is none.
+pub fn max_memory_estimate(code: &[u8]) -> Result<u32, Error> {
+    let mut memory = None;
+    let mut imported_functions = 0;
+    let mut entry_points = vec![];
+    let mut bodies = vec![];
+    // Functions the script can call in ways not listed in the bodies' summaries.
+    let mut referenced = vec![];
+    let mut table_functions = vec![];
+    let mut unknown_table_functions = false;
+    for payload in wasmparser::Parser::new(0).parse_all(code) {
+        match payload.map_err(|_| Error::DeserializationError)? {
+            wasmparser::Payload::ImportSection(reader) => {
+                for import in reader {
+                    match import.map_err(|_| Error::DeserializationError)?.ty {
+                        wasmparser::ImportSectionEntryType::Function(_) => imported_functions += 1,
+                        wasmparser::ImportSectionEntryType::Memory(ty) => memory = Some(ty),
+                        _ => {}
+                    }
+                }
+            }
+            wasmparser::Payload::MemorySection(reader) => {
+                for ty in reader {
+                    memory = Some(ty.map_err(|_| Error::DeserializationError)?);
+                }
+            }
+            wasmparser::Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export.map_err(|_| Error::DeserializationError)?;
+                    if matches!(export.kind, wasmparser::ExternalKind::Function)
+                        && REQUIRED_EXPORTS.contains(&export.field)
+                    {
+                        entry_points.push(export.index as usize);
+                    }
+                }
+            }
+            wasmparser::Payload::StartSection { func, .. } => referenced.push(func as usize),
+            wasmparser::Payload::ElementSection(reader) => {
+                for element in reader {
+                    let element = element.map_err(|_| Error::DeserializationError)?;
+                    let items = element
+                        .items
+                        .get_items_reader()
+                        .map_err(|_| Error::DeserializationError)?;
+                    for item in items {
+                        match item.map_err(|_| Error::DeserializationError)? {
+                            wasmparser::ElementItem::Func(index) => {
+                                table_functions.push(index as usize)
+                            }
+                            wasmparser::ElementItem::Expr(_) => unknown_table_functions = true,
+                        }
+                    }
+                }
+            }
+            wasmparser::Payload::CodeSectionEntry(body) => bodies.push(body),
+            _ => {}
+        }
+    }
+    let memory = memory.ok_or(Error::BadMemorySectionError)?;
+    let maximum = memory.maximum.map_or(MEMORY_LIMIT, |maximum| {
+        maximum.try_into().unwrap_or(MEMORY_LIMIT).min(MEMORY_LIMIT)
+    });
+
+    // Imported functions are provided by the host and never grow the memory.
+    let mut summaries: Vec<GrowSummary> = (0..imported_functions)
+        .map(|_| GrowSummary { pages: Some(0), ..GrowSummary::default() })
+        .collect();
+    for body in bodies {
+        let mut summary = GrowSummary { pages: Some(0), ..GrowSummary::default() };
+        let mut blocks = vec![];
+        let mut last_const = None;
+        let mut reader = body.get_operators_reader().map_err(|_| Error::DeserializationError)?;
+        while !reader.eof() {
+            let operator = reader.read().map_err(|_| Error::DeserializationError)?;
+            let in_loop = blocks.contains(&true);
+            match operator {
+                wasmparser::Operator::Block { .. }
+                | wasmparser::Operator::If { .. }
+                | wasmparser::Operator::Try { .. } => blocks.push(false),
+                wasmparser::Operator::Loop { .. } => blocks.push(true),
+                wasmparser::Operator::End => {
+                    blocks.pop();
+                }
+                wasmparser::Operator::Call { function_index } => {
+                    summary.calls.push((function_index as usize, in_loop))
+                }
+                wasmparser::Operator::CallIndirect { .. } => summary.calls_indirect = true,
+                wasmparser::Operator::RefFunc { function_index } => {
+                    referenced.push(function_index as usize)
+                }
+                wasmparser::Operator::MemoryGrow { .. } => {
+                    summary.pages = match (summary.pages, last_const) {
+                        (Some(pages), Some(added)) if !in_loop => Some(pages.saturating_add(added)),
+                        _ => None,
+                    };
+                }
+                _ => {}
+            }
+            last_const = match operator {
+                wasmparser::Operator::I32Const { value } => Some(value as u32 as u64),
+                _ => None,
+            };
+        }
+        summaries.push(summary);
+    }
+
+    // Whether calling each function may grow the memory, directly or through its callees.
+    let mut may_grow: Vec<bool> =
+        summaries.iter().map(|summary| summary.pages != Some(0)).collect();
+    loop {
+        let table_may_grow = unknown_table_functions
+            || table_functions.iter().any(|&index| may_grow.get(index) != Some(&false));
+        let mut changed = false;
+        for (index, summary) in summaries.iter().enumerate() {
+            if !may_grow[index]
+                && (summary.calls_indirect && table_may_grow
+                    || summary
+                        .calls
+                        .iter()
+                        .any(|&(callee, _)| may_grow.get(callee) != Some(&false)))
+            {
+                may_grow[index] = true;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // How many times each function is referenced, and so may be called, in a run.
+    let mut references = vec![0usize; summaries.len()];
+    let direct_calls = summaries.iter().flat_map(|summary| summary.calls.iter().map(|call| call.0));
+    for index in direct_calls.chain(referenced).chain(table_functions) {
+        if let Some(count) = references.get_mut(index) {
+            *count += 1;
+        }
+    }
+    if unknown_table_functions {
+        references.iter_mut().for_each(|count| *count += 2);
+    }
+
+    let mut largest_growth = 0u64;
+    for index in entry_points {
+        if may_grow.get(index) == Some(&false) {
+            continue;
+        }
+        let growth = match references.get(index) {
+            Some(0) => pages_added(index, &summaries, &may_grow, &references, 0),
+            _ => None,
+        };
+        match growth {
+            Some(growth) => largest_growth = largest_growth.max(growth),
+            None => return Ok(maximum),
+        }
+    }
+    Ok(memory.initial.saturating_add(largest_growth).min(maximum as u64) as u32)
+}
+
+/// Checks that every active data segment of the given code fits in its initial memory, for
+/// chains rejecting such code up front rather than have it fail to instantiate. Segments
+/// whose offset is not a constant cannot be checked, so they fail the check too. Passive
+/// segments are only copied by `memory.init`, which checks its own bounds, so they are skipped.
+pub fn validate_data_segments(code: &[u8]) -> Result<(), Error> {
+    let mut memory_pages = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(code) {
+        match payload.map_err(|_| Error::DeserializationError)? {
+            wasmparser::Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(|_| Error::DeserializationError)?;
+                    if let wasmparser::ImportSectionEntryType::Memory(memory) = import.ty {
+                        memory_pages = memory.initial;
+                    }
+                }
+            }
+            wasmparser::Payload::MemorySection(reader) => {
+                for memory in reader {
+                    memory_pages = memory.map_err(|_| Error::DeserializationError)?.initial;
+                }
+            }
+            wasmparser::Payload::DataSection(reader) => {
+                let memory_size = memory_pages.saturating_mul(wasmer::WASM_PAGE_SIZE as u64);
+                for data in reader {
+                    let data = data.map_err(|_| Error::DeserializationError)?;
+                    let init_expr = match data.kind {
+                        wasmparser::DataKind::Passive => continue,
+                        wasmparser::DataKind::Active { init_expr, .. } => init_expr,
+                    };
+                    let offset = match init_expr.get_operators_reader().read() {
+                        Ok(wasmparser::Operator::I32Const { value }) => value as u32 as u64,
+                        _ => return Err(Error::DataSegmentOutOfBounds),
+                    };
+                    if offset + data.data.len() as u64 > memory_size {
+                        return Err(Error::DataSegmentOutOfBounds);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Returns the first of the `forbidden` features the given code uses, or None if it uses none
+/// of them. This tells which feature made `compile_with_options` fail with `ForbiddenFeature`.
+pub fn find_forbidden_feature(
+    code: &[u8],
+    forbidden: &[WasmFeature],
+) -> Result<Option<WasmFeature>, Error> {
+    if forbidden.is_empty() {
+        return Ok(None);
+    }
+    let all_features = wasmparser::WasmFeatures { threads: true, ..Default::default() };
+    wasmparser::Validator::new()
+        .wasm_features(all_features)
+        .validate_all(code)
+        .map_err(|_| Error::ValidationError)?;
+    // The code is valid with every feature, so failing without one means the code uses it.
+    for &feature in forbidden {
+        let mut features = all_features;
+        match feature {
+            WasmFeature::Simd => features.simd = false,
+            WasmFeature::BulkMemory => features.bulk_memory = false,
+            WasmFeature::ReferenceTypes => features.reference_types = false,
+            WasmFeature::Threads => features.threads = false,
+        }
+        if wasmparser::Validator::new().wasm_features(features).validate_all(code).is_err() {
+            return Ok(Some(feature));
+        }
+    }
+    Ok(None)
+}
+
+fn check_wasm_complexity(code: &[u8], options: &CompileOptions) -> Result<(), Error> {
+    for payload in wasmparser::Parser::new(0).parse_all(code) {
+        match payload.map_err(|_| Error::ValidationError)? {
+            wasmparser::Payload::FunctionSection(reader)
+                if reader.get_count() > options.max_functions =>
+            {
+                return Err(Error::ModuleTooComplex);
+            }
+            wasmparser::Payload::TableSection(reader) => {
+                for table in reader {
+                    let table = table.map_err(|_| Error::ValidationError)?;
+                    if table.initial > options.max_table_size
+                        || table.maximum.is_some_and(|maximum| maximum > options.max_table_size)
+                    {
+                        return Err(Error::ModuleTooComplex);
+                    }
+                }
+            }
+            // Function bodies come last, and are the costly part to parse.
+            wasmparser::Payload::CodeSectionStart { .. } => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
 fn check_wasm_exports(module: &Module) -> Result<(), Error> {
     let available_exports: Vec<&str> = module.export_section().map_or(vec![], |export_section| {
         export_section.entries().iter().map(|entry| entry.field()).collect()
@@ -62,12 +598,9 @@ fn check_wasm_imports(module: &Module) -> Result<(), Error> {
 
     for required_import in required_imports {
         let full_name = format!("{}.{}", required_import.module(), required_import.field());
-        if !SUPPORTED_IMPORTS.contains(&full_name.as_str()) {
-            return Err(Error::InvalidImportsError);
-        }
-
         match required_import.external() {
-            External::Function(_) => (), // ok
+            External::Function(_) if SUPPORTED_IMPORTS.contains(&full_name.as_str()) => (), // ok
+            External::Memory(_) if full_name == IMPORTED_MEMORY => (),                      // ok
             _ => return Err(Error::InvalidImportsError),
         };
     }
@@ -79,7 +612,7 @@ fn inject_memory(module: Module) -> Result<Module, Error> {
     let mut m = module;
     let section = match m.memory_section() {
         Some(section) => section,
-        None => return Err(Error::BadMemorySectionError),
+        None => return inject_imported_memory(m),
     };
 
     // The valid wasm has only the section length of memory.
@@ -106,6 +639,86 @@ fn inject_memory(module: Module) -> Result<Module, Error> {
     Ok(builder::from_module(m).build())
 }
 
+// Same as `inject_memory`, for modules importing their memory rather than declaring it.
+fn inject_imported_memory(module: Module) -> Result<Module, Error> {
+    let mut m = module;
+    let mut found = false;
+    let entries = m.import_section_mut().map_or(&mut [][..], |section| section.entries_mut());
+    for entry in entries {
+        if let External::Memory(memory) = entry.external_mut() {
+            let limits = memory.limits();
+            if limits.initial() > MEMORY_LIMIT || limits.maximum().is_some() {
+                return Err(Error::BadMemorySectionError);
+            }
+            *memory = MemoryType::new(limits.initial(), Some(MEMORY_LIMIT));
+            found = true;
+        }
+    }
+    if !found {
+        return Err(Error::BadMemorySectionError);
+    }
+
+    Ok(builder::from_module(m).build())
+}
+
+/// Returns the type of the memory the given code imports from the host, or None if the code
+/// declares its own memory.
+pub(crate) fn imported_memory_type(code: &[u8]) -> Result<Option<wasmer::MemoryType>, Error> {
+    for payload in wasmparser::Parser::new(0).parse_all(code) {
+        if let wasmparser::Payload::ImportSection(reader) =
+            payload.map_err(|_| Error::DeserializationError)?
+        {
+            for import in reader {
+                let import = import.map_err(|_| Error::DeserializationError)?;
+                if let wasmparser::ImportSectionEntryType::Memory(memory) = import.ty {
+                    let initial =
+                        memory.initial.try_into().map_err(|_| Error::ConvertTypeOutOfBound)?;
+                    let maximum = match memory.maximum {
+                        Some(maximum) => {
+                            Some(maximum.try_into().map_err(|_| Error::ConvertTypeOutOfBound)?)
+                        }
+                        None => None,
+                    };
+                    return Ok(Some(wasmer::MemoryType::new::<u32>(
+                        initial,
+                        maximum,
+                        memory.shared,
+                    )));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the initial size in pages of the memory the given code declares or imports, and the
+/// number of functions it defines, which together make up the cost of instantiating it.
+pub(crate) fn instantiation_size(code: &[u8]) -> Result<(u64, u64), Error> {
+    let mut memory_pages = 0;
+    let mut functions = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(code) {
+        match payload.map_err(|_| Error::DeserializationError)? {
+            wasmparser::Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(|_| Error::DeserializationError)?;
+                    if let wasmparser::ImportSectionEntryType::Memory(memory) = import.ty {
+                        memory_pages = memory.initial.saturating_add(memory_pages);
+                    }
+                }
+            }
+            wasmparser::Payload::MemorySection(reader) => {
+                for memory in reader {
+                    let memory = memory.map_err(|_| Error::DeserializationError)?;
+                    memory_pages = memory.initial.saturating_add(memory_pages);
+                }
+            }
+            wasmparser::Payload::FunctionSection(reader) => functions += reader.get_count() as u64,
+            _ => {}
+        }
+    }
+    Ok((memory_pages, functions))
+}
+
 fn inject_stack_height(module: Module) -> Result<Module, Error> {
     wasm_instrument::inject_stack_limiter(module, MAX_STACK_HEIGHT)
         .map_err(|_| Error::StackHeightInjectionError)
@@ -114,28 +727,9 @@ fn inject_stack_height(module: Module) -> Result<Module, Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::wat_to_wasm;
 
     use assert_matches::assert_matches;
-    use std::io::{Read, Write};
-    use std::process::Command;
-    use tempfile::NamedTempFile;
-
-    fn wat2wasm(wat: impl AsRef<[u8]>) -> Vec<u8> {
-        let mut input_file = NamedTempFile::new().unwrap();
-        let mut output_file = NamedTempFile::new().unwrap();
-        input_file.write_all(wat.as_ref()).unwrap();
-        Command::new("wat2wasm")
-            .args(&[
-                input_file.path().to_str().unwrap(),
-                "-o",
-                output_file.path().to_str().unwrap(),
-            ])
-            .output()
-            .unwrap();
-        let mut wasm = Vec::new();
-        output_file.read_to_end(&mut wasm).unwrap();
-        wasm
-    }
 
     fn get_module_from_wasm(code: &[u8]) -> Module {
         match deserialize_buffer(code) {
@@ -146,14 +740,14 @@ mod tests {
 
     #[test]
     fn test_inject_memory_ok() {
-        let wasm = wat2wasm(r#"(module (memory 1))"#);
+        let wasm = wat_to_wasm(r#"(module (memory 1))"#);
         let module = get_module_from_wasm(&wasm);
         assert_matches!(inject_memory(module), Ok(_));
     }
 
     #[test]
     fn test_inject_memory_no_memory() {
-        let wasm = wat2wasm("(module)");
+        let wasm = wat_to_wasm("(module)");
         let module = get_module_from_wasm(&wasm);
         assert_eq!(inject_memory(module), Err(Error::BadMemorySectionError));
     }
@@ -178,24 +772,45 @@ mod tests {
 
     #[test]
     fn test_inject_memory_initial_size() {
-        let wasm_ok = wat2wasm("(module (memory 512))");
+        let wasm_ok = wat_to_wasm("(module (memory 512))");
         let module = get_module_from_wasm(&wasm_ok);
         assert_matches!(inject_memory(module), Ok(_));
-        let wasm_too_big = wat2wasm("(module (memory 513))");
+        let wasm_too_big = wat_to_wasm("(module (memory 513))");
         let module = get_module_from_wasm(&wasm_too_big);
         assert_eq!(inject_memory(module), Err(Error::BadMemorySectionError));
     }
 
     #[test]
     fn test_inject_memory_maximum_size() {
-        let wasm = wat2wasm("(module (memory 1 5))");
+        let wasm = wat_to_wasm("(module (memory 1 5))");
         let module = get_module_from_wasm(&wasm);
         assert_eq!(inject_memory(module), Err(Error::BadMemorySectionError));
     }
 
+    #[test]
+    fn test_inject_imported_memory() {
+        let wasm = wat_to_wasm(r#"(module (import "env" "memory" (memory 1)))"#);
+        let module = inject_memory(get_module_from_wasm(&wasm)).unwrap();
+        let code = serialize(module).unwrap();
+        assert_eq!(
+            imported_memory_type(&code),
+            Ok(Some(wasmer::MemoryType::new(1, Some(MEMORY_LIMIT), false)))
+        );
+
+        let wasm = wat_to_wasm(r#"(module (import "env" "memory" (memory 513)))"#);
+        let module = get_module_from_wasm(&wasm);
+        assert_eq!(inject_memory(module), Err(Error::BadMemorySectionError));
+        let wasm = wat_to_wasm(r#"(module (import "env" "memory" (memory 1 5)))"#);
+        let module = get_module_from_wasm(&wasm);
+        assert_eq!(inject_memory(module), Err(Error::BadMemorySectionError));
+
+        let wasm = wat_to_wasm("(module (memory 1))");
+        assert_eq!(imported_memory_type(&wasm), Ok(None));
+    }
+
     #[test]
     fn test_inject_stack_height() {
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
             (func
               (local $idx i32)
@@ -217,7 +832,7 @@ mod tests {
         );
         let module = inject_stack_height(get_module_from_wasm(&wasm)).unwrap();
         let wasm = serialize(module).unwrap();
-        let expected = wat2wasm(
+        let expected = wat_to_wasm(
             r#"(module
                 (type (;0;) (func))
                 (func (;0;) (type 0)
@@ -280,37 +895,46 @@ mod tests {
 
     #[test]
     fn test_check_wasm_imports() {
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
                 (type (func (param i64 i64 i64 i64) (result i64)))
                 (import "env" "beeb" (func (type 0))))"#,
         );
         let module = get_module_from_wasm(&wasm);
         assert_eq!(check_wasm_imports(&module), Err(Error::InvalidImportsError));
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
                 (type (func (param i64 i64 i64 i64) (result i64)))
                 (import "env" "ask_external_data" (func  (type 0))))"#,
         );
         let module = get_module_from_wasm(&wasm);
         assert_eq!(check_wasm_imports(&module), Ok(()));
+        let wasm = wat_to_wasm(r#"(module (import "env" "memory" (memory 1)))"#);
+        let module = get_module_from_wasm(&wasm);
+        assert_eq!(check_wasm_imports(&module), Ok(()));
+        let wasm = wat_to_wasm(r#"(module (import "env" "heap" (memory 1)))"#);
+        let module = get_module_from_wasm(&wasm);
+        assert_eq!(check_wasm_imports(&module), Err(Error::InvalidImportsError));
+        let wasm = wat_to_wasm(r#"(module (import "env" "memory" (func)))"#);
+        let module = get_module_from_wasm(&wasm);
+        assert_eq!(check_wasm_imports(&module), Err(Error::InvalidImportsError));
     }
 
     #[test]
     fn test_check_wasm_exports() {
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
             (func $execute (export "execute")))"#,
         );
         let module = get_module_from_wasm(&wasm);
         assert_eq!(check_wasm_exports(&module), Err(Error::InvalidExportsError));
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
                 (func $prepare (export "prepare")))"#,
         );
         let module = get_module_from_wasm(&wasm);
         assert_eq!(check_wasm_exports(&module), Err(Error::InvalidExportsError));
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
                 (func $execute (export "execute"))
                 (func $prepare (export "prepare"))
@@ -320,9 +944,226 @@ mod tests {
         assert_eq!(check_wasm_exports(&module), Ok(()));
     }
 
+    #[test]
+    fn test_compile_with_options() {
+        let wasm = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+                (func $foo (export "foo"))
+                (table 4 funcref)
+                (memory (export "memory") 1)
+              )"#,
+        );
+        let options = |max_functions, max_table_size| CompileOptions {
+            max_functions,
+            max_table_size,
+            ..CompileOptions::default()
+        };
+        assert_eq!(compile_with_options(&wasm, &options(3, 4)), compile(&wasm));
+        assert!(compile(&wasm).is_ok());
+        assert_eq!(compile_with_options(&wasm, &options(2, 4)), Err(Error::ModuleTooComplex));
+        assert_eq!(compile_with_options(&wasm, &options(3, 3)), Err(Error::ModuleTooComplex));
+    }
+
+    #[test]
+    fn test_compile_timeout() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              )
+            (func
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let options = |timeout| CompileOptions {
+            compile_timeout: Some(timeout),
+            ..CompileOptions::default()
+        };
+        assert_eq!(compile_with_options(&wasm, &options(Duration::from_secs(30))), compile(&wasm));
+        // Failures are reported as without a timeout.
+        assert_eq!(
+            compile_with_options(b"not wasm", &options(Duration::from_secs(30))),
+            compile(b"not wasm")
+        );
+
+        // Work held back past its timeout is abandoned, and holds back later compilations
+        // until it finishes.
+        let (release, released) = mpsc::channel::<()>();
+        let held = run_with_timeout(Duration::from_millis(10), move || {
+            released.recv().map_err(|_| Error::InternalError)
+        });
+        assert_eq!(held, Err(Error::CompileTimeout));
+        assert_eq!(
+            compile_with_options(&wasm, &options(Duration::from_secs(30))),
+            Err(Error::CompileTimeout)
+        );
+        release.send(()).unwrap();
+        while ABANDONED_WORKERS.load(Ordering::SeqCst) > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(compile_with_options(&wasm, &options(Duration::from_secs(30))), compile(&wasm));
+    }
+
+    #[test]
+    fn test_forbidden_features() {
+        // Generated manually because wat2wasm needs extra flags for SIMD instructions.
+        let simd = hex::decode(concat!(
+            "0061736d",                             // magic bytes
+            "01000000",                             // binary version (uint32)
+            "0104",                                 // section type (type) and length
+            "01600000",         // one function type, with no params and no results
+            "0303",             // section type (function) and length
+            "020000",           // two functions of type 0
+            "0503",             // section type (memory) and length
+            "010001",           // one memory, min=1, max=unset
+            "0715",             // section type (export) and length
+            "02",               // number of exports
+            "0770726570617265", // "prepare"
+            "0000",             // function 0
+            "0765786563757465", // "execute"
+            "0001",             // function 1
+            "0a1a",             // section type (code) and length
+            "02",               // number of function bodies
+            "1500",             // body length, no locals
+            "fd0c00000000000000000000000000000000", // v128.const 0
+            "1a0b",             // drop, end
+            "02000b",           // body length, no locals, end
+        ))
+        .unwrap();
+        let plain = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+                (memory (export "memory") 1)
+              )"#,
+        );
+        let all = [
+            WasmFeature::Threads,
+            WasmFeature::ReferenceTypes,
+            WasmFeature::BulkMemory,
+            WasmFeature::Simd,
+        ];
+        let options = CompileOptions { forbidden_features: all.to_vec(), ..Default::default() };
+
+        assert_eq!(find_forbidden_feature(&simd, &all), Ok(Some(WasmFeature::Simd)));
+        assert_eq!(find_forbidden_feature(&simd, &all[..3]), Ok(None));
+        assert_eq!(compile_with_options(&simd, &options), Err(Error::ForbiddenFeature));
+        assert_eq!(find_forbidden_feature(&plain, &all), Ok(None));
+        assert_eq!(compile_with_options(&plain, &options), compile(&plain));
+        assert!(compile(&plain).is_ok());
+        assert_eq!(find_forbidden_feature(&[0, 1, 2], &all), Err(Error::ValidationError));
+    }
+
+    #[test]
+    fn test_compile_from_reader() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              )
+            (func
+              )
+            (memory 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let cursor = std::io::Cursor::new(wasm.clone());
+        assert_eq!(compile_from_reader(cursor, wasm.len()), compile(&wasm));
+        let cursor = std::io::Cursor::new(wasm.clone());
+        assert_eq!(compile_from_reader(cursor, wasm.len() - 1), Err(Error::CodeTooLarge));
+
+        // An endless input is cut off right after the limit instead of read to its end.
+        assert_eq!(compile_from_reader(std::io::repeat(0), 1024), Err(Error::CodeTooLarge));
+    }
+
+    #[test]
+    fn test_validate_data_segments() {
+        let module = |memory: &str, data: &str| {
+            wat_to_wasm(format!(
+                r#"(module
+                (func
+                  )
+                (func
+                  )
+                {}
+                {}
+                (export "prepare" (func 0))
+                (export "execute" (func 1)))
+              "#,
+                memory, data
+            ))
+        };
+
+        let wasm =
+            module(r#"(memory (export "memory") 17)"#, r#"(data (i32.const 1048576) "beeb")"#);
+        assert_eq!(validate_data_segments(&wasm), Ok(()));
+        // A segment ending right at the end of the memory fits.
+        let wasm =
+            module(r#"(memory (export "memory") 17)"#, r#"(data (i32.const 1114108) "beeb")"#);
+        assert_eq!(validate_data_segments(&wasm), Ok(()));
+        let wasm =
+            module(r#"(memory (export "memory") 17)"#, r#"(data (i32.const 1114109) "beeb")"#);
+        assert_eq!(validate_data_segments(&wasm), Err(Error::DataSegmentOutOfBounds));
+        // Offsets are unsigned, so negative constants land far past the memory.
+        let wasm = module(r#"(memory (export "memory") 17)"#, r#"(data (i32.const -1) "b")"#);
+        assert_eq!(validate_data_segments(&wasm), Err(Error::DataSegmentOutOfBounds));
+
+        // Imported memories are checked against their declared initial size.
+        let wasm =
+            module(r#"(import "env" "memory" (memory 1))"#, r#"(data (i32.const 65536) "b")"#);
+        assert_eq!(validate_data_segments(&wasm), Err(Error::DataSegmentOutOfBounds));
+
+        assert_eq!(validate_data_segments(&module("", "")), Ok(()));
+        assert_eq!(validate_data_segments(b"not wasm"), Err(Error::DeserializationError));
+    }
+
+    #[test]
+    fn test_compile_fingerprint() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              (local $idx i32)
+              (loop
+                (local.set $idx (i32.add (local.get $idx) (i32.const 1)))
+                (br_if 0 (i32.lt_u (local.get $idx) (i32.const 1000000))))
+              )
+            (func
+              )
+            (memory 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let fingerprint = compile_fingerprint(&code).unwrap();
+        for _ in 0..3 {
+            assert_eq!(compile_fingerprint(&code), Ok(fingerprint));
+        }
+
+        // Different code compiles to a different artifact.
+        let other = compile(&wat_to_wasm(
+            r#"(module
+            (func
+              )
+            (func
+              )
+            (memory 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        ))
+        .unwrap();
+        assert_ne!(compile_fingerprint(&other), Ok(fingerprint));
+        assert_eq!(compile_fingerprint(b"not wasm"), Err(Error::InstantiationError));
+    }
+
     #[test]
     fn test_compile() {
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
             (type (func (param i64 i64 i64 i64) (result i64)))
             (import "env" "ask_external_data" (func (type 0)))
@@ -345,7 +1186,7 @@ mod tests {
           "#,
         );
         let code = compile(&wasm).unwrap();
-        let expected = wat2wasm(
+        let expected = wat_to_wasm(
             r#"(module
                 (type (;0;) (func (param i64 i64 i64 i64) (result i64)))
                 (type (;1;) (func))
@@ -391,4 +1232,98 @@ mod tests {
         );
         assert_eq!(code, expected);
     }
+
+    #[test]
+    fn test_module_exports() {
+        let wasm = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+                (memory (export "memory") 100)
+                (data (i32.const 1048576) "beeb")
+              )"#,
+        );
+        let code = compile(&wasm).unwrap();
+        assert_eq!(
+            module_exports(&code),
+            Ok(vec![
+                ("execute".to_string(), ExportKind::Function),
+                ("prepare".to_string(), ExportKind::Function),
+                ("memory".to_string(), ExportKind::Memory),
+            ])
+        );
+        assert_eq!(module_exports(&[0, 1, 2]), Err(Error::DeserializationError));
+    }
+
+    #[test]
+    fn test_max_memory_estimate() {
+        let estimate = |body: &str| {
+            max_memory_estimate(&wat_to_wasm(format!(
+                r#"(module
+                (func $grow (drop (memory.grow (i32.const 1))))
+                (func $no_grow (nop))
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare") {})
+                (memory (export "memory") 2 100)
+              )"#,
+                body
+            )))
+        };
+
+        assert_eq!(estimate(""), Ok(2));
+        // Constant grows in an entry point add up.
+        assert_eq!(
+            estimate("(drop (memory.grow (i32.const 3))) (drop (memory.grow (i32.const 4)))"),
+            Ok(9)
+        );
+        assert_eq!(estimate("(drop (memory.grow (i32.const 1000)))"), Ok(100));
+        // Dynamic grows, grows in loops and grows in functions called more than once can reach
+        // the maximum.
+        assert_eq!(estimate("(drop (memory.grow (i32.add (i32.const 1) (i32.const 1))))"), Ok(100));
+        assert_eq!(estimate("(loop (drop (memory.grow (i32.const 1))))"), Ok(100));
+        assert_eq!(estimate("(drop (memory.grow (i32.const 1))) (call $prepare)"), Ok(100));
+        assert_eq!(estimate("(call $grow) (call $grow)"), Ok(100));
+        // Grows in a function called once count like grows in the entry point.
+        assert_eq!(estimate("(call $grow) (drop (memory.grow (i32.const 3)))"), Ok(6));
+        assert_eq!(estimate("(call $no_grow) (call $no_grow)"), Ok(2));
+
+        let code = compile(&wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute") (drop (memory.grow (i32.const 5))))
+                (func $prepare (export "prepare") (drop (memory.grow (i32.const 2))))
+                (memory (export "memory") 1)
+              )"#,
+        ))
+        .unwrap();
+        assert_eq!(max_memory_estimate(&code), Ok(6));
+        assert_eq!(max_memory_estimate(&[0, 1, 2]), Err(Error::DeserializationError));
+    }
+
+    #[test]
+    fn test_read_custom_section() {
+        let mut wasm = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+                (memory (export "memory") 100)
+              )"#,
+        );
+        let meta = br#"{"name":"price","version":"1.0.0"}"#;
+        let mut section = vec![11];
+        section.extend_from_slice(b"script_meta");
+        section.extend_from_slice(meta);
+        wasm.push(0);
+        wasm.push(section.len() as u8);
+        wasm.extend(section);
+
+        assert_eq!(read_custom_section(&wasm, "script_meta"), Ok(Some(meta.to_vec())));
+        assert_eq!(read_custom_section(&wasm, "name"), Ok(None));
+        // Compiling keeps the section.
+        let code = compile(&wasm).unwrap();
+        assert_eq!(read_custom_section(&code, "script_meta"), Ok(Some(meta.to_vec())));
+        assert_eq!(
+            read_custom_section(&[0, 1, 2], "script_meta"),
+            Err(Error::DeserializationError)
+        );
+    }
 }
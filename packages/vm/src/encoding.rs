@@ -0,0 +1,108 @@
+static STANDARD_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+static URL_SAFE_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+fn base64_alphabet(url_safe: bool) -> &'static [u8; 64] {
+    if url_safe {
+        URL_SAFE_ALPHABET
+    } else {
+        STANDARD_ALPHABET
+    }
+}
+
+/// Encodes the given data as padded base64, using the URL-safe alphabet if requested.
+pub fn base64_encode(data: &[u8], url_safe: bool) -> Vec<u8> {
+    let alphabet = base64_alphabet(url_safe);
+    let mut encoded = Vec::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        encoded.push(alphabet[(n >> 18) as usize & 63]);
+        encoded.push(alphabet[(n >> 12) as usize & 63]);
+        encoded.push(if chunk.len() > 1 { alphabet[(n >> 6) as usize & 63] } else { b'=' });
+        encoded.push(if chunk.len() > 2 { alphabet[n as usize & 63] } else { b'=' });
+    }
+    encoded
+}
+
+/// Decodes the given base64 data, with or without padding. Returns None if the data contains
+/// a character outside of the selected alphabet or has an impossible length.
+pub fn base64_decode(data: &[u8], url_safe: bool) -> Option<Vec<u8>> {
+    let alphabet = base64_alphabet(url_safe);
+    // Padding is only meaningful on input made of complete 4-character groups.
+    let data = match data.len() % 4 {
+        0 => &data[..data.len() - data.iter().rev().take(2).take_while(|c| **c == b'=').count()],
+        _ => data,
+    };
+    if data.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+    for chunk in data.chunks(4) {
+        let mut n = 0u32;
+        for (idx, c) in chunk.iter().enumerate() {
+            let value = alphabet.iter().position(|a| a == c)? as u32;
+            n |= value << (18 - 6 * idx);
+        }
+        // A short final group must leave its unused low bits zero, or decoding is malleable.
+        let unused_bits = match chunk.len() {
+            2 => n & 0xffff,
+            3 => n & 0xff,
+            _ => 0,
+        };
+        if unused_bits != 0 {
+            return None;
+        }
+        decoded.push((n >> 16) as u8);
+        if chunk.len() > 2 {
+            decoded.push((n >> 8) as u8);
+        }
+        if chunk.len() > 3 {
+            decoded.push(n as u8);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_encode_works() {
+        assert_eq!(base64_encode(b"", false), b"");
+        assert_eq!(base64_encode(b"b", false), b"Yg==");
+        assert_eq!(base64_encode(b"be", false), b"YmU=");
+        assert_eq!(base64_encode(b"bee", false), b"YmVl");
+        assert_eq!(base64_encode(b"beeb", false), b"YmVlYg==");
+        assert_eq!(base64_encode(&[0xfb, 0xff], false), b"+/8=");
+        assert_eq!(base64_encode(&[0xfb, 0xff], true), b"-_8=");
+    }
+
+    #[test]
+    fn base64_decode_works() {
+        assert_eq!(base64_decode(b"", false), Some(vec![]));
+        assert_eq!(base64_decode(b"Yg==", false), Some(b"b".to_vec()));
+        assert_eq!(base64_decode(b"YmU=", false), Some(b"be".to_vec()));
+        assert_eq!(base64_decode(b"YmVlYg==", false), Some(b"beeb".to_vec()));
+        assert_eq!(base64_decode(b"YmVlYg", false), Some(b"beeb".to_vec()));
+        assert_eq!(base64_decode(b"+/8=", false), Some(vec![0xfb, 0xff]));
+        assert_eq!(base64_decode(b"-_8=", true), Some(vec![0xfb, 0xff]));
+    }
+
+    #[test]
+    fn base64_decode_invalid() {
+        assert_eq!(base64_decode(b"-_8=", false), None);
+        assert_eq!(base64_decode(b"+/8=", true), None);
+        assert_eq!(base64_decode(b"Y", false), None);
+        assert_eq!(base64_decode(b"Y===", false), None);
+        assert_eq!(base64_decode(b"Y=mU", false), None);
+        assert_eq!(base64_decode(b"beeb!", false), None);
+        assert_eq!(base64_decode(b"YR==", false), None);
+        assert_eq!(base64_decode(b"YmV=", false), None);
+        assert_eq!(base64_decode(b"YR", false), None);
+    }
+}
@@ -25,6 +25,26 @@ pub enum Error {
     ChecksumLengthNotMatch = 16, // Checksum not of intended length.
     DataLengthOutOfBound = 17, // Data length is out of bound.
     ConvertTypeOutOfBound = 18, // Error while try to convert type.
+    InvalidEncodingError = 19, // Data to decode is not validly encoded.
+    IntegerDivisionByZero = 20, // Wasm script trapped on integer division by zero.
+    IntegerOverflow = 21,    // Wasm script trapped on integer overflow.
+    Unreachable = 22,        // Wasm script trapped on an unreachable instruction.
+    MemoryAccessOutOfBounds = 23, // Wasm script trapped on out-of-bound memory access.
+    InvalidCacheSize = 24,   // Cache is configured to hold no modules.
+    InsufficientGas = 25,    // Gas limit is below the minimum cost of running the code.
+    InvalidSpanSize = 26,    // Span size from the querier is negative or larger than memory.
+    InvalidCostTable = 27,   // Cost table config is malformed.
+    DataBudgetExceeded = 28, // External data requests exceed the configured byte budget.
+    InternalError = 29,      // The VM panicked, e.g. on a lifecycle bug; see `run_safe`.
+    TooManyReturnSegments = 30, // Return data is appended more times than allowed.
+    InvalidRefund = 31,      // Refund exceeds the gas charged for external data requests.
+    MeteringNotConfigured = 32, // Metered environment runs an instance compiled without metering.
+    ModuleTooComplex = 33,   // Wasm code defines more functions or table elements than allowed.
+    ForbiddenFeature = 34,   // Wasm code uses a feature the compile options forbid.
+    IoBudgetExceeded = 35,   // Host functions copy more memory bytes than the run allows.
+    CodeTooLarge = 36,       // Wasm code is longer than the caller allows.
+    DataSegmentOutOfBounds = 37, // Wasm code declares data past its initial memory.
+    CompileTimeout = 38,     // Compiling the Wasm code takes longer than the caller allows.
     // Host-generated errors while interacting with OEI.
     WrongPeriodActionError = 128, // OEI action to invoke is not available.
     TooManyExternalDataError = 129, // Too many external data requests.
@@ -33,10 +53,82 @@ pub enum Error {
     BadExternalIDError = 132,     // Bad external ID parameter.
     UnavailableExternalDataError = 133, // External data is not available.
     RepeatSetReturnDataError = 134, // Set return data is called more than once.
+    BadDataSourceIndexError = 135, // Bad requested data source index parameter.
+    RequestsNotAllowedInView = 136, // Wasm code asks external data while run in view mode.
+    MapEntriesLimitExceeded = 137, // Wasm code sets more map entries than a run may hold.
+    TickLimitExceeded = 138,      // Wasm code calls `tick` more times than a run allows.
+    BadExternalIDIndexError = 139, // Bad requested external ID index parameter.
     // Unexpected error
     UnknownError = 255,
 }
 
+impl Error {
+    // Every variant, for mapping codes back to errors. New variants must be added here too.
+    const ALL: [Error; 52] = [
+        Error::NoError,
+        Error::SpanTooSmallError,
+        Error::ValidationError,
+        Error::DeserializationError,
+        Error::SerializationError,
+        Error::InvalidImportsError,
+        Error::InvalidExportsError,
+        Error::BadMemorySectionError,
+        Error::GasCounterInjectionError,
+        Error::StackHeightInjectionError,
+        Error::InstantiationError,
+        Error::RuntimeError,
+        Error::OutOfGasError,
+        Error::BadEntrySignatureError,
+        Error::MemoryOutOfBoundError,
+        Error::UninitializedContextData,
+        Error::ChecksumLengthNotMatch,
+        Error::DataLengthOutOfBound,
+        Error::ConvertTypeOutOfBound,
+        Error::InvalidEncodingError,
+        Error::IntegerDivisionByZero,
+        Error::IntegerOverflow,
+        Error::Unreachable,
+        Error::MemoryAccessOutOfBounds,
+        Error::InvalidCacheSize,
+        Error::InsufficientGas,
+        Error::InvalidSpanSize,
+        Error::InvalidCostTable,
+        Error::DataBudgetExceeded,
+        Error::InternalError,
+        Error::TooManyReturnSegments,
+        Error::InvalidRefund,
+        Error::MeteringNotConfigured,
+        Error::ModuleTooComplex,
+        Error::ForbiddenFeature,
+        Error::IoBudgetExceeded,
+        Error::CodeTooLarge,
+        Error::DataSegmentOutOfBounds,
+        Error::CompileTimeout,
+        Error::WrongPeriodActionError,
+        Error::TooManyExternalDataError,
+        Error::DuplicateExternalIDError,
+        Error::BadValidatorIndexError,
+        Error::BadExternalIDError,
+        Error::UnavailableExternalDataError,
+        Error::RepeatSetReturnDataError,
+        Error::BadDataSourceIndexError,
+        Error::RequestsNotAllowedInView,
+        Error::MapEntriesLimitExceeded,
+        Error::TickLimitExceeded,
+        Error::BadExternalIDIndexError,
+        Error::UnknownError,
+    ];
+}
+
+/// Returns the error with the given code, failing with the code itself if no error has it.
+impl TryFrom<i32> for Error {
+    type Error = i32;
+
+    fn try_from(code: i32) -> Result<Self, i32> {
+        Error::ALL.iter().copied().find(|error| *error as i32 == code).ok_or(code)
+    }
+}
+
 impl std::error::Error for Error {}
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -53,4 +145,13 @@ mod tests {
         assert_eq!(format!("{}", Error::NoError), "NoError");
         assert_eq!(format!("{}", Error::SpanTooSmallError), "SpanTooSmallError");
     }
+
+    #[test]
+    fn try_from_code_works() {
+        for error in Error::ALL {
+            assert_eq!(Error::try_from(error as i32), Ok(error));
+        }
+        assert_eq!(Error::try_from(127), Err(127));
+        assert_eq!(Error::try_from(-1), Err(-1));
+    }
 }
@@ -1,13 +1,77 @@
+use crate::compile::MEMORY_LIMIT;
+use crate::encoding;
 use crate::error::Error;
-use crate::vm::{Environment, Querier};
+use crate::json::{self, JsonError};
+use crate::vm::{Environment, Querier, ScriptFailure};
 
-use wasmer::{imports, Function, ImportObject, Store};
+use sha2::{Digest, Sha256};
+use wasmer::{imports, Function, ImportObject, Memory, Pages, Store, WASM_PAGE_SIZE};
 
 use owasm_crypto::ecvrf;
 use owasm_crypto::error::CryptoError;
 
+/// The version of the set of host functions the VM provides to scripts, as returned by
+/// `get_abi_version`. It must be bumped whenever an import is added, removed, or changes its
+/// signature or behavior, so that scripts can check for it before using newer imports.
+pub const ABI_VERSION: i64 = 16;
+
 const IMPORTED_FUNCTION_GAS: u64 = 750_000_000;
 const ECVRF_VERIFY_GAS: u64 = 7_500_000_000_000;
+// Cost of each memory page `read_external_data_grow` adds, on top of writing the data.
+const MEMORY_GROW_GAS_PER_PAGE: u64 = 1_000_000_000;
+
+// Status codes returned by `json_extract_f64` to the script.
+const JSON_EXTRACT_OK: i64 = 0;
+const JSON_EXTRACT_PATH_NOT_FOUND: i64 = 1;
+const JSON_EXTRACT_NOT_A_NUMBER: i64 = 2;
+const JSON_EXTRACT_INVALID_JSON: i64 = 3;
+const JSON_EXTRACT_OUT_OF_RANGE: i64 = 4;
+// Number of decimal places kept when numbers are returned as fixed-point in deterministic mode.
+const JSON_FIXED_POINT_DECIMALS: u32 = 9;
+// Status codes returned by `hex_decode` to the script, in place of the decoded length.
+const HEX_DECODE_ODD_LENGTH: i64 = -1;
+const HEX_DECODE_INVALID_DIGIT: i64 = -2;
+// Returned by `get_storage` to the script, in place of the value length, for absent keys.
+const STORAGE_KEY_ABSENT: i64 = -1;
+// Returned by `map_get` to the script, in place of the value length, for keys never set.
+const MAP_KEY_ABSENT: i64 = -1;
+
+// Returns the span size, rejecting values no script memory could ever hold. A negative
+// span size would otherwise flip every `len > span_size` check.
+fn checked_span_size<Q>(env: &Environment<Q>) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    let span_size = env.get_span_size();
+    if span_size < 0 || span_size > MEMORY_LIMIT as i64 * WASM_PAGE_SIZE as i64 {
+        return Err(Error::InvalidSpanSize);
+    }
+    Ok(span_size)
+}
+
+// Same as `checked_span_size`, for the maximum return data size, which spans as many times
+// that size as the environment allows.
+fn checked_max_return_size<Q>(env: &Environment<Q>) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    let max_return_size = env.get_max_return_size();
+    if max_return_size < 0 || max_return_size > MEMORY_LIMIT as i64 * WASM_PAGE_SIZE as i64 {
+        return Err(Error::InvalidSpanSize);
+    }
+    let spans = i64::try_from(env.get_max_return_spans()).unwrap_or(i64::MAX);
+    Ok(max_return_size.saturating_mul(spans))
+}
+
+// Returns the flat cost of calling a host function, plus that cost again for each unit of
+// the environment's host call multiplier.
+fn host_call_gas<Q>(env: &Environment<Q>) -> u64
+where
+    Q: Querier + 'static,
+{
+    IMPORTED_FUNCTION_GAS
+        .saturating_add(IMPORTED_FUNCTION_GAS.saturating_mul(env.get_host_call_multiplier()))
+}
 
 fn require_mem_range(max_range: usize, require_range: usize) -> Result<(), Error> {
     if max_range < require_range {
@@ -23,8 +87,15 @@ where
     a.try_into().map_err(|_| Error::ConvertTypeOutOfBound)
 }
 
+// Returns the end offset `a + b`. Wasm memory offsets are 32-bit, so ends past `u32::MAX` are
+// out of bounds. Checking that before converting to usize makes every host agree, whatever
+// the width of its usize.
 fn safe_add(a: i64, b: i64) -> Result<usize, Error> {
-    (safe_convert::<_, usize>(a)?).checked_add(safe_convert(b)?).ok_or(Error::MemoryOutOfBoundError)
+    let end = safe_convert::<_, u64>(a)?
+        .checked_add(safe_convert(b)?)
+        .filter(|end| *end <= u64::from(u32::MAX))
+        .ok_or(Error::MemoryOutOfBoundError)?;
+    safe_convert(end)
 }
 
 fn read_memory<Q>(env: &Environment<Q>, ptr: i64, len: i64) -> Result<Vec<u8>, Error>
@@ -36,6 +107,7 @@ where
     }
     let memory = env.memory()?;
     require_mem_range(memory.size().bytes().0, safe_add(ptr, len)?)?;
+    env.spend_io_budget(safe_convert(len)?)?;
     Ok(memory
         .view()
         .get(safe_convert(ptr)?..safe_add(ptr, len)?)
@@ -54,6 +126,7 @@ where
     }
     let memory = env.memory()?;
     require_mem_range(memory.size().bytes().0, safe_add(ptr, safe_convert(data.len())?)?)?;
+    env.spend_io_budget(safe_convert(data.len())?)?;
     for (idx, byte) in data.iter().enumerate() {
         memory
             .view()
@@ -84,8 +157,9 @@ fn do_get_span_size<Q>(env: &Environment<Q>) -> Result<i64, Error>
 where
     Q: Querier + 'static,
 {
-    env.decrease_gas_left(IMPORTED_FUNCTION_GAS)?;
-    Ok(env.with_querier_from_context(|querier| querier.get_span_size()))
+    let span_size = checked_span_size(env)?;
+    env.decrease_gas_left(host_call_gas(env))?;
+    Ok(span_size)
 }
 
 fn do_read_calldata<Q>(env: &Environment<Q>, ptr: i64) -> Result<i64, Error>
@@ -93,7 +167,7 @@ where
     Q: Querier + 'static,
 {
     env.with_querier_from_context(|querier| {
-        let span_size = querier.get_span_size();
+        let span_size = checked_span_size(env)?;
         let data = querier.get_calldata()?;
 
         if safe_convert::<_, i64>(data.len())? > span_size {
@@ -101,55 +175,136 @@ where
         }
 
         env.decrease_gas_left(
-            IMPORTED_FUNCTION_GAS.saturating_add(calculate_write_memory_gas(data.len())),
+            host_call_gas(env).saturating_add(calculate_write_memory_gas(data.len())),
         )?;
         write_memory(env, ptr, data)
     })
 }
 
-fn do_set_return_data<Q>(env: &Environment<Q>, ptr: i64, len: i64) -> Result<(), Error>
+fn do_get_random_seed<Q>(env: &Environment<Q>, ptr: i64) -> Result<i64, Error>
 where
     Q: Querier + 'static,
 {
-    if len < 0 {
-        return Err(Error::DataLengthOutOfBound);
-    }
     env.with_querier_from_context(|querier| {
-        let span_size = querier.get_span_size();
+        let span_size = checked_span_size(env)?;
+        let seed = querier.get_random_seed()?;
 
-        if len > span_size {
+        if safe_convert::<_, i64>(seed.len())? > span_size {
             return Err(Error::SpanTooSmallError);
         }
+
         env.decrease_gas_left(
-            IMPORTED_FUNCTION_GAS.saturating_add(calculate_read_memory_gas(len)),
+            host_call_gas(env).saturating_add(calculate_write_memory_gas(seed.len())),
         )?;
-
-        let data: Vec<u8> = read_memory(env, ptr, len)?;
-        querier.set_return_data(&data)
+        write_memory(env, ptr, seed)
     })
 }
 
+fn do_set_return_data<Q>(env: &Environment<Q>, ptr: i64, len: i64) -> Result<(), Error>
+where
+    Q: Querier + 'static,
+{
+    if env.has_failure() {
+        return Err(Error::RepeatSetReturnDataError);
+    }
+    if len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let max_return_size = checked_max_return_size(env)?;
+    if len > max_return_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    env.decrease_gas_left(host_call_gas(env).saturating_add(calculate_read_memory_gas(len)))?;
+
+    let data: Vec<u8> = read_memory(env, ptr, len)?;
+    // Sent to the querier with whatever is appended after it, once the run ends.
+    env.replace_return_data(data);
+    Ok(())
+}
+
+fn do_fail_with<Q>(env: &Environment<Q>, code: i64, ptr: i64, len: i64) -> Result<(), Error>
+where
+    Q: Querier + 'static,
+{
+    if env.has_failure() {
+        return Err(Error::RepeatSetReturnDataError);
+    }
+    if len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let max_return_size = checked_max_return_size(env)?;
+    // The code takes the first 8 bytes of the return data.
+    if len > max_return_size - 8 {
+        return Err(Error::SpanTooSmallError);
+    }
+    env.decrease_gas_left(host_call_gas(env).saturating_add(calculate_read_memory_gas(len)))?;
+
+    let failure = ScriptFailure { code, message: read_memory(env, ptr, len)? };
+    // The failure replaces whatever return data the script has assembled so far, and nothing
+    // may be set or appended after it.
+    env.replace_return_data(failure.to_return_data());
+    env.record_failure(failure);
+    Ok(())
+}
+
+fn do_append_return_data<Q>(env: &Environment<Q>, ptr: i64, len: i64) -> Result<(), Error>
+where
+    Q: Querier + 'static,
+{
+    if env.has_failure() {
+        return Err(Error::RepeatSetReturnDataError);
+    }
+    if len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let max_return_size = checked_max_return_size(env)?;
+    if len > max_return_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    env.decrease_gas_left(host_call_gas(env).saturating_add(calculate_read_memory_gas(len)))?;
+
+    let data: Vec<u8> = read_memory(env, ptr, len)?;
+    // The limit bounds the whole accumulated return data, not each appended segment.
+    env.append_return_data(&data, safe_convert(max_return_size)?)
+}
+
+fn do_get_abi_version<Q>(env: &Environment<Q>) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    env.decrease_gas_left(host_call_gas(env))?;
+    Ok(ABI_VERSION)
+}
+
+fn do_tick<Q>(env: &Environment<Q>) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    env.decrease_gas_left(host_call_gas(env))?;
+    safe_convert(env.tick()?)
+}
+
 fn do_get_ask_count<Q>(env: &Environment<Q>) -> Result<i64, Error>
 where
     Q: Querier + 'static,
 {
-    env.decrease_gas_left(IMPORTED_FUNCTION_GAS)?;
-    Ok(env.with_querier_from_context(|querier| querier.get_ask_count()))
+    env.decrease_gas_left(host_call_gas(env))?;
+    Ok(env.get_ask_count())
 }
 
 fn do_get_min_count<Q>(env: &Environment<Q>) -> Result<i64, Error>
 where
     Q: Querier + 'static,
 {
-    env.decrease_gas_left(IMPORTED_FUNCTION_GAS)?;
-    Ok(env.with_querier_from_context(|querier| querier.get_min_count()))
+    env.decrease_gas_left(host_call_gas(env))?;
+    Ok(env.get_min_count())
 }
 
 fn do_get_prepare_time<Q>(env: &Environment<Q>) -> Result<i64, Error>
 where
     Q: Querier + 'static,
 {
-    env.decrease_gas_left(IMPORTED_FUNCTION_GAS)?;
+    env.decrease_gas_left(host_call_gas(env))?;
     Ok(env.with_querier_from_context(|querier| querier.get_prepare_time()))
 }
 
@@ -157,7 +312,7 @@ fn do_get_execute_time<Q>(env: &Environment<Q>) -> Result<i64, Error>
 where
     Q: Querier + 'static,
 {
-    env.decrease_gas_left(IMPORTED_FUNCTION_GAS)?;
+    env.decrease_gas_left(host_call_gas(env))?;
     env.with_querier_from_context(|querier| querier.get_execute_time())
 }
 
@@ -165,10 +320,156 @@ fn do_get_ans_count<Q>(env: &Environment<Q>) -> Result<i64, Error>
 where
     Q: Querier + 'static,
 {
-    env.decrease_gas_left(IMPORTED_FUNCTION_GAS)?;
+    env.decrease_gas_left(host_call_gas(env))?;
     env.with_querier_from_context(|querier| querier.get_ans_count())
 }
 
+fn do_get_gas_price<Q>(env: &Environment<Q>) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    env.decrease_gas_left(host_call_gas(env))?;
+    env.with_querier_from_context(|querier| querier.get_gas_price())
+}
+
+fn do_get_data_source_count<Q>(env: &Environment<Q>) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    env.decrease_gas_left(host_call_gas(env))?;
+    let count =
+        env.with_querier_from_context(|querier| querier.get_requested_data_sources())?.len();
+    i64::try_from(count).map_err(|_| Error::ConvertTypeOutOfBound)
+}
+
+fn do_get_data_source_id<Q>(env: &Environment<Q>, index: i64) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    env.decrease_gas_left(host_call_gas(env))?;
+    let data_sources =
+        env.with_querier_from_context(|querier| querier.get_requested_data_sources())?;
+    usize::try_from(index)
+        .ok()
+        .and_then(|index| data_sources.get(index).copied())
+        .ok_or(Error::BadDataSourceIndexError)
+}
+
+fn do_get_external_id_count<Q>(env: &Environment<Q>) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    env.decrease_gas_left(host_call_gas(env))?;
+    let count =
+        env.with_querier_from_context(|querier| querier.get_requested_external_ids())?.len();
+    i64::try_from(count).map_err(|_| Error::ConvertTypeOutOfBound)
+}
+
+fn do_get_external_id<Q>(env: &Environment<Q>, index: i64) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    env.decrease_gas_left(host_call_gas(env))?;
+    let external_ids =
+        env.with_querier_from_context(|querier| querier.get_requested_external_ids())?;
+    usize::try_from(index)
+        .ok()
+        .and_then(|index| external_ids.get(index).copied())
+        .ok_or(Error::BadExternalIDIndexError)
+}
+
+fn do_get_storage<Q>(
+    env: &Environment<Q>,
+    key_ptr: i64,
+    key_len: i64,
+    out_ptr: i64,
+) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    if key_len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    env.with_querier_from_context(|querier| {
+        let span_size = checked_span_size(env)?;
+
+        if key_len > span_size {
+            return Err(Error::SpanTooSmallError);
+        }
+        env.decrease_gas_left(
+            host_call_gas(env).saturating_add(calculate_read_memory_gas(key_len)),
+        )?;
+
+        let key: Vec<u8> = read_memory(env, key_ptr, key_len)?;
+        let value = match querier.get_storage(&key)? {
+            Some(value) => value,
+            None => return Ok(STORAGE_KEY_ABSENT),
+        };
+        if safe_convert::<_, i64>(value.len())? > span_size {
+            return Err(Error::SpanTooSmallError);
+        }
+        env.decrease_gas_left(calculate_write_memory_gas(value.len()))?;
+        write_memory(env, out_ptr, value)
+    })
+}
+
+fn do_map_set<Q>(
+    env: &Environment<Q>,
+    key_ptr: i64,
+    key_len: i64,
+    val_ptr: i64,
+    val_len: i64,
+) -> Result<(), Error>
+where
+    Q: Querier + 'static,
+{
+    if key_len < 0 || val_len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let span_size = checked_span_size(env)?;
+
+    if key_len > span_size || val_len > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    env.decrease_gas_left(
+        host_call_gas(env)
+            .saturating_add(calculate_read_memory_gas(key_len))
+            .saturating_add(calculate_read_memory_gas(val_len)),
+    )?;
+
+    let key: Vec<u8> = read_memory(env, key_ptr, key_len)?;
+    let value: Vec<u8> = read_memory(env, val_ptr, val_len)?;
+    env.map_set(key, value)
+}
+
+fn do_map_get<Q>(
+    env: &Environment<Q>,
+    key_ptr: i64,
+    key_len: i64,
+    out_ptr: i64,
+) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    if key_len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let span_size = checked_span_size(env)?;
+
+    if key_len > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    env.decrease_gas_left(host_call_gas(env).saturating_add(calculate_read_memory_gas(key_len)))?;
+
+    let key: Vec<u8> = read_memory(env, key_ptr, key_len)?;
+    let value = match env.map_get(&key) {
+        Some(value) => value,
+        None => return Ok(MAP_KEY_ABSENT),
+    };
+    env.decrease_gas_left(calculate_write_memory_gas(value.len()))?;
+    write_memory(env, out_ptr, value)
+}
+
 fn do_ask_external_data<Q>(
     env: &Environment<Q>,
     eid: i64,
@@ -179,29 +480,54 @@ fn do_ask_external_data<Q>(
 where
     Q: Querier + 'static,
 {
+    do_ask_external_data_indexed(env, eid, did, ptr, len).map(|_| ())
+}
+
+// Same as `do_ask_external_data`, but returns the index of the request among those the run
+// issued, for scripts correlating requests with their results.
+fn do_ask_external_data_indexed<Q>(
+    env: &Environment<Q>,
+    eid: i64,
+    did: i64,
+    ptr: i64,
+    len: i64,
+) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    if env.is_view() {
+        return Err(Error::RequestsNotAllowedInView);
+    }
     if len < 0 {
         return Err(Error::DataLengthOutOfBound);
     }
-    env.with_querier_from_context(|querier| {
-        let span_size = querier.get_span_size();
+    env.spend_external_data_budget(len as u64)?;
+    let gas = if env.is_metered() {
+        host_call_gas(env).saturating_add(calculate_read_memory_gas(len))
+    } else {
+        0
+    };
+    let data = env.with_querier_from_context(|querier| {
+        let span_size = checked_span_size(env)?;
 
         if len > span_size {
             return Err(Error::SpanTooSmallError);
         }
-        env.decrease_gas_left(
-            IMPORTED_FUNCTION_GAS.saturating_add(calculate_read_memory_gas(len)),
-        )?;
+        env.decrease_gas_left(gas)?;
 
         let data: Vec<u8> = read_memory(env, ptr, len)?;
-        querier.ask_external_data(eid, did, &data)
-    })
+        querier.ask_external_data(eid, did, &data)?;
+        Ok(data)
+    })?;
+    let index = env.record_external_request(eid, did, data, gas);
+    i64::try_from(index).map_err(|_| Error::ConvertTypeOutOfBound)
 }
 
 fn do_get_external_data_status<Q>(env: &Environment<Q>, eid: i64, vid: i64) -> Result<i64, Error>
 where
     Q: Querier + 'static,
 {
-    env.decrease_gas_left(IMPORTED_FUNCTION_GAS)?;
+    env.decrease_gas_left(host_call_gas(env))?;
     env.with_querier_from_context(|querier| querier.get_external_data_status(eid, vid))
 }
 
@@ -211,24 +537,153 @@ fn do_read_external_data<Q>(
     vid: i64,
     ptr: i64,
 ) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    let span_size = checked_span_size(env)?;
+    let data = env.get_external_data(eid, vid)?;
+
+    if safe_convert::<_, i64>(data.len())? > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+
+    env.decrease_gas_left(
+        host_call_gas(env).saturating_add(calculate_write_memory_gas(data.len())),
+    )?;
+    write_memory(env, ptr, data)
+}
+
+// Same as `do_read_external_data`, but first grows the memory by as many pages as the data at
+// `ptr` needs, up to the memory limit, so that scripts can read data of any size at the end of
+// their memory.
+fn do_read_external_data_grow<Q>(
+    env: &Environment<Q>,
+    eid: i64,
+    vid: i64,
+    ptr: i64,
+) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    if ptr < 0 {
+        return Err(Error::MemoryOutOfBoundError);
+    }
+    let span_size = checked_span_size(env)?;
+    let data = env.get_external_data(eid, vid)?;
+
+    if safe_convert::<_, i64>(data.len())? > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+
+    let memory = env.memory()?;
+    let end = safe_add(ptr, safe_convert(data.len())?)?;
+    let pages_needed: u32 = safe_convert(end.div_ceil(WASM_PAGE_SIZE))?;
+    if pages_needed > MEMORY_LIMIT {
+        return Err(Error::MemoryOutOfBoundError);
+    }
+    let pages_added = pages_needed.saturating_sub(memory.size().0);
+
+    env.decrease_gas_left(
+        host_call_gas(env)
+            .saturating_add(calculate_write_memory_gas(data.len()))
+            .saturating_add(MEMORY_GROW_GAS_PER_PAGE.saturating_mul(pages_added.into())),
+    )?;
+    if pages_added > 0 {
+        // Fails if the memory declares a lower maximum than the limit.
+        memory.grow(Pages(pages_added)).map_err(|_| Error::MemoryOutOfBoundError)?;
+    }
+    write_memory(env, ptr, data)
+}
+
+// Returns 1 if the SHA-256 digest of the data span with the data id `eid` from validator index
+// `vid` is the 32 bytes at `expected_ptr`, and 0 otherwise.
+fn do_verify_external_data_hash<Q>(
+    env: &Environment<Q>,
+    eid: i64,
+    vid: i64,
+    expected_ptr: i64,
+) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    let data = env.get_external_data(eid, vid)?;
+    // Hashing reads every byte of the span, so it is charged like copying the span in.
+    env.decrease_gas_left(
+        host_call_gas(env)
+            .saturating_add(calculate_read_memory_gas(32))
+            .saturating_add(calculate_read_memory_gas(safe_convert(data.len())?)),
+    )?;
+
+    let expected = read_memory(env, expected_ptr, 32)?;
+    Ok(const_time_eq(&Sha256::digest(&data), &expected) as i64)
+}
+
+fn do_peek_external_data_len<Q>(env: &Environment<Q>, eid: i64, vid: i64) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    // Nothing is copied into the script memory, so only the flat call cost is charged.
+    env.decrease_gas_left(host_call_gas(env))?;
+    env.with_querier_from_context(|querier| querier.get_external_data_size(eid, vid))
+}
+
+fn do_read_all_external_data<Q>(env: &Environment<Q>, eid: i64, ptr: i64) -> Result<i64, Error>
 where
     Q: Querier + 'static,
 {
     env.with_querier_from_context(|querier| {
-        let span_size = querier.get_span_size();
-        let data = querier.get_external_data(eid, vid)?;
+        let span_size = checked_span_size(env)?;
+        // Each report is written as its length, an 8-byte little-endian integer, then its bytes.
+        let mut data = vec![];
+        for report in querier.get_all_external_data(eid)? {
+            data.extend_from_slice(&safe_convert::<_, i64>(report.len())?.to_le_bytes());
+            data.extend(report);
+        }
 
         if safe_convert::<_, i64>(data.len())? > span_size {
             return Err(Error::SpanTooSmallError);
         }
 
         env.decrease_gas_left(
-            IMPORTED_FUNCTION_GAS.saturating_add(calculate_write_memory_gas(data.len())),
+            host_call_gas(env).saturating_add(calculate_write_memory_gas(data.len())),
         )?;
         write_memory(env, ptr, data)
     })
 }
 
+fn do_read_external_data_range<Q>(
+    env: &Environment<Q>,
+    eid: i64,
+    vid: i64,
+    offset: i64,
+    ptr: i64,
+    len: i64,
+) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    if offset < 0 || len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let span_size = checked_span_size(env)?;
+
+    if len > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    let data = env.get_external_data(eid, vid)?;
+    // The end is checked first, so the start below it converts on every host.
+    let end = safe_add(offset, len)?;
+    let start: usize = safe_convert(offset)?;
+    if end > data.len() {
+        return Err(Error::MemoryOutOfBoundError);
+    }
+
+    env.decrease_gas_left(
+        host_call_gas(env).saturating_add(calculate_write_memory_gas(end - start)),
+    )?;
+    write_memory(env, ptr, data[start..end].to_vec())
+}
+
 fn do_ecvrf_verify<Q>(
     env: &Environment<Q>,
     y_ptr: i64,
@@ -244,71 +699,358 @@ where
     if y_len < 0 || pi_len < 0 || alpha_len < 0 {
         return Err(Error::DataLengthOutOfBound);
     }
-    env.with_querier_from_context(|querier| {
-        let span_size = querier.get_span_size();
+    let span_size = checked_span_size(env)?;
 
-        if y_len > span_size || pi_len > span_size || alpha_len > span_size {
-            return Err(Error::SpanTooSmallError);
-        }
-        // consume gas relatively to the function running time (~7.5ms)
-        env.decrease_gas_left(ECVRF_VERIFY_GAS)?;
-        let y: Vec<u8> = read_memory(env, y_ptr, y_len)?;
-        let pi: Vec<u8> = read_memory(env, pi_ptr, pi_len)?;
-        let alpha: Vec<u8> = read_memory(env, alpha_ptr, alpha_len)?;
-
-        let result = ecvrf::ecvrf_verify(&y, &pi, &alpha);
-        Ok(result.map_or_else(
-            |err| match err {
-                CryptoError::InvalidPointOnCurve { .. }
-                | CryptoError::InvalidPubkeyFormat { .. }
-                | CryptoError::InvalidProofFormat { .. }
-                | CryptoError::InvalidHashFormat { .. }
-                | CryptoError::GenericErr { .. } => err.code(),
-            },
-            |valid| if valid { 0 } else { 1 },
-        ))
-    })
+    if y_len > span_size || pi_len > span_size || alpha_len > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    // consume gas relatively to the function running time (~7.5ms)
+    env.decrease_gas_left(ECVRF_VERIFY_GAS)?;
+    let y: Vec<u8> = read_memory(env, y_ptr, y_len)?;
+    let pi: Vec<u8> = read_memory(env, pi_ptr, pi_len)?;
+    let alpha: Vec<u8> = read_memory(env, alpha_ptr, alpha_len)?;
+
+    let result = ecvrf::ecvrf_verify(&y, &pi, &alpha);
+    Ok(result.map_or_else(
+        |err| match err {
+            CryptoError::InvalidPointOnCurve { .. }
+            | CryptoError::InvalidPubkeyFormat { .. }
+            | CryptoError::InvalidProofFormat { .. }
+            | CryptoError::InvalidHashFormat { .. }
+            | CryptoError::GenericErr { .. } => err.code(),
+        },
+        |valid| if valid { 0 } else { 1 },
+    ))
 }
 
-pub fn create_import_object<Q>(store: &Store, owasm_env: Environment<Q>) -> ImportObject
+/// Compares two byte strings in time that depends only on their lengths, never on where
+/// the first mismatching byte is.
+fn const_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = (a.len() != b.len()) as u8;
+    for idx in 0..a.len().max(b.len()) {
+        diff |= a.get(idx).unwrap_or(&0) ^ b.get(idx).unwrap_or(&0);
+    }
+    diff == 0
+}
+
+fn do_sort_u64<Q>(env: &Environment<Q>, ptr: i64, count: i64) -> Result<(), Error>
 where
     Q: Querier + 'static,
 {
-    imports! {
-        "env" => {
-            "gas" => Function::new_native_with_env(store, owasm_env.clone(), do_gas),
-            "get_span_size" => Function::new_native_with_env(store, owasm_env.clone(), do_get_span_size),
-            "read_calldata" => Function::new_native_with_env(store, owasm_env.clone(), do_read_calldata),
-            "set_return_data" => Function::new_native_with_env(store, owasm_env.clone(), do_set_return_data),
-            "get_ask_count" => Function::new_native_with_env(store, owasm_env.clone(), do_get_ask_count),
-            "get_min_count" => Function::new_native_with_env(store, owasm_env.clone(), do_get_min_count),
-            "get_prepare_time" => Function::new_native_with_env(store, owasm_env.clone(), do_get_prepare_time),
-            "get_execute_time" => Function::new_native_with_env(store, owasm_env.clone(), do_get_execute_time),
-            "get_ans_count" => Function::new_native_with_env(store, owasm_env.clone(), do_get_ans_count),
-            "ask_external_data" => Function::new_native_with_env(store, owasm_env.clone(), do_ask_external_data),
-            "get_external_data_status" => Function::new_native_with_env(store, owasm_env.clone(), do_get_external_data_status),
-            "read_external_data" => Function::new_native_with_env(store, owasm_env.clone(), do_read_external_data),
-            "ecvrf_verify" => Function::new_native_with_env(store, owasm_env.clone(), do_ecvrf_verify),
-        },
+    if count < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let len = count.checked_mul(8).ok_or(Error::DataLengthOutOfBound)?;
+    let span_size = checked_span_size(env)?;
+
+    if len > span_size {
+        return Err(Error::SpanTooSmallError);
     }
+    env.decrease_gas_left(
+        host_call_gas(env)
+            .saturating_add(calculate_read_memory_gas(len))
+            .saturating_add(calculate_write_memory_gas(safe_convert(len)?)),
+    )?;
+
+    let data = read_memory(env, ptr, len)?;
+    let mut values: Vec<u64> = data
+        .chunks_exact(8)
+        .map(|chunk| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(chunk);
+            u64::from_le_bytes(bytes)
+        })
+        .collect();
+    values.sort_unstable();
+    write_memory(env, ptr, values.iter().flat_map(|value| value.to_le_bytes()).collect())?;
+    Ok(())
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+fn do_const_time_eq<Q>(
+    env: &Environment<Q>,
+    a_ptr: i64,
+    a_len: i64,
+    b_ptr: i64,
+    b_len: i64,
+) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    if a_len < 0 || b_len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let span_size = checked_span_size(env)?;
 
-    use crate::cache::{Cache, CacheOptions};
-    use crate::compile::compile;
-    use crate::store::make_store;
+    if a_len > span_size || b_len > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    env.decrease_gas_left(
+        host_call_gas(env)
+            .saturating_add(calculate_read_memory_gas(a_len))
+            .saturating_add(calculate_read_memory_gas(b_len)),
+    )?;
+
+    let a: Vec<u8> = read_memory(env, a_ptr, a_len)?;
+    let b: Vec<u8> = read_memory(env, b_ptr, b_len)?;
+    Ok(const_time_eq(&a, &b) as i64)
+}
 
-    use std::io::{Read, Write};
-    use std::process::Command;
-    use std::ptr::NonNull;
-    use tempfile::NamedTempFile;
-    use wasmer::ExternType::Function;
-    use wasmer::FunctionType;
-    use wasmer::Instance;
-    use wasmer::ValType::{I32, I64};
+fn do_base64_encode<Q>(
+    env: &Environment<Q>,
+    ptr: i64,
+    len: i64,
+    out_ptr: i64,
+    url_safe: i64,
+) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    if len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let span_size = checked_span_size(env)?;
+
+    if len > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    env.decrease_gas_left(host_call_gas(env).saturating_add(calculate_read_memory_gas(len)))?;
+
+    let data: Vec<u8> = read_memory(env, ptr, len)?;
+    let encoded = encoding::base64_encode(&data, url_safe != 0);
+    if safe_convert::<_, i64>(encoded.len())? > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+
+    env.decrease_gas_left(calculate_write_memory_gas(encoded.len()))?;
+    write_memory(env, out_ptr, encoded)
+}
+
+fn do_base64_decode<Q>(
+    env: &Environment<Q>,
+    ptr: i64,
+    len: i64,
+    out_ptr: i64,
+    url_safe: i64,
+) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    if len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let span_size = checked_span_size(env)?;
+
+    if len > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    env.decrease_gas_left(host_call_gas(env).saturating_add(calculate_read_memory_gas(len)))?;
+
+    let data: Vec<u8> = read_memory(env, ptr, len)?;
+    let decoded =
+        encoding::base64_decode(&data, url_safe != 0).ok_or(Error::InvalidEncodingError)?;
+
+    env.decrease_gas_left(calculate_write_memory_gas(decoded.len()))?;
+    write_memory(env, out_ptr, decoded)
+}
+
+fn do_hex_encode<Q>(env: &Environment<Q>, ptr: i64, len: i64, out_ptr: i64) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    if len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let span_size = checked_span_size(env)?;
+
+    // Every byte becomes two digits, which must fit in a span too.
+    if len.saturating_mul(2) > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    env.decrease_gas_left(
+        host_call_gas(env)
+            .saturating_add(calculate_read_memory_gas(len))
+            .saturating_add(calculate_write_memory_gas(safe_convert(len * 2)?)),
+    )?;
+
+    let data: Vec<u8> = read_memory(env, ptr, len)?;
+    write_memory(env, out_ptr, hex::encode(data).into_bytes())
+}
+
+fn do_hex_decode<Q>(env: &Environment<Q>, ptr: i64, len: i64, out_ptr: i64) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    if len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let span_size = checked_span_size(env)?;
+
+    if len > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    env.decrease_gas_left(host_call_gas(env).saturating_add(calculate_read_memory_gas(len)))?;
+
+    let data: Vec<u8> = read_memory(env, ptr, len)?;
+    let decoded = match hex::decode(data) {
+        Ok(decoded) => decoded,
+        Err(hex::FromHexError::OddLength) => return Ok(HEX_DECODE_ODD_LENGTH),
+        Err(_) => return Ok(HEX_DECODE_INVALID_DIGIT),
+    };
+
+    env.decrease_gas_left(calculate_write_memory_gas(decoded.len()))?;
+    write_memory(env, out_ptr, decoded)
+}
+
+fn do_json_extract_f64<Q>(
+    env: &Environment<Q>,
+    json_ptr: i64,
+    json_len: i64,
+    path_ptr: i64,
+    path_len: i64,
+    result_ptr: i64,
+) -> Result<i64, Error>
+where
+    Q: Querier + 'static,
+{
+    if json_len < 0 || path_len < 0 {
+        return Err(Error::DataLengthOutOfBound);
+    }
+    let deterministic = env.is_deterministic();
+    let span_size = checked_span_size(env)?;
+
+    if json_len > span_size || path_len > span_size {
+        return Err(Error::SpanTooSmallError);
+    }
+    env.decrease_gas_left(
+        host_call_gas(env)
+            .saturating_add(calculate_read_memory_gas(json_len))
+            .saturating_add(calculate_read_memory_gas(path_len)),
+    )?;
+
+    let json_data: Vec<u8> = read_memory(env, json_ptr, json_len)?;
+    let path = String::from_utf8(read_memory(env, path_ptr, path_len)?)
+        .map_err(|_| Error::InvalidEncodingError)?;
+    let number = match json::extract_number(&json_data, &path) {
+        Ok(number) => number,
+        Err(JsonError::NotFound) => return Ok(JSON_EXTRACT_PATH_NOT_FOUND),
+        Err(JsonError::NotNumber) => return Ok(JSON_EXTRACT_NOT_A_NUMBER),
+        Err(JsonError::Invalid) => return Ok(JSON_EXTRACT_INVALID_JSON),
+    };
+    let result = if deterministic {
+        match json::to_fixed_point(number, JSON_FIXED_POINT_DECIMALS) {
+            Some(value) => value.to_le_bytes(),
+            None => return Ok(JSON_EXTRACT_OUT_OF_RANGE),
+        }
+    } else {
+        match number.parse::<f64>() {
+            Ok(value) if value.is_finite() => value.to_le_bytes(),
+            _ => return Ok(JSON_EXTRACT_OUT_OF_RANGE),
+        }
+    };
+
+    env.decrease_gas_left(calculate_write_memory_gas(result.len()))?;
+    write_memory(env, result_ptr, result.to_vec())?;
+    Ok(JSON_EXTRACT_OK)
+}
+
+// Runs a host function, adding the gas it charges to the environment's breakdown under the
+// given import name.
+fn track_host_gas<Q, R>(env: &Environment<Q>, name: &'static str, call: impl FnOnce() -> R) -> R
+where
+    Q: Querier + 'static,
+{
+    if !env.is_metered() {
+        return call();
+    }
+    let gas_before = env.try_get_gas_left();
+    let result = call();
+    if let (Ok(gas_before), Ok(gas_after)) = (gas_before, env.try_get_gas_left()) {
+        env.record_host_gas(name, gas_before.saturating_sub(gas_after));
+    }
+    result
+}
+
+// Makes the import of the given host function, named after it without the `do_` prefix, which
+// also names its entry in the gas breakdown. The closure captures nothing, as wasmer requires.
+macro_rules! host_function {
+    ($store:expr, $env:expr, $function:ident($($arg:ident: $ty:ty),*)) => {
+        Function::new_native_with_env($store, $env.clone(), |env: &Environment<Q>, $($arg: $ty),*| {
+            track_host_gas(env, &stringify!($function)[3..], || $function(env, $($arg),*))
+        })
+    };
+}
+
+pub fn create_import_object<Q>(store: &Store, owasm_env: Environment<Q>) -> ImportObject
+where
+    Q: Querier + 'static,
+{
+    imports! {
+        "env" => {
+            "gas" => host_function!(store, owasm_env, do_gas(_gas: u32)),
+            "get_span_size" => host_function!(store, owasm_env, do_get_span_size()),
+            "read_calldata" => host_function!(store, owasm_env, do_read_calldata(ptr: i64)),
+            "set_return_data" => host_function!(store, owasm_env, do_set_return_data(ptr: i64, len: i64)),
+            "get_ask_count" => host_function!(store, owasm_env, do_get_ask_count()),
+            "get_min_count" => host_function!(store, owasm_env, do_get_min_count()),
+            "get_prepare_time" => host_function!(store, owasm_env, do_get_prepare_time()),
+            "get_execute_time" => host_function!(store, owasm_env, do_get_execute_time()),
+            "get_ans_count" => host_function!(store, owasm_env, do_get_ans_count()),
+            "ask_external_data" => host_function!(store, owasm_env, do_ask_external_data(eid: i64, did: i64, ptr: i64, len: i64)),
+            "get_external_data_status" => host_function!(store, owasm_env, do_get_external_data_status(eid: i64, vid: i64)),
+            "read_external_data" => host_function!(store, owasm_env, do_read_external_data(eid: i64, vid: i64, ptr: i64)),
+            "ecvrf_verify" => host_function!(store, owasm_env, do_ecvrf_verify(y_ptr: i64, y_len: i64, pi_ptr: i64, pi_len: i64, alpha_ptr: i64, alpha_len: i64)),
+            "const_time_eq" => host_function!(store, owasm_env, do_const_time_eq(a_ptr: i64, a_len: i64, b_ptr: i64, b_len: i64)),
+            "base64_encode" => host_function!(store, owasm_env, do_base64_encode(ptr: i64, len: i64, out_ptr: i64, url_safe: i64)),
+            "base64_decode" => host_function!(store, owasm_env, do_base64_decode(ptr: i64, len: i64, out_ptr: i64, url_safe: i64)),
+            "read_external_data_range" => host_function!(store, owasm_env, do_read_external_data_range(eid: i64, vid: i64, offset: i64, ptr: i64, len: i64)),
+            "json_extract_f64" => host_function!(store, owasm_env, do_json_extract_f64(json_ptr: i64, json_len: i64, path_ptr: i64, path_len: i64, result_ptr: i64)),
+            "append_return_data" => host_function!(store, owasm_env, do_append_return_data(ptr: i64, len: i64)),
+            "get_abi_version" => host_function!(store, owasm_env, do_get_abi_version()),
+            "get_random_seed" => host_function!(store, owasm_env, do_get_random_seed(ptr: i64)),
+            "read_all_external_data" => host_function!(store, owasm_env, do_read_all_external_data(eid: i64, ptr: i64)),
+            "peek_external_data_len" => host_function!(store, owasm_env, do_peek_external_data_len(eid: i64, vid: i64)),
+            "sort_u64" => host_function!(store, owasm_env, do_sort_u64(ptr: i64, count: i64)),
+            "verify_external_data_hash" => host_function!(store, owasm_env, do_verify_external_data_hash(eid: i64, vid: i64, expected_ptr: i64)),
+            "hex_encode" => host_function!(store, owasm_env, do_hex_encode(ptr: i64, len: i64, out_ptr: i64)),
+            "hex_decode" => host_function!(store, owasm_env, do_hex_decode(ptr: i64, len: i64, out_ptr: i64)),
+            "get_gas_price" => host_function!(store, owasm_env, do_get_gas_price()),
+            "get_data_source_count" => host_function!(store, owasm_env, do_get_data_source_count()),
+            "get_data_source_id" => host_function!(store, owasm_env, do_get_data_source_id(index: i64)),
+            "ask_external_data_indexed" => host_function!(store, owasm_env, do_ask_external_data_indexed(eid: i64, did: i64, ptr: i64, len: i64)),
+            "get_storage" => host_function!(store, owasm_env, do_get_storage(key_ptr: i64, key_len: i64, out_ptr: i64)),
+            "read_external_data_grow" => host_function!(store, owasm_env, do_read_external_data_grow(eid: i64, vid: i64, ptr: i64)),
+            "map_set" => host_function!(store, owasm_env, do_map_set(key_ptr: i64, key_len: i64, val_ptr: i64, val_len: i64)),
+            "map_get" => host_function!(store, owasm_env, do_map_get(key_ptr: i64, key_len: i64, out_ptr: i64)),
+            "fail_with" => host_function!(store, owasm_env, do_fail_with(code: i64, ptr: i64, len: i64)),
+            "tick" => host_function!(store, owasm_env, do_tick()),
+            "get_external_id_count" => host_function!(store, owasm_env, do_get_external_id_count()),
+            "get_external_id" => host_function!(store, owasm_env, do_get_external_id(index: i64)),
+        },
+    }
+}
+
+// Provides `memory` as `env.memory`, for scripts importing their memory from the host.
+pub(crate) fn add_imported_memory(import_object: &mut ImportObject, memory: Memory) {
+    let mut exports = import_object.get_namespace_exports("env").unwrap_or_default();
+    exports.insert("memory", memory);
+    import_object.register("env", exports);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::wat_to_wasm;
+    use crate::vm::MAX_MAP_ENTRIES;
+
+    use crate::cache::{Cache, CacheOptions};
+    use crate::compile::{compile, supported_host_functions};
+    use crate::store::{make_store_with_backend, Backend};
+
+    use std::ptr::NonNull;
+    use wasmer::ExternType::Function;
+    use wasmer::FunctionType;
+    use wasmer::Instance;
+    use wasmer::ValType::{I32, I64};
 
     pub struct MockQuerier {}
 
@@ -340,33 +1082,40 @@ mod test {
         fn ask_external_data(&self, _: i64, _: i64, _: &[u8]) -> Result<(), Error> {
             Ok(())
         }
-        fn get_external_data_status(&self, _: i64, _: i64) -> Result<i64, Error> {
-            Ok(1)
+        fn get_external_data_status(&self, eid: i64, vid: i64) -> Result<i64, Error> {
+            match eid {
+                0 => Ok(1),
+                _ => Ok(vid % 2),
+            }
         }
-        fn get_external_data(&self, _: i64, _: i64) -> Result<Vec<u8>, Error> {
-            Ok(vec![1])
+        fn get_external_data(&self, eid: i64, vid: i64) -> Result<Vec<u8>, Error> {
+            match eid {
+                0 => Ok(vec![1]),
+                _ => Ok(format!("data-{}-{}", eid, vid).into_bytes()),
+            }
+        }
+        fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+            Ok(vec![0xab; 32])
+        }
+        fn get_gas_price(&self) -> Result<i64, Error> {
+            Ok(25)
+        }
+        fn get_requested_data_sources(&self) -> Result<Vec<i64>, Error> {
+            Ok(vec![3, 7, 11])
+        }
+        fn get_requested_external_ids(&self) -> Result<Vec<i64>, Error> {
+            Ok(vec![2, 1])
+        }
+        fn get_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+            Ok(match key {
+                b"price" => Some(b"42".to_vec()),
+                _ => None,
+            })
         }
-    }
-
-    fn wat2wasm(wat: impl AsRef<[u8]>) -> Vec<u8> {
-        let mut input_file = NamedTempFile::new().unwrap();
-        let mut output_file = NamedTempFile::new().unwrap();
-        input_file.write_all(wat.as_ref()).unwrap();
-        Command::new("wat2wasm")
-            .args(&[
-                input_file.path().to_str().unwrap(),
-                "-o",
-                output_file.path().to_str().unwrap(),
-            ])
-            .output()
-            .unwrap();
-        let mut wasm = Vec::new();
-        output_file.read_to_end(&mut wasm).unwrap();
-        wasm
     }
 
     fn create_owasm_env() -> (Environment<MockQuerier>, Instance) {
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
             (func
             )
@@ -382,7 +1131,7 @@ mod test {
 
         let querier = MockQuerier {};
         let owasm_env = Environment::new(querier);
-        let store = make_store();
+        let store = make_store_with_backend(Backend::default());
         let import_object = create_import_object(&store, owasm_env.clone());
         let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
         let (instance, _) = cache.get_instance(&code, &store, &import_object).unwrap();
@@ -390,6 +1139,173 @@ mod test {
         return (owasm_env, instance);
     }
 
+    // Serves the given span size and return size cap and otherwise behaves like `MockQuerier`,
+    // without any of the optional querier methods.
+    pub struct ConfigurableQuerier {
+        span_size: i64,
+        max_return_size: Option<i64>,
+    }
+
+    impl ConfigurableQuerier {
+        fn with_span_size(span_size: i64) -> Self {
+            Self { span_size, max_return_size: None }
+        }
+
+        fn with_max_return_size(max_return_size: i64) -> Self {
+            Self {
+                span_size: MockQuerier {}.get_span_size(),
+                max_return_size: Some(max_return_size),
+            }
+        }
+    }
+
+    impl Querier for ConfigurableQuerier {
+        fn get_span_size(&self) -> i64 {
+            self.span_size
+        }
+        fn get_calldata(&self) -> Result<Vec<u8>, Error> {
+            MockQuerier {}.get_calldata()
+        }
+        fn set_return_data(&self, data: &[u8]) -> Result<(), Error> {
+            MockQuerier {}.set_return_data(data)
+        }
+        fn get_ask_count(&self) -> i64 {
+            MockQuerier {}.get_ask_count()
+        }
+        fn get_min_count(&self) -> i64 {
+            MockQuerier {}.get_min_count()
+        }
+        fn get_prepare_time(&self) -> i64 {
+            MockQuerier {}.get_prepare_time()
+        }
+        fn get_execute_time(&self) -> Result<i64, Error> {
+            MockQuerier {}.get_execute_time()
+        }
+        fn get_ans_count(&self) -> Result<i64, Error> {
+            MockQuerier {}.get_ans_count()
+        }
+        fn ask_external_data(&self, eid: i64, did: i64, data: &[u8]) -> Result<(), Error> {
+            MockQuerier {}.ask_external_data(eid, did, data)
+        }
+        fn get_external_data_status(&self, eid: i64, vid: i64) -> Result<i64, Error> {
+            MockQuerier {}.get_external_data_status(eid, vid)
+        }
+        fn get_external_data(&self, eid: i64, vid: i64) -> Result<Vec<u8>, Error> {
+            MockQuerier {}.get_external_data(eid, vid)
+        }
+        fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+            MockQuerier {}.get_random_seed()
+        }
+        fn get_max_return_size(&self) -> i64 {
+            self.max_return_size.unwrap_or(self.span_size)
+        }
+    }
+
+    #[test]
+    fn test_max_return_spans() {
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(2_500_000_000_000);
+        assert_eq!(owasm_env.get_max_return_spans(), 1);
+
+        // A single span by default.
+        assert_eq!(Ok(()), do_set_return_data(&owasm_env, 0, 300));
+        assert_eq!(Err(Error::SpanTooSmallError), do_set_return_data(&owasm_env, 0, 301));
+
+        owasm_env.set_max_return_spans(3);
+        assert_eq!(Ok(()), do_set_return_data(&owasm_env, 0, 900));
+        assert_eq!(Err(Error::SpanTooSmallError), do_set_return_data(&owasm_env, 0, 901));
+        assert_eq!(Err(Error::SpanTooSmallError), do_append_return_data(&owasm_env, 0, 1));
+        assert_eq!(Ok(()), do_set_return_data(&owasm_env, 0, 600));
+        assert_eq!(Ok(()), do_append_return_data(&owasm_env, 0, 300));
+        assert_eq!(Err(Error::SpanTooSmallError), do_append_return_data(&owasm_env, 0, 1));
+        assert_eq!(900, owasm_env.take_return_data().unwrap().len());
+
+        // Each read still fits in a single span.
+        assert_eq!(Err(Error::SpanTooSmallError), do_get_storage(&owasm_env, 0, 301, 0));
+
+        // Spans multiply the return size the querier caps the data at, if any.
+        let owasm_env = Environment::new(ConfigurableQuerier::with_max_return_size(6));
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(2_500_000_000_000);
+        owasm_env.set_max_return_spans(2);
+        assert_eq!(Ok(()), do_set_return_data(&owasm_env, 0, 12));
+        assert_eq!(Err(Error::SpanTooSmallError), do_set_return_data(&owasm_env, 0, 13));
+
+        owasm_env.set_max_return_spans(u64::MAX);
+        assert_eq!(Ok(i64::MAX), checked_max_return_size(&owasm_env));
+    }
+
+    #[test]
+    fn test_max_return_size() {
+        let (_, instance) = create_owasm_env();
+        let owasm_env = Environment::new(ConfigurableQuerier::with_max_return_size(6));
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(2_500_000_000_000);
+        assert_eq!(owasm_env.get_span_size(), 300);
+        assert_eq!(owasm_env.get_max_return_size(), 6);
+
+        write_memory(&owasm_env, 0, b"beebbeeb".to_vec()).unwrap();
+        assert_eq!(Ok(()), do_set_return_data(&owasm_env, 0, 6));
+        assert_eq!(Err(Error::SpanTooSmallError), do_set_return_data(&owasm_env, 0, 7));
+        assert_eq!(Ok(()), do_set_return_data(&owasm_env, 0, 2));
+        assert_eq!(Err(Error::SpanTooSmallError), do_append_return_data(&owasm_env, 0, 5));
+        assert_eq!(Ok(()), do_append_return_data(&owasm_env, 0, 4));
+        assert_eq!(Some(b"bebeeb".to_vec()), owasm_env.take_return_data());
+
+        // Reads are still bounded by the span size alone.
+        assert_eq!(Ok(1), do_read_calldata(&owasm_env, 0));
+
+        let owasm_env = Environment::new(ConfigurableQuerier::with_max_return_size(-1));
+        assert_eq!(Err(Error::InvalidSpanSize), checked_max_return_size(&owasm_env));
+    }
+
+    #[test]
+    fn test_supported_host_functions() {
+        let owasm_env = Environment::new(MockQuerier {});
+        let store = make_store_with_backend(Backend::default());
+        let provided: Vec<String> = create_import_object(&store, owasm_env)
+            .externs_vec()
+            .into_iter()
+            .map(|(_, name, _)| name)
+            .collect();
+        // Every import scripts may use is provided. The `gas` import is for the metering
+        // injected at compile time, so scripts may not import it themselves.
+        let supported = supported_host_functions();
+        assert_eq!(supported.len(), 38);
+        assert_eq!(&supported[..3], &["get_span_size", "read_calldata", "set_return_data"]);
+        assert_eq!(provided[0], "gas");
+        let mut provided = provided[1..].to_vec();
+        provided.sort();
+        let mut supported: Vec<String> = supported.iter().map(|name| name.to_string()).collect();
+        supported.sort();
+        assert_eq!(provided, supported);
+    }
+
+    #[test]
+    fn test_invalid_span_size() {
+        for span_size in [-1, i64::MAX] {
+            let env = Environment::new(ConfigurableQuerier::with_span_size(span_size));
+            assert_eq!(Err(Error::InvalidSpanSize), checked_span_size(&env));
+            assert_eq!(Err(Error::InvalidSpanSize), do_get_span_size(&env));
+            assert_eq!(Err(Error::InvalidSpanSize), do_read_calldata(&env, 0));
+            assert_eq!(Err(Error::InvalidSpanSize), do_set_return_data(&env, 0, 0));
+            assert_eq!(Err(Error::InvalidSpanSize), do_append_return_data(&env, 0, 0));
+            assert_eq!(Err(Error::InvalidSpanSize), do_ask_external_data(&env, 1, 1, 0, 0));
+            assert_eq!(Err(Error::InvalidSpanSize), do_read_external_data(&env, 1, 1, 0));
+            assert_eq!(Err(Error::InvalidSpanSize), do_base64_encode(&env, 0, 0, 0, 0));
+        }
+
+        let env = Environment::new(ConfigurableQuerier::with_span_size(0));
+        assert_eq!(Ok(0), checked_span_size(&env));
+        let env = Environment::new(ConfigurableQuerier::with_span_size(512 * 65536));
+        assert_eq!(Ok(512 * 65536), checked_span_size(&env));
+        let env = Environment::new(ConfigurableQuerier::with_span_size(512 * 65536 + 1));
+        assert_eq!(Err(Error::InvalidSpanSize), checked_span_size(&env));
+    }
+
     #[test]
     fn test_wrapper_fn() {
         let querier = MockQuerier {};
@@ -402,7 +1318,7 @@ mod test {
         assert_eq!(Err(Error::ConvertTypeOutOfBound), safe_convert::<_, usize>(i64::MIN));
         assert_eq!(Err(Error::ConvertTypeOutOfBound), safe_convert::<_, i64>(usize::MAX));
         assert_eq!(Ok(10), safe_add(4, 6));
-        assert_eq!(Ok(i64::MAX as usize + 1), safe_add(i64::MAX, 1));
+        assert_eq!(Err(Error::MemoryOutOfBoundError), safe_add(i64::MAX, 1));
         assert_eq!(Err(Error::ConvertTypeOutOfBound), safe_add(-1, 6));
         assert_eq!(Err(Error::ConvertTypeOutOfBound), safe_add(5, -10));
         assert_eq!(Err(Error::ConvertTypeOutOfBound), safe_add(usize::MAX as i64, 1));
@@ -414,8 +1330,8 @@ mod test {
     fn test_import_object_function_type() {
         let querier = MockQuerier {};
         let owasm_env = Environment::new(querier);
-        let store = make_store();
-        assert_eq!(create_import_object(&store, owasm_env.clone()).externs_vec().len(), 13);
+        let store = make_store_with_backend(Backend::default());
+        assert_eq!(create_import_object(&store, owasm_env.clone()).externs_vec().len(), 39);
 
         assert_eq!(create_import_object(&store, owasm_env.clone()).externs_vec()[0].1, "gas");
         assert_eq!(
@@ -521,6 +1437,228 @@ mod test {
             create_import_object(&store, owasm_env.clone()).externs_vec()[11].2.ty(),
             Function(FunctionType::new([I64, I64, I64], [I64]))
         );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[13].1,
+            "const_time_eq"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[13].2.ty(),
+            Function(FunctionType::new([I64, I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[14].1,
+            "base64_encode"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[14].2.ty(),
+            Function(FunctionType::new([I64, I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[15].1,
+            "base64_decode"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[15].2.ty(),
+            Function(FunctionType::new([I64, I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[16].1,
+            "read_external_data_range"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[16].2.ty(),
+            Function(FunctionType::new([I64, I64, I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[17].1,
+            "json_extract_f64"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[17].2.ty(),
+            Function(FunctionType::new([I64, I64, I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[18].1,
+            "append_return_data"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[18].2.ty(),
+            Function(FunctionType::new([I64, I64], []))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[19].1,
+            "get_abi_version"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[19].2.ty(),
+            Function(FunctionType::new([], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[20].1,
+            "get_random_seed"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[20].2.ty(),
+            Function(FunctionType::new([I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[21].1,
+            "read_all_external_data"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[21].2.ty(),
+            Function(FunctionType::new([I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[22].1,
+            "peek_external_data_len"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[22].2.ty(),
+            Function(FunctionType::new([I64, I64], [I64]))
+        );
+
+        assert_eq!(create_import_object(&store, owasm_env.clone()).externs_vec()[23].1, "sort_u64");
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[23].2.ty(),
+            Function(FunctionType::new([I64, I64], []))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[24].1,
+            "verify_external_data_hash"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[24].2.ty(),
+            Function(FunctionType::new([I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[25].1,
+            "hex_encode"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[25].2.ty(),
+            Function(FunctionType::new([I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[26].1,
+            "hex_decode"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[26].2.ty(),
+            Function(FunctionType::new([I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[27].1,
+            "get_gas_price"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[27].2.ty(),
+            Function(FunctionType::new([], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[28].1,
+            "get_data_source_count"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[28].2.ty(),
+            Function(FunctionType::new([], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[29].1,
+            "get_data_source_id"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[29].2.ty(),
+            Function(FunctionType::new([I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[30].1,
+            "ask_external_data_indexed"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[30].2.ty(),
+            Function(FunctionType::new([I64, I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[31].1,
+            "get_storage"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[31].2.ty(),
+            Function(FunctionType::new([I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[32].1,
+            "read_external_data_grow"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[32].2.ty(),
+            Function(FunctionType::new([I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(create_import_object(&store, owasm_env.clone()).externs_vec()[33].1, "map_set");
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[33].2.ty(),
+            Function(FunctionType::new([I64, I64, I64, I64], []))
+        );
+
+        assert_eq!(create_import_object(&store, owasm_env.clone()).externs_vec()[34].1, "map_get");
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[34].2.ty(),
+            Function(FunctionType::new([I64, I64, I64], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[35].1,
+            "fail_with"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[35].2.ty(),
+            Function(FunctionType::new([I64, I64, I64], []))
+        );
+
+        assert_eq!(create_import_object(&store, owasm_env.clone()).externs_vec()[36].1, "tick");
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[36].2.ty(),
+            Function(FunctionType::new([], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[37].1,
+            "get_external_id_count"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[37].2.ty(),
+            Function(FunctionType::new([], [I64]))
+        );
+
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[38].1,
+            "get_external_id"
+        );
+        assert_eq!(
+            create_import_object(&store, owasm_env.clone()).externs_vec()[38].2.ty(),
+            Function(FunctionType::new([I64], [I64]))
+        );
     }
 
     #[test]
@@ -587,6 +1725,22 @@ mod test {
         assert_eq!(gas_limit, owasm_env.get_gas_left());
     }
 
+    #[test]
+    fn test_do_get_random_seed() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        assert_eq!(Ok(32), do_get_random_seed(&owasm_env, 0));
+        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS - calculate_write_memory_gas(32);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Ok(vec![0xab; 32]), read_memory(&owasm_env, 0, 32));
+
+        assert_eq!(Err(Error::MemoryOutOfBoundError), do_get_random_seed(&owasm_env, -1));
+    }
+
     #[test]
     fn test_do_set_return_data() {
         let mut gas_limit = 2_500_000_000_000;
@@ -625,6 +1779,112 @@ mod test {
         assert_eq!(gas_limit, owasm_env.get_gas_left());
     }
 
+    #[test]
+    fn test_do_append_return_data() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        write_memory(&owasm_env, 0, b"beebbeeb".to_vec()).unwrap();
+        assert_eq!(Ok(()), do_append_return_data(&owasm_env, 0, 4));
+        assert_eq!(Ok(()), do_append_return_data(&owasm_env, 2, 6));
+        gas_limit = gas_limit
+            - 2 * IMPORTED_FUNCTION_GAS
+            - calculate_read_memory_gas(4)
+            - calculate_read_memory_gas(6);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Some(b"beebebbeeb".to_vec()), owasm_env.take_return_data());
+        assert_eq!(None, owasm_env.take_return_data());
+
+        // Setting the return data replaces what was appended before and is extended by what
+        // is appended after.
+        assert_eq!(Ok(()), do_append_return_data(&owasm_env, 0, 4));
+        assert_eq!(Ok(()), do_set_return_data(&owasm_env, 4, 2));
+        assert_eq!(Ok(()), do_append_return_data(&owasm_env, 2, 2));
+        assert_eq!(Some(b"beeb".to_vec()), owasm_env.take_return_data());
+
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_append_return_data(&owasm_env, 0, -1));
+    }
+
+    #[test]
+    fn test_copy_gas_scales_with_length() {
+        let gas_used = |call: &dyn Fn(&Environment<MockQuerier>)| {
+            let (owasm_env, instance) = create_owasm_env();
+            let instance_ptr = NonNull::from(&instance);
+            owasm_env.set_wasmer_instance(Some(instance_ptr));
+            owasm_env.set_gas_left(2_500_000_000_000);
+            call(&owasm_env);
+            2_500_000_000_000 - owasm_env.get_gas_left()
+        };
+
+        // The mock span size is 300 bytes.
+        let small = gas_used(&|env| do_set_return_data(env, 0, 1).unwrap());
+        let full = gas_used(&|env| do_set_return_data(env, 0, 300).unwrap());
+        assert_eq!(full - small, calculate_read_memory_gas(300) - calculate_read_memory_gas(1));
+
+        let small = gas_used(&|env| do_ask_external_data(env, 1, 1, 0, 1).unwrap());
+        let full = gas_used(&|env| do_ask_external_data(env, 1, 1, 0, 300).unwrap());
+        assert_eq!(full - small, calculate_read_memory_gas(300) - calculate_read_memory_gas(1));
+
+        // The mock reports 1 byte for external ID 0 and "data-1-0" for external ID 1.
+        let small = gas_used(&|env| assert_eq!(Ok(1), do_read_external_data(env, 0, 0, 0)));
+        let large = gas_used(&|env| assert_eq!(Ok(8), do_read_external_data(env, 1, 0, 0)));
+        assert_eq!(large - small, calculate_write_memory_gas(8) - calculate_write_memory_gas(1));
+
+        // The mock calldata is always 1 byte, so only the flat part can be checked here.
+        let calldata = gas_used(&|env| assert_eq!(Ok(1), do_read_calldata(env, 0)));
+        assert_eq!(calldata, IMPORTED_FUNCTION_GAS + calculate_write_memory_gas(1));
+    }
+
+    #[test]
+    fn test_do_append_return_data_too_many_segments() {
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(2_500_000_000_000);
+        owasm_env.set_max_return_segments(3);
+
+        for _ in 0..3 {
+            assert_eq!(Ok(()), do_append_return_data(&owasm_env, 0, 1));
+        }
+        assert_eq!(Err(Error::TooManyReturnSegments), do_append_return_data(&owasm_env, 0, 1));
+        assert_eq!(Some(vec![0; 3]), owasm_env.take_return_data());
+
+        // Taking the return data starts over.
+        assert_eq!(Ok(()), do_append_return_data(&owasm_env, 0, 1));
+    }
+
+    #[test]
+    fn test_do_append_return_data_span_overflow() {
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(2_500_000_000_000);
+
+        // The mock span size is 300 bytes in aggregate.
+        assert_eq!(Ok(()), do_append_return_data(&owasm_env, 0, 200));
+        assert_eq!(Ok(()), do_append_return_data(&owasm_env, 0, 100));
+        assert_eq!(Err(Error::SpanTooSmallError), do_append_return_data(&owasm_env, 0, 1));
+        assert_eq!(Err(Error::SpanTooSmallError), do_append_return_data(&owasm_env, 0, 301));
+        assert_eq!(300, owasm_env.take_return_data().unwrap().len());
+    }
+
+    #[test]
+    fn test_do_get_abi_version() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        assert_eq!(16, ABI_VERSION);
+        assert_eq!(Ok(ABI_VERSION), do_get_abi_version(&owasm_env));
+        gas_limit -= IMPORTED_FUNCTION_GAS;
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+    }
+
     #[test]
     fn test_do_get_ask_count() {
         let mut gas_limit = 2_500_000_000_000;
@@ -651,43 +1911,267 @@ mod test {
         assert_eq!(gas_limit, owasm_env.get_gas_left());
     }
 
-    #[test]
-    fn test_do_get_prepare_time() {
-        let mut gas_limit = 2_500_000_000_000;
-        let (owasm_env, instance) = create_owasm_env();
-        let instance_ptr = NonNull::from(&instance);
-        owasm_env.set_wasmer_instance(Some(instance_ptr));
-        owasm_env.set_gas_left(gas_limit);
+    #[test]
+    fn test_do_get_prepare_time() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        assert_eq!(Ok(100_000), do_get_prepare_time(&owasm_env));
+        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS;
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+    }
+
+    #[test]
+    fn test_do_get_execute_time() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        assert_eq!(Ok(100_000), do_get_execute_time(&owasm_env));
+        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS;
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+    }
+
+    #[test]
+    fn test_do_get_ans_count() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        assert_eq!(Ok(8), do_get_ans_count(&owasm_env));
+        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS;
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+    }
+
+    #[test]
+    fn test_do_get_gas_price() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        assert_eq!(Ok(25), do_get_gas_price(&owasm_env));
+        gas_limit -= IMPORTED_FUNCTION_GAS;
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+
+        // Runners that provide no gas price fail the script asking for it.
+        let owasm_env = Environment::new(ConfigurableQuerier::with_span_size(300));
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+        assert_eq!(Err(Error::WrongPeriodActionError), do_get_gas_price(&owasm_env));
+    }
+
+    #[test]
+    fn test_do_get_data_sources() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        assert_eq!(Ok(3), do_get_data_source_count(&owasm_env));
+        gas_limit -= IMPORTED_FUNCTION_GAS;
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        let ids: Result<Vec<i64>, Error> =
+            (0..3).map(|index| do_get_data_source_id(&owasm_env, index)).collect();
+        assert_eq!(ids, Ok(vec![3, 7, 11]));
+        gas_limit -= 3 * IMPORTED_FUNCTION_GAS;
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Err(Error::BadDataSourceIndexError), do_get_data_source_id(&owasm_env, 3));
+        assert_eq!(Err(Error::BadDataSourceIndexError), do_get_data_source_id(&owasm_env, -1));
+
+        // Runners that provide no data sources fail the script asking for them.
+        let owasm_env = Environment::new(ConfigurableQuerier::with_span_size(300));
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+        assert_eq!(Err(Error::WrongPeriodActionError), do_get_data_source_count(&owasm_env));
+    }
+
+    #[test]
+    fn test_do_get_external_ids() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        assert_eq!(Ok(2), do_get_external_id_count(&owasm_env));
+        gas_limit -= IMPORTED_FUNCTION_GAS;
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        let ids: Result<Vec<i64>, Error> =
+            (0..2).map(|index| do_get_external_id(&owasm_env, index)).collect();
+        assert_eq!(ids, Ok(vec![2, 1]));
+        gas_limit -= 2 * IMPORTED_FUNCTION_GAS;
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Err(Error::BadExternalIDIndexError), do_get_external_id(&owasm_env, 2));
+        assert_eq!(Err(Error::BadExternalIDIndexError), do_get_external_id(&owasm_env, -1));
+
+        // Runners that provide no external IDs fail the script asking for them.
+        let owasm_env = Environment::new(ConfigurableQuerier::with_span_size(300));
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+        assert_eq!(Err(Error::WrongPeriodActionError), do_get_external_id_count(&owasm_env));
+    }
+
+    #[test]
+    fn test_do_get_storage() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        write_memory(&owasm_env, 0, b"price".to_vec()).unwrap();
+        assert_eq!(Ok(2), do_get_storage(&owasm_env, 0, 5, 100));
+        gas_limit = gas_limit
+            - IMPORTED_FUNCTION_GAS
+            - calculate_read_memory_gas(5)
+            - calculate_write_memory_gas(2);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Ok(b"42".to_vec()), read_memory(&owasm_env, 100, 2));
+
+        // An absent key leaves the output untouched.
+        assert_eq!(Ok(STORAGE_KEY_ABSENT), do_get_storage(&owasm_env, 0, 4, 200));
+        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS - calculate_read_memory_gas(4);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Ok(vec![0; 2]), read_memory(&owasm_env, 200, 2));
+
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_get_storage(&owasm_env, 0, -1, 100));
+        assert_eq!(Err(Error::SpanTooSmallError), do_get_storage(&owasm_env, 0, i64::MAX, 100));
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+
+        // Runners that provide no state fail the script reading it.
+        let owasm_env = Environment::new(ConfigurableQuerier::with_span_size(300));
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+        assert_eq!(Err(Error::WrongPeriodActionError), do_get_storage(&owasm_env, 0, 5, 100));
+    }
+
+    #[test]
+    fn test_do_tick() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        for ticks in 1..=5 {
+            assert_eq!(Ok(ticks), do_tick(&owasm_env));
+            gas_limit -= IMPORTED_FUNCTION_GAS;
+            assert_eq!(gas_limit, owasm_env.get_gas_left());
+        }
+
+        // Without a limit, ticks keep counting; with one, the call past it fails.
+        owasm_env.set_max_ticks(7);
+        assert_eq!(Ok(6), do_tick(&owasm_env));
+        assert_eq!(Ok(7), do_tick(&owasm_env));
+        assert_eq!(Err(Error::TickLimitExceeded), do_tick(&owasm_env));
+        assert_eq!(Err(Error::TickLimitExceeded), do_tick(&owasm_env));
+
+        owasm_env.reset_ticks();
+        assert_eq!(Ok(1), do_tick(&owasm_env));
+
+        owasm_env.set_max_ticks(0);
+        owasm_env.reset_ticks();
+        assert_eq!(Err(Error::TickLimitExceeded), do_tick(&owasm_env));
+    }
+
+    #[test]
+    fn test_do_map_set_and_get() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        write_memory(&owasm_env, 0, b"BTC42000".to_vec()).unwrap();
+        assert_eq!(Ok(()), do_map_set(&owasm_env, 0, 3, 3, 5));
+        gas_limit = gas_limit
+            - IMPORTED_FUNCTION_GAS
+            - calculate_read_memory_gas(3)
+            - calculate_read_memory_gas(5);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+
+        assert_eq!(Ok(5), do_map_get(&owasm_env, 0, 3, 100));
+        gas_limit = gas_limit
+            - IMPORTED_FUNCTION_GAS
+            - calculate_read_memory_gas(3)
+            - calculate_write_memory_gas(5);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Ok(b"42000".to_vec()), read_memory(&owasm_env, 100, 5));
+
+        // Setting a key again replaces its value.
+        assert_eq!(Ok(()), do_map_set(&owasm_env, 0, 3, 3, 2));
+        assert_eq!(Ok(2), do_map_get(&owasm_env, 0, 3, 200));
+        assert_eq!(Ok(b"42".to_vec()), read_memory(&owasm_env, 200, 2));
+        gas_limit = owasm_env.get_gas_left();
+
+        // A missing key leaves the output untouched.
+        assert_eq!(Ok(MAP_KEY_ABSENT), do_map_get(&owasm_env, 0, 2, 300));
+        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS - calculate_read_memory_gas(2);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Ok(vec![0; 2]), read_memory(&owasm_env, 300, 2));
 
-        assert_eq!(Ok(100_000), do_get_prepare_time(&owasm_env));
-        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS;
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_map_set(&owasm_env, 0, -1, 0, 0));
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_map_set(&owasm_env, 0, 0, 0, -1));
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_map_get(&owasm_env, 0, -1, 0));
+        assert_eq!(Err(Error::SpanTooSmallError), do_map_set(&owasm_env, 0, 0, 0, i64::MAX));
+        assert_eq!(Err(Error::SpanTooSmallError), do_map_get(&owasm_env, 0, i64::MAX, 0));
         assert_eq!(gas_limit, owasm_env.get_gas_left());
     }
 
     #[test]
-    fn test_do_get_execute_time() {
-        let mut gas_limit = 2_500_000_000_000;
+    fn test_do_map_set_entry_limit() {
         let (owasm_env, instance) = create_owasm_env();
         let instance_ptr = NonNull::from(&instance);
         owasm_env.set_wasmer_instance(Some(instance_ptr));
-        owasm_env.set_gas_left(gas_limit);
+        owasm_env.set_gas_left(u64::MAX);
 
-        assert_eq!(Ok(100_000), do_get_execute_time(&owasm_env));
-        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS;
-        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        for key in 0..MAX_MAP_ENTRIES as u32 {
+            write_memory(&owasm_env, 0, key.to_le_bytes().to_vec()).unwrap();
+            assert_eq!(Ok(()), do_map_set(&owasm_env, 0, 4, 0, 4));
+        }
+        write_memory(&owasm_env, 0, (MAX_MAP_ENTRIES as u32).to_le_bytes().to_vec()).unwrap();
+        assert_eq!(Err(Error::MapEntriesLimitExceeded), do_map_set(&owasm_env, 0, 4, 0, 4));
+        assert_eq!(Ok(MAP_KEY_ABSENT), do_map_get(&owasm_env, 0, 4, 100));
+
+        // Keys already in the full map can still be updated.
+        write_memory(&owasm_env, 0, 7_u32.to_le_bytes().to_vec()).unwrap();
+        assert_eq!(Ok(()), do_map_set(&owasm_env, 0, 4, 8, 1));
+        assert_eq!(Ok(1), do_map_get(&owasm_env, 0, 4, 100));
     }
 
     #[test]
-    fn test_do_get_ans_count() {
-        let mut gas_limit = 2_500_000_000_000;
+    fn test_do_ask_external_data_indexed() {
         let (owasm_env, instance) = create_owasm_env();
         let instance_ptr = NonNull::from(&instance);
         owasm_env.set_wasmer_instance(Some(instance_ptr));
-        owasm_env.set_gas_left(gas_limit);
+        owasm_env.set_gas_left(2_500_000_000_000);
 
-        assert_eq!(Ok(8), do_get_ans_count(&owasm_env));
-        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS;
-        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Ok(0), do_ask_external_data_indexed(&owasm_env, 1, 1, 0, 0));
+        assert_eq!(Ok(()), do_ask_external_data(&owasm_env, 2, 1, 0, 0));
+        assert_eq!(Ok(2), do_ask_external_data_indexed(&owasm_env, 3, 1, 0, 0));
+        // A failed request is not assigned an index.
+        assert_eq!(
+            Err(Error::MemoryOutOfBoundError),
+            do_ask_external_data_indexed(&owasm_env, 4, 1, -1, 0)
+        );
+        assert_eq!(Ok(3), do_ask_external_data_indexed(&owasm_env, 5, 1, 0, 0));
+        let eids: Vec<i64> =
+            owasm_env.finalize().external_requests.iter().map(|request| request.eid).collect();
+        assert_eq!(eids, vec![1, 2, 3, 5]);
     }
 
     #[test]
@@ -746,6 +2230,25 @@ mod test {
         assert_eq!(gas_limit, owasm_env.get_gas_left());
     }
 
+    #[test]
+    fn test_external_data_budget() {
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(2_500_000_000_000);
+        owasm_env.set_external_data_budget(10);
+
+        assert_eq!(Ok(()), do_ask_external_data(&owasm_env, 1, 1, 0, 4));
+        assert_eq!(Ok(()), do_ask_external_data(&owasm_env, 2, 1, 0, 6));
+        assert_eq!(Err(Error::DataBudgetExceeded), do_ask_external_data(&owasm_env, 3, 1, 0, 1));
+        assert_eq!(Ok(()), do_ask_external_data(&owasm_env, 3, 1, 0, 0));
+
+        owasm_env.set_wasmer_instance(None);
+        let eids: Vec<i64> =
+            owasm_env.finalize().external_requests.iter().map(|request| request.eid).collect();
+        assert_eq!(eids, vec![1, 2, 3]);
+    }
+
     #[test]
     fn test_do_get_external_data_status() {
         let mut gas_limit = 2_500_000_000_000;
@@ -794,6 +2297,230 @@ mod test {
         assert_eq!(gas_limit, owasm_env.get_gas_left());
     }
 
+    #[test]
+    fn test_do_peek_external_data_len() {
+        let gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        assert_eq!(Ok(8), do_peek_external_data_len(&owasm_env, 1, 2));
+        assert_eq!(gas_limit - IMPORTED_FUNCTION_GAS, owasm_env.get_gas_left());
+        assert_eq!(Ok(8), do_read_external_data(&owasm_env, 1, 2, 0));
+
+        assert_eq!(Ok(1), do_peek_external_data_len(&owasm_env, 0, 0));
+        assert_eq!(Ok(1), do_read_external_data(&owasm_env, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_do_read_external_data_grow() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+        let page = WASM_PAGE_SIZE as i64;
+
+        // The 8 bytes of data straddle the end of the 100-page memory.
+        let ptr = 100 * page - 4;
+        assert_eq!(Err(Error::MemoryOutOfBoundError), do_read_external_data(&owasm_env, 1, 2, ptr));
+        gas_limit -= IMPORTED_FUNCTION_GAS + calculate_write_memory_gas(8);
+        assert_eq!(Ok(8), do_read_external_data_grow(&owasm_env, 1, 2, ptr));
+        gas_limit -=
+            IMPORTED_FUNCTION_GAS + calculate_write_memory_gas(8) + MEMORY_GROW_GAS_PER_PAGE;
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(owasm_env.memory().unwrap().size(), Pages(101));
+        assert_eq!(read_memory(&owasm_env, ptr, 8), Ok(b"data-1-2".to_vec()));
+
+        // Data already fitting in the memory is written without growing it.
+        assert_eq!(Ok(8), do_read_external_data_grow(&owasm_env, 1, 2, 0));
+        gas_limit -= IMPORTED_FUNCTION_GAS + calculate_write_memory_gas(8);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(owasm_env.memory().unwrap().size(), Pages(101));
+
+        // The memory never grows past the limit.
+        let ptr = MEMORY_LIMIT as i64 * page - 4;
+        assert_eq!(
+            Err(Error::MemoryOutOfBoundError),
+            do_read_external_data_grow(&owasm_env, 1, 2, ptr)
+        );
+        assert_eq!(
+            Err(Error::MemoryOutOfBoundError),
+            do_read_external_data_grow(&owasm_env, 1, 2, -1)
+        );
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(owasm_env.memory().unwrap().size(), Pages(101));
+    }
+
+    #[test]
+    fn test_do_read_all_external_data() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        // Validators with an even index report data, out of the 10 asked.
+        let mut expected = vec![];
+        for vid in [0, 2, 4, 6, 8] {
+            expected.extend_from_slice(&8_i64.to_le_bytes());
+            expected.extend(format!("data-1-{}", vid).into_bytes());
+        }
+        assert_eq!(Ok(80), do_read_all_external_data(&owasm_env, 1, 0));
+        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS - calculate_write_memory_gas(80);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Ok(expected), read_memory(&owasm_env, 0, 80));
+
+        // No validator reports data for external ID 0.
+        assert_eq!(Ok(0), do_read_all_external_data(&owasm_env, 0, 0));
+        assert_eq!(Err(Error::MemoryOutOfBoundError), do_read_all_external_data(&owasm_env, 1, -1));
+    }
+
+    #[test]
+    fn test_offsets_near_u32_max() {
+        let max = u32::MAX as i64;
+        assert_eq!(Ok(u32::MAX as usize), safe_add(max, 0));
+        assert_eq!(Ok(u32::MAX as usize), safe_add(max - 1, 1));
+        assert_eq!(Err(Error::MemoryOutOfBoundError), safe_add(max, 1));
+        assert_eq!(Err(Error::MemoryOutOfBoundError), safe_add(max + 1, 0));
+        assert_eq!(Err(Error::MemoryOutOfBoundError), safe_add(1, max));
+
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(2_500_000_000_000);
+        assert_eq!(Err(Error::MemoryOutOfBoundError), do_read_calldata(&owasm_env, max));
+        assert_eq!(Err(Error::MemoryOutOfBoundError), do_read_calldata(&owasm_env, max + 1));
+        assert_eq!(Err(Error::MemoryOutOfBoundError), do_set_return_data(&owasm_env, max, 1));
+        assert_eq!(
+            Err(Error::MemoryOutOfBoundError),
+            do_read_external_data(&owasm_env, 1, 2, max + 1)
+        );
+        assert_eq!(
+            Err(Error::MemoryOutOfBoundError),
+            do_read_external_data_range(&owasm_env, 1, 2, max + 1, 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_do_read_external_data_range() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        // The mock serves "data-1-2" for eid 1 from validator 2.
+        assert_eq!(Ok(3), do_read_external_data_range(&owasm_env, 1, 2, 2, 0, 3));
+        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS - calculate_write_memory_gas(3);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Ok(b"ta-".to_vec()), read_memory(&owasm_env, 0, 3));
+
+        assert_eq!(Ok(0), do_read_external_data_range(&owasm_env, 1, 2, 8, 0, 0));
+        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS - calculate_write_memory_gas(0);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+
+        assert_eq!(
+            Err(Error::MemoryOutOfBoundError),
+            do_read_external_data_range(&owasm_env, 1, 2, 6, 0, 3)
+        );
+        assert_eq!(
+            Err(Error::MemoryOutOfBoundError),
+            do_read_external_data_range(&owasm_env, 1, 2, 9, 0, 0)
+        );
+        assert_eq!(
+            Err(Error::DataLengthOutOfBound),
+            do_read_external_data_range(&owasm_env, 1, 2, -1, 0, 3)
+        );
+        assert_eq!(
+            Err(Error::DataLengthOutOfBound),
+            do_read_external_data_range(&owasm_env, 1, 2, 0, 0, -1)
+        );
+        assert_eq!(
+            Err(Error::SpanTooSmallError),
+            do_read_external_data_range(&owasm_env, 1, 2, 0, 0, i64::MAX)
+        );
+        assert_eq!(
+            Err(Error::MemoryOutOfBoundError),
+            do_read_external_data_range(&owasm_env, 1, 2, i64::MAX, 0, 1)
+        );
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+    }
+
+    #[test]
+    fn test_do_json_extract_f64() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        let json = br#"{"data": {"prices": [{"usd": 42100.25}, {"usd": "n/a"}]}}"#.to_vec();
+        let json_len = json.len() as i64;
+        write_memory(&owasm_env, 0, json).unwrap();
+        let mut extract = |path: &[u8]| {
+            write_memory(&owasm_env, 100, path.to_vec()).unwrap();
+            let status = do_json_extract_f64(&owasm_env, 0, json_len, 100, path.len() as i64, 200);
+            gas_limit = gas_limit
+                - IMPORTED_FUNCTION_GAS
+                - calculate_read_memory_gas(json_len)
+                - calculate_read_memory_gas(path.len() as i64);
+            if status == Ok(JSON_EXTRACT_OK) {
+                gas_limit -= calculate_write_memory_gas(8);
+            }
+            assert_eq!(gas_limit, owasm_env.get_gas_left());
+            status
+        };
+
+        assert_eq!(Ok(JSON_EXTRACT_OK), extract(b"data.prices.0.usd"));
+        let result = read_memory(&owasm_env, 200, 8).unwrap();
+        assert_eq!(42100.25, f64::from_le_bytes(result.try_into().unwrap()));
+        assert_eq!(Ok(JSON_EXTRACT_PATH_NOT_FOUND), extract(b"data.prices.2.usd"));
+        assert_eq!(Ok(JSON_EXTRACT_PATH_NOT_FOUND), extract(b"data.volume"));
+        assert_eq!(Ok(JSON_EXTRACT_NOT_A_NUMBER), extract(b"data.prices.1.usd"));
+        assert_eq!(Ok(JSON_EXTRACT_NOT_A_NUMBER), extract(b"data.prices"));
+
+        owasm_env.set_deterministic(true);
+        assert_eq!(Ok(JSON_EXTRACT_OK), extract(b"data.prices.0.usd"));
+        let result = read_memory(&owasm_env, 200, 8).unwrap();
+        assert_eq!(42100_250000000, i64::from_le_bytes(result.try_into().unwrap()));
+    }
+
+    #[test]
+    fn test_do_json_extract_f64_invalid_input() {
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(2_500_000_000_000);
+
+        write_memory(&owasm_env, 0, b"{\"usd\": 1e999, \"eur\" 1}".to_vec()).unwrap();
+        write_memory(&owasm_env, 100, b"usd".to_vec()).unwrap();
+        write_memory(&owasm_env, 110, b"eur".to_vec()).unwrap();
+        write_memory(&owasm_env, 120, vec![0xff]).unwrap();
+
+        assert_eq!(
+            Ok(JSON_EXTRACT_OUT_OF_RANGE),
+            do_json_extract_f64(&owasm_env, 0, 23, 100, 3, 200)
+        );
+        assert_eq!(
+            Ok(JSON_EXTRACT_INVALID_JSON),
+            do_json_extract_f64(&owasm_env, 0, 23, 110, 3, 200)
+        );
+        assert_eq!(
+            Err(Error::InvalidEncodingError),
+            do_json_extract_f64(&owasm_env, 0, 23, 120, 1, 200)
+        );
+        assert_eq!(
+            Err(Error::DataLengthOutOfBound),
+            do_json_extract_f64(&owasm_env, 0, -1, 100, 3, 200)
+        );
+        assert_eq!(
+            Err(Error::SpanTooSmallError),
+            do_json_extract_f64(&owasm_env, 0, 23, 100, i64::MAX, 200)
+        );
+    }
+
     #[test]
     fn test_do_ecvrf_verify() {
         let mut gas_limit = 100_000_000_000_000;
@@ -887,4 +2614,228 @@ mod test {
         );
         assert_eq!(gas_limit, owasm_env.get_gas_left());
     }
+
+    #[test]
+    fn test_const_time_eq() {
+        assert!(const_time_eq(b"", b""));
+        assert!(const_time_eq(b"beeb", b"beeb"));
+        assert!(!const_time_eq(b"beeb", b"beef"));
+        assert!(!const_time_eq(b"beeb", b"beebs"));
+        assert!(!const_time_eq(b"", b"\0"));
+    }
+
+    #[test]
+    fn test_do_verify_external_data_hash() {
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(2_500_000_000_000);
+
+        // The mock reports "data-1-0" for external ID 1 and validator 0.
+        write_memory(&owasm_env, 0, Sha256::digest(b"data-1-0").to_vec()).unwrap();
+        let gas_left = owasm_env.get_gas_left();
+        assert_eq!(Ok(1), do_verify_external_data_hash(&owasm_env, 1, 0, 0));
+        assert_eq!(
+            gas_left - owasm_env.get_gas_left(),
+            IMPORTED_FUNCTION_GAS + calculate_read_memory_gas(32) + calculate_read_memory_gas(8)
+        );
+        assert_eq!(Ok(0), do_verify_external_data_hash(&owasm_env, 1, 1, 0));
+
+        write_memory(&owasm_env, 0, vec![0; 32]).unwrap();
+        assert_eq!(Ok(0), do_verify_external_data_hash(&owasm_env, 1, 0, 0));
+        assert_eq!(
+            Err(Error::MemoryOutOfBoundError),
+            do_verify_external_data_hash(&owasm_env, 1, 0, -1)
+        );
+    }
+
+    #[test]
+    fn test_do_sort_u64() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        let values: Vec<u8> =
+            [42_u64, u64::MAX, 0, 7, 42].iter().flat_map(|value| value.to_le_bytes()).collect();
+        write_memory(&owasm_env, 0, values).unwrap();
+        gas_limit = owasm_env.get_gas_left();
+
+        assert_eq!(Ok(()), do_sort_u64(&owasm_env, 0, 5));
+        gas_limit = gas_limit
+            - IMPORTED_FUNCTION_GAS
+            - calculate_read_memory_gas(40)
+            - calculate_write_memory_gas(40);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        let sorted: Vec<u8> =
+            [0_u64, 7, 42, 42, u64::MAX].iter().flat_map(|value| value.to_le_bytes()).collect();
+        assert_eq!(Ok(sorted), read_memory(&owasm_env, 0, 40));
+
+        assert_eq!(Ok(()), do_sort_u64(&owasm_env, 0, 0));
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_sort_u64(&owasm_env, 0, -1));
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_sort_u64(&owasm_env, 0, i64::MAX));
+        assert_eq!(Err(Error::SpanTooSmallError), do_sort_u64(&owasm_env, 0, 38));
+        assert_eq!(Err(Error::MemoryOutOfBoundError), do_sort_u64(&owasm_env, -1, 1));
+    }
+
+    #[test]
+    fn test_do_const_time_eq() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        write_memory(&owasm_env, 0, b"beeb".to_vec()).unwrap();
+        write_memory(&owasm_env, 4, b"beeb".to_vec()).unwrap();
+        write_memory(&owasm_env, 8, b"beef".to_vec()).unwrap();
+
+        // equal
+        assert_eq!(Ok(1), do_const_time_eq(&owasm_env, 0, 4, 4, 4));
+        gas_limit = gas_limit
+            - IMPORTED_FUNCTION_GAS
+            - calculate_read_memory_gas(4)
+            - calculate_read_memory_gas(4);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+
+        // unequal with the same length
+        assert_eq!(Ok(0), do_const_time_eq(&owasm_env, 0, 4, 8, 4));
+        gas_limit = gas_limit
+            - IMPORTED_FUNCTION_GAS
+            - calculate_read_memory_gas(4)
+            - calculate_read_memory_gas(4);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+
+        // different lengths with an equal prefix
+        assert_eq!(Ok(0), do_const_time_eq(&owasm_env, 0, 4, 4, 3));
+        gas_limit = gas_limit
+            - IMPORTED_FUNCTION_GAS
+            - calculate_read_memory_gas(4)
+            - calculate_read_memory_gas(3);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_const_time_eq(&owasm_env, 0, -1, 0, 0));
+        assert_eq!(Err(Error::SpanTooSmallError), do_const_time_eq(&owasm_env, 0, 0, 0, i64::MAX));
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+
+        assert_eq!(Err(Error::MemoryOutOfBoundError), do_const_time_eq(&owasm_env, -1, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_do_base64_round_trip() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        write_memory(&owasm_env, 0, vec![0xfb, 0xff, 0x62, 0x65, 0x65, 0x62]).unwrap();
+
+        for url_safe in [0, 1] {
+            assert_eq!(Ok(8), do_base64_encode(&owasm_env, 0, 6, 100, url_safe));
+            gas_limit = gas_limit
+                - IMPORTED_FUNCTION_GAS
+                - calculate_read_memory_gas(6)
+                - calculate_write_memory_gas(8);
+            assert_eq!(gas_limit, owasm_env.get_gas_left());
+            let expected: &[u8] = if url_safe == 0 { b"+/9iZWVi" } else { b"-_9iZWVi" };
+            assert_eq!(Ok(expected.to_vec()), read_memory(&owasm_env, 100, 8));
+
+            assert_eq!(Ok(6), do_base64_decode(&owasm_env, 100, 8, 200, url_safe));
+            gas_limit = gas_limit
+                - IMPORTED_FUNCTION_GAS
+                - calculate_read_memory_gas(8)
+                - calculate_write_memory_gas(6);
+            assert_eq!(gas_limit, owasm_env.get_gas_left());
+            assert_eq!(read_memory(&owasm_env, 0, 6), read_memory(&owasm_env, 200, 6));
+        }
+    }
+
+    #[test]
+    fn test_do_base64_decode_invalid_input() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        write_memory(&owasm_env, 0, b"-_9iZWVi".to_vec()).unwrap();
+        assert_eq!(Err(Error::InvalidEncodingError), do_base64_decode(&owasm_env, 0, 8, 100, 0));
+        gas_limit = gas_limit - IMPORTED_FUNCTION_GAS - calculate_read_memory_gas(8);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_base64_decode(&owasm_env, 0, -1, 100, 0));
+        assert_eq!(
+            Err(Error::SpanTooSmallError),
+            do_base64_decode(&owasm_env, 0, i64::MAX, 100, 0)
+        );
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_base64_encode(&owasm_env, 0, -1, 100, 0));
+        assert_eq!(
+            Err(Error::SpanTooSmallError),
+            do_base64_encode(&owasm_env, 0, i64::MAX, 100, 0)
+        );
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+
+        // The encoded form of a full span no longer fits in a span.
+        assert_eq!(Err(Error::SpanTooSmallError), do_base64_encode(&owasm_env, 0, 300, 400, 0));
+    }
+
+    #[test]
+    fn test_do_hex_round_trip() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        write_memory(&owasm_env, 0, vec![0x00, 0xab, 0x62, 0xff]).unwrap();
+        assert_eq!(Ok(8), do_hex_encode(&owasm_env, 0, 4, 100));
+        gas_limit = gas_limit
+            - IMPORTED_FUNCTION_GAS
+            - calculate_read_memory_gas(4)
+            - calculate_write_memory_gas(8);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(Ok(b"00ab62ff".to_vec()), read_memory(&owasm_env, 100, 8));
+
+        assert_eq!(Ok(4), do_hex_decode(&owasm_env, 100, 8, 200));
+        gas_limit = gas_limit
+            - IMPORTED_FUNCTION_GAS
+            - calculate_read_memory_gas(8)
+            - calculate_write_memory_gas(4);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+        assert_eq!(read_memory(&owasm_env, 0, 4), read_memory(&owasm_env, 200, 4));
+
+        // Upper-case digits decode too.
+        write_memory(&owasm_env, 100, b"00AB62FF".to_vec()).unwrap();
+        assert_eq!(Ok(4), do_hex_decode(&owasm_env, 100, 8, 200));
+        assert_eq!(read_memory(&owasm_env, 0, 4), read_memory(&owasm_env, 200, 4));
+    }
+
+    #[test]
+    fn test_do_hex_invalid_input() {
+        let mut gas_limit = 2_500_000_000_000;
+        let (owasm_env, instance) = create_owasm_env();
+        let instance_ptr = NonNull::from(&instance);
+        owasm_env.set_wasmer_instance(Some(instance_ptr));
+        owasm_env.set_gas_left(gas_limit);
+
+        write_memory(&owasm_env, 0, b"0xab1".to_vec()).unwrap();
+        assert_eq!(Ok(HEX_DECODE_ODD_LENGTH), do_hex_decode(&owasm_env, 2, 3, 100));
+        assert_eq!(Ok(HEX_DECODE_INVALID_DIGIT), do_hex_decode(&owasm_env, 0, 4, 100));
+        gas_limit = gas_limit
+            - 2 * IMPORTED_FUNCTION_GAS
+            - calculate_read_memory_gas(3)
+            - calculate_read_memory_gas(4);
+        assert_eq!(gas_limit, owasm_env.get_gas_left());
+
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_hex_decode(&owasm_env, 0, -1, 100));
+        assert_eq!(Err(Error::SpanTooSmallError), do_hex_decode(&owasm_env, 0, 301, 100));
+        assert_eq!(Err(Error::DataLengthOutOfBound), do_hex_encode(&owasm_env, 0, -1, 100));
+        // The mock span size is 300 bytes, so at most 150 bytes can be encoded.
+        assert_eq!(Ok(300), do_hex_encode(&owasm_env, 0, 150, 100));
+        assert_eq!(Err(Error::SpanTooSmallError), do_hex_encode(&owasm_env, 0, 151, 100));
+        assert_eq!(Err(Error::SpanTooSmallError), do_hex_encode(&owasm_env, 0, i64::MAX, 100));
+        assert_eq!(Err(Error::MemoryOutOfBoundError), do_hex_encode(&owasm_env, -1, 1, 100));
+    }
 }
@@ -0,0 +1,136 @@
+use loupe::MemoryUsage;
+use std::fmt;
+use std::sync::Mutex;
+use wasmer::wasmparser::Operator;
+use wasmer::{
+    ExportIndex, FunctionMiddleware, GlobalInit, GlobalType, Instance, LocalFunctionIndex,
+    MiddlewareError, MiddlewareReaderState, ModuleMiddleware, Mutability, Type, Value,
+};
+use wasmer_types::{GlobalIndex, ModuleInfo};
+
+// The global holding the number of operators executed so far.
+const COUNT_GLOBAL: &str = "owasm_instruction_count";
+// The global the metering middleware keeps the remaining points in.
+const METERING_REMAINING_POINTS: &str = "wasmer_metering_remaining_points";
+
+#[derive(Debug, Clone, Copy, MemoryUsage)]
+struct CounterGlobals {
+    count: GlobalIndex,
+    /// The remaining points of the metering middleware, if it runs before the counter.
+    remaining_points: Option<GlobalIndex>,
+}
+
+/// A middleware counting the Wasm operators an instance executes, for tooling reporting it
+/// next to the gas used. It must come after the metering middleware, so that its own
+/// operators are never charged, and it leaves out the operators the metering middleware adds.
+/// Like the metering middleware, each counter must only ever compile one module.
+#[derive(Default, MemoryUsage)]
+pub(crate) struct InstructionCounter {
+    globals: Mutex<Option<CounterGlobals>>,
+}
+
+impl fmt::Debug for InstructionCounter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InstructionCounter").finish()
+    }
+}
+
+impl ModuleMiddleware for InstructionCounter {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        let globals = self.globals.lock().unwrap_or_else(|err| err.into_inner());
+        Box::new(FunctionCounter {
+            globals: globals.expect("module info is transformed before any function"),
+            count: 0,
+            in_metering: false,
+        })
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        let mut globals = self.globals.lock().unwrap_or_else(|err| err.into_inner());
+        assert!(globals.is_none(), "an instruction counter must only compile one module");
+
+        let count = module_info.globals.push(GlobalType::new(Type::I64, Mutability::Var));
+        module_info.global_initializers.push(GlobalInit::I64Const(0));
+        module_info.exports.insert(COUNT_GLOBAL.to_string(), ExportIndex::Global(count));
+        let remaining_points = match module_info.exports.get(METERING_REMAINING_POINTS) {
+            Some(ExportIndex::Global(index)) => Some(*index),
+            _ => None,
+        };
+        *globals = Some(CounterGlobals { count, remaining_points });
+    }
+}
+
+#[derive(Debug)]
+struct FunctionCounter {
+    globals: CounterGlobals,
+    /// The operators of the current basic block not counted yet.
+    count: u64,
+    /// Whether the operators fed are the gas check the metering middleware adds, which starts
+    /// by reading the remaining points and ends by writing them.
+    in_metering: bool,
+}
+
+impl FunctionMiddleware for FunctionCounter {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        let remaining_points = self.globals.remaining_points.map(|index| index.as_u32());
+        match operator {
+            Operator::GlobalGet { global_index } if Some(global_index) == remaining_points => {
+                self.in_metering = true;
+            }
+            Operator::GlobalSet { global_index } if Some(global_index) == remaining_points => {
+                self.in_metering = false;
+                state.push_operator(operator);
+                return Ok(());
+            }
+            _ => {}
+        }
+        if self.in_metering {
+            state.push_operator(operator);
+            return Ok(());
+        }
+
+        self.count += 1;
+        // The same branch sources and targets the metering middleware ends its blocks at.
+        if matches!(
+            operator,
+            Operator::Loop { .. }
+                | Operator::End
+                | Operator::Else
+                | Operator::Br { .. }
+                | Operator::BrTable { .. }
+                | Operator::BrIf { .. }
+                | Operator::Call { .. }
+                | Operator::CallIndirect { .. }
+                | Operator::Return
+        ) {
+            let count = self.globals.count.as_u32();
+            state.extend(&[
+                Operator::GlobalGet { global_index: count },
+                Operator::I64Const { value: self.count as i64 },
+                Operator::I64Add,
+                Operator::GlobalSet { global_index: count },
+            ]);
+            self.count = 0;
+        }
+        state.push_operator(operator);
+        Ok(())
+    }
+}
+
+/// Returns the operators the instance executed since the count was last reset, or None if
+/// it was compiled without an `InstructionCounter`.
+pub(crate) fn get_instruction_count(instance: &Instance) -> Option<u64> {
+    let count = instance.exports.get_global(COUNT_GLOBAL).ok()?.get().i64()?;
+    Some(count as u64)
+}
+
+/// Restarts counting from zero, e.g. before running another entry point of the instance.
+pub(crate) fn reset_instruction_count(instance: &Instance) {
+    if let Ok(global) = instance.exports.get_global(COUNT_GLOBAL) {
+        let _ = global.set(Value::I64(0));
+    }
+}
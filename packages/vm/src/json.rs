@@ -0,0 +1,379 @@
+// The deepest nesting of objects and arrays we are willing to walk through.
+static MAX_DEPTH: usize = 64;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JsonError {
+    Invalid,   // The document is not valid JSON up to the point we needed to read.
+    NotFound,  // The path does not lead to any value in the document.
+    NotNumber, // The path leads to a value that is not a number.
+}
+
+/// Returns the text of the number found by following the given dotted `path` through the
+/// JSON document. Object members are selected by key and array elements by their decimal
+/// index, e.g. `data.0.price`. The first member wins if an object repeats a key.
+pub fn extract_number<'a>(json: &'a [u8], path: &str) -> Result<&'a str, JsonError> {
+    let segments: Vec<&str> = if path.is_empty() { vec![] } else { path.split('.').collect() };
+    Parser { data: json, pos: 0 }.find(&segments, 0)
+}
+
+/// Converts the text of a JSON number into an integer scaled by `10^decimals`, truncating
+/// any digits beyond that precision toward zero. Returns None if the result overflows i64.
+pub fn to_fixed_point(number: &str, decimals: u32) -> Option<i64> {
+    let (negative, rest) = match number.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, number),
+    };
+    let (mantissa, exponent) = match rest.find(['e', 'E']) {
+        Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+        None => (rest, "0"),
+    };
+    let (integral, fraction) = match mantissa.find('.') {
+        Some(idx) => (&mantissa[..idx], &mantissa[idx + 1..]),
+        None => (mantissa, ""),
+    };
+    let digits: String = integral.chars().chain(fraction.chars()).collect();
+    if digits.chars().all(|c| c == '0') {
+        return Some(0);
+    }
+
+    let exponent = exponent.strip_prefix('+').unwrap_or(exponent);
+    let shift = match exponent.parse::<i64>() {
+        Ok(exponent) => {
+            exponent.checked_sub(fraction.len() as i64)?.checked_add(decimals as i64)?
+        }
+        // Exponents this large are only parseable as out of range or as zero.
+        Err(_) if exponent.starts_with('-') => return Some(0),
+        Err(_) => return None,
+    };
+
+    let value = if shift >= 0 {
+        let scale = 10i128.checked_pow(u32::try_from(shift).ok()?)?;
+        digits.parse::<i128>().ok()?.checked_mul(scale)?
+    } else {
+        let keep = digits.len() as i64 + shift;
+        if keep <= 0 {
+            return Some(0);
+        }
+        digits[..keep as usize].parse::<i128>().ok()?
+    };
+    i64::try_from(if negative { -value } else { value }).ok()
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return Err(JsonError::Invalid);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn find(&mut self, path: &[&str], depth: usize) -> Result<&'a str, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(JsonError::Invalid);
+        }
+        self.skip_whitespace();
+        let (segment, rest) = match path.split_first() {
+            Some(split) => split,
+            None => {
+                return match self.peek() {
+                    Some(b'-' | b'0'..=b'9') => {
+                        let number = self.parse_number()?;
+                        // Reject numbers running into garbage such as the "1" in "01".
+                        self.skip_whitespace();
+                        match self.peek() {
+                            None | Some(b',' | b'}' | b']') => Ok(number),
+                            _ => Err(JsonError::Invalid),
+                        }
+                    }
+                    _ => self.skip_value(depth).and(Err(JsonError::NotNumber)),
+                };
+            }
+        };
+
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                if self.is_empty(b'}') {
+                    return Err(JsonError::NotFound);
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.parse_string()?;
+                    self.expect(b':')?;
+                    if key == *segment {
+                        return self.find(rest, depth + 1);
+                    }
+                    self.skip_value(depth + 1)?;
+                    if self.end_of(b'}')? {
+                        return Err(JsonError::NotFound);
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let index = segment.parse::<usize>().map_err(|_| JsonError::NotFound)?;
+                if self.is_empty(b']') {
+                    return Err(JsonError::NotFound);
+                }
+                for idx in 0.. {
+                    if idx == index {
+                        return self.find(rest, depth + 1);
+                    }
+                    self.skip_value(depth + 1)?;
+                    if self.end_of(b']')? {
+                        break;
+                    }
+                }
+                Err(JsonError::NotFound)
+            }
+            _ => self.skip_value(depth).and(Err(JsonError::NotFound)),
+        }
+    }
+
+    // Consumes the closing character right after an opening one, returning whether it was there.
+    fn is_empty(&mut self, close: u8) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(close) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    // Consumes the closing character or a separating comma, returning whether it was the end.
+    fn end_of(&mut self, close: u8) -> Result<bool, JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) if c == close => {
+                self.pos += 1;
+                Ok(true)
+            }
+            Some(b',') => {
+                self.pos += 1;
+                Ok(false)
+            }
+            _ => Err(JsonError::Invalid),
+        }
+    }
+
+    fn skip_value(&mut self, depth: usize) -> Result<(), JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(JsonError::Invalid);
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                if self.is_empty(b'}') {
+                    return Ok(());
+                }
+                loop {
+                    self.skip_whitespace();
+                    self.parse_string()?;
+                    self.expect(b':')?;
+                    self.skip_value(depth + 1)?;
+                    if self.end_of(b'}')? {
+                        return Ok(());
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                if self.is_empty(b']') {
+                    return Ok(());
+                }
+                loop {
+                    self.skip_value(depth + 1)?;
+                    if self.end_of(b']')? {
+                        return Ok(());
+                    }
+                }
+            }
+            Some(b'"') => self.parse_string().map(|_| ()),
+            Some(b'-' | b'0'..=b'9') => self.parse_number().map(|_| ()),
+            _ => {
+                for literal in [&b"true"[..], b"false", b"null"] {
+                    if self.data[self.pos..].starts_with(literal) {
+                        self.pos += literal.len();
+                        return Ok(());
+                    }
+                }
+                Err(JsonError::Invalid)
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<&'a str, JsonError> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => self.skip_digits(),
+            _ => return Err(JsonError::Invalid),
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            self.require_digits()?;
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            self.require_digits()?;
+        }
+        // The number only consists of ASCII characters checked above.
+        std::str::from_utf8(&self.data[start..self.pos]).map_err(|_| JsonError::Invalid)
+    }
+
+    fn skip_digits(&mut self) {
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn require_digits(&mut self) -> Result<(), JsonError> {
+        let start = self.pos;
+        self.skip_digits();
+        if self.pos == start {
+            return Err(JsonError::Invalid);
+        }
+        Ok(())
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        if self.peek() != Some(b'"') {
+            return Err(JsonError::Invalid);
+        }
+        self.pos += 1;
+        let mut decoded: Vec<u16> = vec![];
+        let mut raw: Vec<u8> = vec![];
+        loop {
+            let c = self.peek().ok_or(JsonError::Invalid)?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self.peek().ok_or(JsonError::Invalid)?;
+                    self.pos += 1;
+                    let unit = match escaped {
+                        b'"' => 0x22,
+                        b'\\' => 0x5c,
+                        b'/' => 0x2f,
+                        b'b' => 0x08,
+                        b'f' => 0x0c,
+                        b'n' => 0x0a,
+                        b'r' => 0x0d,
+                        b't' => 0x09,
+                        b'u' => {
+                            let hex =
+                                self.data.get(self.pos..self.pos + 4).ok_or(JsonError::Invalid)?;
+                            self.pos += 4;
+                            let hex = std::str::from_utf8(hex).map_err(|_| JsonError::Invalid)?;
+                            u16::from_str_radix(hex, 16).map_err(|_| JsonError::Invalid)?
+                        }
+                        _ => return Err(JsonError::Invalid),
+                    };
+                    decoded.extend(Self::utf16(&raw)?);
+                    raw.clear();
+                    decoded.push(unit);
+                }
+                0x00..=0x1f => return Err(JsonError::Invalid),
+                _ => raw.push(c),
+            }
+        }
+        decoded.extend(Self::utf16(&raw)?);
+        String::from_utf16(&decoded).map_err(|_| JsonError::Invalid)
+    }
+
+    fn utf16(raw: &[u8]) -> Result<Vec<u16>, JsonError> {
+        Ok(std::str::from_utf8(raw).map_err(|_| JsonError::Invalid)?.encode_utf16().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static DOCUMENT: &[u8] = br#"{
+        "symbol": "BTC",
+        "raw": {"price": 42100.25, "volume": -3e2, "ok": true, "tags": ["a", {"b": null}]},
+        "data": [{"price": 1}, {"price": 2.5, "price": 7}],
+        "we\"irdA": 0.125
+    }"#;
+
+    #[test]
+    fn extract_number_works() {
+        assert_eq!(extract_number(DOCUMENT, "raw.price"), Ok("42100.25"));
+        assert_eq!(extract_number(DOCUMENT, "raw.volume"), Ok("-3e2"));
+        assert_eq!(extract_number(DOCUMENT, "data.0.price"), Ok("1"));
+        assert_eq!(extract_number(DOCUMENT, "data.1.price"), Ok("2.5"));
+        assert_eq!(extract_number(DOCUMENT, "we\"irdA"), Ok("0.125"));
+        assert_eq!(extract_number(b" 12 ", ""), Ok("12"));
+    }
+
+    #[test]
+    fn extract_number_missing_path() {
+        assert_eq!(extract_number(DOCUMENT, "raw.missing"), Err(JsonError::NotFound));
+        assert_eq!(extract_number(DOCUMENT, "data.2.price"), Err(JsonError::NotFound));
+        assert_eq!(extract_number(DOCUMENT, "data.first"), Err(JsonError::NotFound));
+        assert_eq!(extract_number(DOCUMENT, "symbol.price"), Err(JsonError::NotFound));
+        assert_eq!(extract_number(b"{}", "price"), Err(JsonError::NotFound));
+        assert_eq!(extract_number(b"[]", "0"), Err(JsonError::NotFound));
+    }
+
+    #[test]
+    fn extract_number_not_a_number() {
+        assert_eq!(extract_number(DOCUMENT, "symbol"), Err(JsonError::NotNumber));
+        assert_eq!(extract_number(DOCUMENT, "raw.ok"), Err(JsonError::NotNumber));
+        assert_eq!(extract_number(DOCUMENT, "raw.tags"), Err(JsonError::NotNumber));
+        assert_eq!(extract_number(DOCUMENT, "raw"), Err(JsonError::NotNumber));
+    }
+
+    #[test]
+    fn extract_number_invalid_json() {
+        assert_eq!(extract_number(b"", "price"), Err(JsonError::Invalid));
+        assert_eq!(extract_number(b"{\"a\" 1}", "price"), Err(JsonError::Invalid));
+        assert_eq!(extract_number(b"{\"a\": tru}", "price"), Err(JsonError::Invalid));
+        assert_eq!(extract_number(b"{\"price\": 01}", "price"), Err(JsonError::Invalid));
+        assert_eq!(extract_number(b"{\"price\": 1.}", "price"), Err(JsonError::Invalid));
+        assert_eq!(extract_number(b"{\"price\": -}", "price"), Err(JsonError::Invalid));
+        assert_eq!(extract_number("[".repeat(100).as_bytes(), ""), Err(JsonError::Invalid));
+    }
+
+    #[test]
+    fn to_fixed_point_works() {
+        assert_eq!(to_fixed_point("42100.25", 2), Some(4210025));
+        assert_eq!(to_fixed_point("42100.259", 2), Some(4210025));
+        assert_eq!(to_fixed_point("-42100.259", 2), Some(-4210025));
+        assert_eq!(to_fixed_point("-3e2", 0), Some(-300));
+        assert_eq!(to_fixed_point("1.5E+1", 1), Some(150));
+        assert_eq!(to_fixed_point("125e-3", 3), Some(125));
+        assert_eq!(to_fixed_point("1e-10", 9), Some(0));
+        assert_eq!(to_fixed_point("0.000e99999999999999999999", 9), Some(0));
+        assert_eq!(to_fixed_point("1e-99999999999999999999", 9), Some(0));
+        assert_eq!(to_fixed_point("9223372036854775807", 0), Some(i64::MAX));
+        assert_eq!(to_fixed_point("9223372036854775808", 0), None);
+        assert_eq!(to_fixed_point("1e99999999999999999999", 9), None);
+        assert_eq!(to_fixed_point("1e30", 9), None);
+    }
+}
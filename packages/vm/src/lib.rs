@@ -1,12 +1,37 @@
+mod async_querier;
 pub mod cache;
 mod calls;
 mod checksum;
 mod compile;
+mod encoding;
 pub mod error;
 mod imports;
+mod instructions;
+mod json;
+mod replay;
+mod runner;
 mod store;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod vm;
 
-pub use calls::run;
-pub use compile::compile;
+pub use async_querier::{AsyncQuerier, BlockingQuerier, Executor, ThreadExecutor};
+#[cfg(feature = "debug")]
+pub use calls::run_with_memory_dump;
+pub use calls::{
+    minimum_gas, run, run_prepare_and_execute, run_safe, run_with_calldata, run_with_env,
+    run_with_report, RunReport, RunReportDiff,
+};
+pub use compile::{
+    compile, compile_fingerprint, compile_from_reader, compile_with_options,
+    find_forbidden_feature, max_memory_estimate, module_exports, read_custom_section,
+    supported_host_functions, validate_data_segments, CompileOptions, ExportKind, WasmFeature,
+};
 pub use error::Error;
+pub use imports::ABI_VERSION;
+pub use replay::{Recording, RecordingQuerier, ReplayQuerier};
+pub use runner::{ExecuteOutcome, OracleRunner, PartialExecution, PrepareOutcome};
+pub use store::{
+    make_store_with_backend, make_store_with_cost_table, make_store_with_initial_points, Backend,
+    CostTable,
+};
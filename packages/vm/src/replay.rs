@@ -0,0 +1,548 @@
+use crate::error::Error;
+use crate::vm::Querier;
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+
+/// Everything a querier answered during a run, so that the run can be reproduced off-chain
+/// with `ReplayQuerier`, e.g. to debug a result validators disagree on.
+///
+/// The values every run may read are captured up front; external data and storage are
+/// captured as the script reads them, in the form the script asks for them in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    pub span_size: i64,
+    pub max_return_size: i64,
+    pub calldata: Result<Vec<u8>, Error>,
+    pub ask_count: i64,
+    pub min_count: i64,
+    pub prepare_time: i64,
+    pub execute_time: Result<i64, Error>,
+    pub ans_count: Result<i64, Error>,
+    pub random_seed: Result<Vec<u8>, Error>,
+    pub gas_price: Result<i64, Error>,
+    pub requested_data_sources: Result<Vec<i64>, Error>,
+    pub requested_external_ids: Result<Vec<i64>, Error>,
+    /// The status of each external data read, by external ID and validator index.
+    pub external_data_status: BTreeMap<(i64, i64), Result<i64, Error>>,
+    /// The data of each external data read, by external ID and validator index.
+    pub external_data: BTreeMap<(i64, i64), Result<Vec<u8>, Error>>,
+    /// The length of each external data the script asked the size of, by external ID and
+    /// validator index.
+    pub external_data_size: BTreeMap<(i64, i64), Result<i64, Error>>,
+    /// The reports of each external ID the script read all the data of at once.
+    pub all_external_data: BTreeMap<i64, Result<Vec<Vec<u8>>, Error>>,
+    pub storage: BTreeMap<Vec<u8>, Result<Option<Vec<u8>>, Error>>,
+}
+
+/// Wraps a querier, recording every answer it gives; see `Recording`.
+pub struct RecordingQuerier<Q> {
+    querier: Q,
+    recording: Mutex<Recording>,
+}
+
+impl<Q: Querier> RecordingQuerier<Q> {
+    pub fn new(querier: Q) -> Self {
+        let recording = Recording {
+            span_size: querier.get_span_size(),
+            max_return_size: querier.get_max_return_size(),
+            calldata: querier.get_calldata(),
+            ask_count: querier.get_ask_count(),
+            min_count: querier.get_min_count(),
+            prepare_time: querier.get_prepare_time(),
+            execute_time: querier.get_execute_time(),
+            ans_count: querier.get_ans_count(),
+            random_seed: querier.get_random_seed(),
+            gas_price: querier.get_gas_price(),
+            requested_data_sources: querier.get_requested_data_sources(),
+            requested_external_ids: querier.get_requested_external_ids(),
+            external_data_status: BTreeMap::new(),
+            external_data: BTreeMap::new(),
+            external_data_size: BTreeMap::new(),
+            all_external_data: BTreeMap::new(),
+            storage: BTreeMap::new(),
+        };
+        Self { querier, recording: Mutex::new(recording) }
+    }
+
+    /// Returns what the querier answered so far.
+    pub fn recording(&self) -> Recording {
+        self.with_recording(|recording| recording.clone())
+    }
+
+    fn with_recording<C, R>(&self, callback: C) -> R
+    where
+        C: FnOnce(&mut Recording) -> R,
+    {
+        callback(&mut self.recording.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+impl<Q: Querier> Querier for RecordingQuerier<Q> {
+    fn get_span_size(&self) -> i64 {
+        self.with_recording(|recording| recording.span_size)
+    }
+    fn get_calldata(&self) -> Result<Vec<u8>, Error> {
+        self.with_recording(|recording| recording.calldata.clone())
+    }
+    fn set_return_data(&self, data: &[u8]) -> Result<(), Error> {
+        self.querier.set_return_data(data)
+    }
+    fn get_ask_count(&self) -> i64 {
+        self.with_recording(|recording| recording.ask_count)
+    }
+    fn get_min_count(&self) -> i64 {
+        self.with_recording(|recording| recording.min_count)
+    }
+    fn get_prepare_time(&self) -> i64 {
+        self.with_recording(|recording| recording.prepare_time)
+    }
+    fn get_execute_time(&self) -> Result<i64, Error> {
+        self.with_recording(|recording| recording.execute_time)
+    }
+    fn get_ans_count(&self) -> Result<i64, Error> {
+        self.with_recording(|recording| recording.ans_count)
+    }
+    fn ask_external_data(&self, eid: i64, did: i64, data: &[u8]) -> Result<(), Error> {
+        self.querier.ask_external_data(eid, did, data)
+    }
+    fn get_external_data_status(&self, eid: i64, vid: i64) -> Result<i64, Error> {
+        let status = self.querier.get_external_data_status(eid, vid);
+        self.with_recording(|recording| recording.external_data_status.insert((eid, vid), status));
+        status
+    }
+    fn get_external_data(&self, eid: i64, vid: i64) -> Result<Vec<u8>, Error> {
+        let data = self.querier.get_external_data(eid, vid);
+        self.with_recording(|recording| recording.external_data.insert((eid, vid), data.clone()));
+        data
+    }
+    fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+        self.with_recording(|recording| recording.random_seed.clone())
+    }
+    fn get_external_data_size(&self, eid: i64, vid: i64) -> Result<i64, Error> {
+        let size = self.querier.get_external_data_size(eid, vid);
+        self.with_recording(|recording| recording.external_data_size.insert((eid, vid), size));
+        size
+    }
+    fn get_all_external_data(&self, eid: i64) -> Result<Vec<Vec<u8>>, Error> {
+        let reports = self.querier.get_all_external_data(eid);
+        self.with_recording(|recording| recording.all_external_data.insert(eid, reports.clone()));
+        reports
+    }
+    fn get_gas_price(&self) -> Result<i64, Error> {
+        self.with_recording(|recording| recording.gas_price)
+    }
+    fn get_requested_data_sources(&self) -> Result<Vec<i64>, Error> {
+        self.with_recording(|recording| recording.requested_data_sources.clone())
+    }
+    fn get_requested_external_ids(&self) -> Result<Vec<i64>, Error> {
+        self.with_recording(|recording| recording.requested_external_ids.clone())
+    }
+    fn get_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let value = self.querier.get_storage(key);
+        self.with_recording(|recording| recording.storage.insert(key.to_vec(), value.clone()));
+        value
+    }
+    fn get_max_return_size(&self) -> i64 {
+        self.with_recording(|recording| recording.max_return_size)
+    }
+}
+
+/// Answers from a `Recording`, accepting whatever the script sends. External data and
+/// storage the recorded run did not read fail with `UnavailableExternalDataError`.
+pub struct ReplayQuerier {
+    recording: Recording,
+}
+
+impl ReplayQuerier {
+    pub fn new(recording: Recording) -> Self {
+        Self { recording }
+    }
+}
+
+impl Querier for ReplayQuerier {
+    fn get_span_size(&self) -> i64 {
+        self.recording.span_size
+    }
+    fn get_calldata(&self) -> Result<Vec<u8>, Error> {
+        self.recording.calldata.clone()
+    }
+    fn set_return_data(&self, _: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+    fn get_ask_count(&self) -> i64 {
+        self.recording.ask_count
+    }
+    fn get_min_count(&self) -> i64 {
+        self.recording.min_count
+    }
+    fn get_prepare_time(&self) -> i64 {
+        self.recording.prepare_time
+    }
+    fn get_execute_time(&self) -> Result<i64, Error> {
+        self.recording.execute_time
+    }
+    fn get_ans_count(&self) -> Result<i64, Error> {
+        self.recording.ans_count
+    }
+    fn ask_external_data(&self, _: i64, _: i64, _: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+    fn get_external_data_status(&self, eid: i64, vid: i64) -> Result<i64, Error> {
+        let status = self.recording.external_data_status.get(&(eid, vid));
+        status.copied().unwrap_or(Err(Error::UnavailableExternalDataError))
+    }
+    fn get_external_data(&self, eid: i64, vid: i64) -> Result<Vec<u8>, Error> {
+        let data = self.recording.external_data.get(&(eid, vid));
+        data.cloned().unwrap_or(Err(Error::UnavailableExternalDataError))
+    }
+    fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+        self.recording.random_seed.clone()
+    }
+    fn get_external_data_size(&self, eid: i64, vid: i64) -> Result<i64, Error> {
+        let size = self.recording.external_data_size.get(&(eid, vid));
+        size.copied().unwrap_or(Err(Error::UnavailableExternalDataError))
+    }
+    fn get_all_external_data(&self, eid: i64) -> Result<Vec<Vec<u8>>, Error> {
+        let reports = self.recording.all_external_data.get(&eid);
+        reports.cloned().unwrap_or(Err(Error::UnavailableExternalDataError))
+    }
+    fn get_gas_price(&self) -> Result<i64, Error> {
+        self.recording.gas_price
+    }
+    fn get_requested_data_sources(&self) -> Result<Vec<i64>, Error> {
+        self.recording.requested_data_sources.clone()
+    }
+    fn get_requested_external_ids(&self) -> Result<Vec<i64>, Error> {
+        self.recording.requested_external_ids.clone()
+    }
+    fn get_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let value = self.recording.storage.get(key);
+        value.cloned().unwrap_or(Err(Error::UnavailableExternalDataError))
+    }
+    fn get_max_return_size(&self) -> i64 {
+        self.recording.max_return_size
+    }
+}
+
+// The serialized form is a sequence of fields in declaration order: integers as 8 bytes
+// little-endian, byte strings and lists prefixed with their length, and results prefixed with
+// their error code, or 0 for success.
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn int(&mut self, value: i64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.int(len as i64);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.len(bytes.len());
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn result<T>(&mut self, result: &Result<T, Error>, write: impl FnOnce(&mut Self, &T)) {
+        match result {
+            Ok(value) => {
+                self.int(0);
+                write(self, value);
+            }
+            Err(error) => self.int(*error as i64),
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn int(&mut self) -> Result<i64, Error> {
+        if self.0.len() < 8 {
+            return Err(Error::InvalidEncodingError);
+        }
+        let (int, rest) = self.0.split_at(8);
+        self.0 = rest;
+        Ok(i64::from_le_bytes(int.try_into().map_err(|_| Error::InvalidEncodingError)?))
+    }
+
+    fn len(&mut self) -> Result<usize, Error> {
+        self.int()?.try_into().map_err(|_| Error::InvalidEncodingError)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.len()?;
+        if self.0.len() < len {
+            return Err(Error::InvalidEncodingError);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes.to_vec())
+    }
+
+    fn result<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<Result<T, Error>, Error> {
+        match self.int()? {
+            0 => Ok(Ok(read(self)?)),
+            code => {
+                let code = i32::try_from(code).map_err(|_| Error::InvalidEncodingError)?;
+                Ok(Err(Error::try_from(code).map_err(|_| Error::InvalidEncodingError)?))
+            }
+        }
+    }
+}
+
+impl Recording {
+    /// Serializes the recording, e.g. to attach it to a bug report.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer(vec![]);
+        w.int(self.span_size);
+        w.int(self.max_return_size);
+        w.result(&self.calldata, |w, data| w.bytes(data));
+        w.int(self.ask_count);
+        w.int(self.min_count);
+        w.int(self.prepare_time);
+        w.result(&self.execute_time, |w, time| w.int(*time));
+        w.result(&self.ans_count, |w, count| w.int(*count));
+        w.result(&self.random_seed, |w, seed| w.bytes(seed));
+        w.result(&self.gas_price, |w, price| w.int(*price));
+        w.result(&self.requested_data_sources, |w, ids| {
+            w.len(ids.len());
+            ids.iter().for_each(|id| w.int(*id));
+        });
+        w.result(&self.requested_external_ids, |w, ids| {
+            w.len(ids.len());
+            ids.iter().for_each(|id| w.int(*id));
+        });
+        w.len(self.external_data_status.len());
+        for ((eid, vid), status) in &self.external_data_status {
+            w.int(*eid);
+            w.int(*vid);
+            w.result(status, |w, status| w.int(*status));
+        }
+        w.len(self.external_data.len());
+        for ((eid, vid), data) in &self.external_data {
+            w.int(*eid);
+            w.int(*vid);
+            w.result(data, |w, data| w.bytes(data));
+        }
+        w.len(self.external_data_size.len());
+        for ((eid, vid), size) in &self.external_data_size {
+            w.int(*eid);
+            w.int(*vid);
+            w.result(size, |w, size| w.int(*size));
+        }
+        w.len(self.all_external_data.len());
+        for (eid, reports) in &self.all_external_data {
+            w.int(*eid);
+            w.result(reports, |w, reports| {
+                w.len(reports.len());
+                reports.iter().for_each(|report| w.bytes(report));
+            });
+        }
+        w.len(self.storage.len());
+        for (key, value) in &self.storage {
+            w.bytes(key);
+            w.result(value, |w, value| match value {
+                Some(value) => {
+                    w.int(1);
+                    w.bytes(value);
+                }
+                None => w.int(0),
+            });
+        }
+        w.0
+    }
+
+    /// Parses a recording serialized by `to_bytes`, failing with `InvalidEncodingError` if
+    /// the bytes are malformed.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut r = Reader(bytes);
+        let mut recording = Recording {
+            span_size: r.int()?,
+            max_return_size: r.int()?,
+            calldata: r.result(Reader::bytes)?,
+            ask_count: r.int()?,
+            min_count: r.int()?,
+            prepare_time: r.int()?,
+            execute_time: r.result(Reader::int)?,
+            ans_count: r.result(Reader::int)?,
+            random_seed: r.result(Reader::bytes)?,
+            gas_price: r.result(Reader::int)?,
+            requested_data_sources: r.result(|r| (0..r.len()?).map(|_| r.int()).collect())?,
+            requested_external_ids: r.result(|r| (0..r.len()?).map(|_| r.int()).collect())?,
+            external_data_status: BTreeMap::new(),
+            external_data: BTreeMap::new(),
+            external_data_size: BTreeMap::new(),
+            all_external_data: BTreeMap::new(),
+            storage: BTreeMap::new(),
+        };
+        for _ in 0..r.len()? {
+            let key = (r.int()?, r.int()?);
+            recording.external_data_status.insert(key, r.result(Reader::int)?);
+        }
+        for _ in 0..r.len()? {
+            let key = (r.int()?, r.int()?);
+            recording.external_data.insert(key, r.result(Reader::bytes)?);
+        }
+        for _ in 0..r.len()? {
+            let key = (r.int()?, r.int()?);
+            recording.external_data_size.insert(key, r.result(Reader::int)?);
+        }
+        for _ in 0..r.len()? {
+            let eid = r.int()?;
+            let reports = r.result(|r| (0..r.len()?).map(|_| r.bytes()).collect())?;
+            recording.all_external_data.insert(eid, reports);
+        }
+        for _ in 0..r.len()? {
+            let key = r.bytes()?;
+            let value = r.result(|r| match r.int()? {
+                0 => Ok(None),
+                1 => Ok(Some(r.bytes()?)),
+                _ => Err(Error::InvalidEncodingError),
+            })?;
+            recording.storage.insert(key, value);
+        }
+        if !r.0.is_empty() {
+            return Err(Error::InvalidEncodingError);
+        }
+        Ok(recording)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{Cache, CacheOptions};
+    use crate::calls::run_with_env;
+    use crate::compile::compile;
+    use crate::testing::wat_to_wasm;
+    use crate::vm::Environment;
+
+    pub struct MockQuerier {}
+
+    impl Querier for MockQuerier {
+        fn get_span_size(&self) -> i64 {
+            300
+        }
+        fn get_calldata(&self) -> Result<Vec<u8>, Error> {
+            Ok(b"beeb".to_vec())
+        }
+        fn set_return_data(&self, _: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_ask_count(&self) -> i64 {
+            4
+        }
+        fn get_min_count(&self) -> i64 {
+            3
+        }
+        fn get_prepare_time(&self) -> i64 {
+            100_000
+        }
+        fn get_execute_time(&self) -> Result<i64, Error> {
+            Ok(100_010)
+        }
+        fn get_ans_count(&self) -> Result<i64, Error> {
+            Ok(3)
+        }
+        fn ask_external_data(&self, _: i64, _: i64, _: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_external_data_status(&self, _: i64, vid: i64) -> Result<i64, Error> {
+            Ok(if vid == 2 { 1 } else { 0 })
+        }
+        fn get_external_data(&self, eid: i64, vid: i64) -> Result<Vec<u8>, Error> {
+            match vid {
+                2 => Err(Error::UnavailableExternalDataError),
+                _ => Ok(format!("price-{}-{}", eid, vid).into_bytes()),
+            }
+        }
+        fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+            Ok(vec![0xab; 32])
+        }
+        fn get_requested_external_ids(&self) -> Result<Vec<i64>, Error> {
+            Ok(vec![1])
+        }
+        fn get_storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+            Ok(if key == b"beeb" { Some(b"42".to_vec()) } else { None })
+        }
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        // Returns every report of external ID 1, the stored value of the calldata as key, and
+        // as many bytes as the report of validator 0 for external ID 2 is long.
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64) (result i64)))
+            (type (func (param i64 i64) (result)))
+            (type (func (param i64) (result i64)))
+            (type (func (param i64 i64 i64) (result i64)))
+            (import "env" "read_all_external_data" (func (type 0)))
+            (import "env" "append_return_data" (func (type 1)))
+            (import "env" "read_calldata" (func (type 2)))
+            (import "env" "get_storage" (func (type 3)))
+            (import "env" "peek_external_data_len" (func (type 0)))
+            (func
+              )
+            (func
+              (call 1 (i64.const 1024) (call 0 (i64.const 1) (i64.const 1024)))
+              (call 1 (i64.const 2048)
+                (call 3 (i64.const 4096) (call 2 (i64.const 4096)) (i64.const 2048)))
+              (call 1 (i64.const 8192) (call 4 (i64.const 2) (i64.const 0)))
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 5))
+            (export "execute" (func 6)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+
+        let recorded_env = Environment::new(RecordingQuerier::new(MockQuerier {}));
+        run_with_env(&mut cache, &code, u64::MAX, false, recorded_env.clone()).unwrap();
+        let recording = recorded_env.with_querier_from_context(|querier| querier.recording());
+        // The querier answers the bulk read and the length itself, without the single reads.
+        let reports = ["price-1-0", "price-1-1", "price-1-3"].map(|report| report.into());
+        assert_eq!(recording.all_external_data.get(&1), Some(&Ok(reports.to_vec())));
+        assert_eq!(recording.external_data_size.get(&(2, 0)), Some(&Ok(9)));
+        assert!(recording.external_data_status.is_empty());
+        assert!(recording.external_data.is_empty());
+        assert_eq!(recording.storage.get(b"beeb".as_slice()), Some(&Ok(Some(b"42".to_vec()))));
+        assert_eq!(recording.gas_price, Err(Error::WrongPeriodActionError));
+        assert_eq!(recording.requested_external_ids, Ok(vec![1]));
+
+        let bytes = recording.to_bytes();
+        let parsed = Recording::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, recording);
+        assert_eq!(
+            Recording::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(Error::InvalidEncodingError)
+        );
+        assert_eq!(
+            Recording::from_bytes(&[bytes.clone(), vec![0]].concat()),
+            Err(Error::InvalidEncodingError)
+        );
+
+        let replayed_env = Environment::new(ReplayQuerier::new(parsed));
+        run_with_env(&mut cache, &code, u64::MAX, false, replayed_env.clone()).unwrap();
+        let recorded = recorded_env.finalize();
+        assert!(recorded.return_data.is_some());
+        assert_eq!(replayed_env.finalize(), recorded);
+    }
+
+    #[test]
+    fn test_replay_unrecorded_data() {
+        let recording = RecordingQuerier::new(MockQuerier {}).recording();
+        let querier = ReplayQuerier::new(recording);
+        assert_eq!(querier.get_calldata(), Ok(b"beeb".to_vec()));
+        assert_eq!(querier.get_external_data(1, 0), Err(Error::UnavailableExternalDataError));
+        assert_eq!(querier.get_external_data_size(1, 0), Err(Error::UnavailableExternalDataError));
+        assert_eq!(querier.get_all_external_data(1), Err(Error::UnavailableExternalDataError));
+        assert_eq!(querier.get_storage(b"beeb"), Err(Error::UnavailableExternalDataError));
+    }
+}
@@ -0,0 +1,300 @@
+use crate::cache::Cache;
+use crate::calls::{run_and_inspect, CalldataQuerier};
+use crate::error::Error;
+use crate::store::{make_store_with_backend, Backend};
+use crate::vm::{Environment, ExternalRequest, Querier};
+
+use wasmer::Store;
+
+/// What the preparation phase of an oracle script produced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrepareOutcome {
+    pub gas_used: u64,
+    /// The external data requests issued by the script, in order.
+    pub external_requests: Vec<ExternalRequest>,
+}
+
+/// What the execution phase of an oracle script produced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecuteOutcome {
+    pub gas_used: u64,
+    /// The return data sent to the querier, or None if the script did not set any.
+    pub return_data: Option<Vec<u8>>,
+}
+
+/// A failed execution, together with the return data the script had set before failing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialExecution {
+    pub error: Error,
+    /// The return data sent to the querier before the failure, if any.
+    pub return_data: Option<Vec<u8>>,
+}
+
+/// Runs oracle scripts through the two phases of an oracle request, creating the instance
+/// and setting up gas for each run. This is the recommended entry point for chain runners;
+/// `run` and its variants remain for callers needing finer control.
+///
+/// All runs share one store. The import object is still built for every run, since its host
+/// functions are bound to the environment, and so to the querier, of that run.
+pub struct OracleRunner {
+    cache: Cache,
+    store: Store,
+}
+
+impl OracleRunner {
+    pub fn new(cache: Cache, backend: Backend) -> Self {
+        Self { cache, store: make_store_with_backend(backend) }
+    }
+
+    /// Runs the `prepare` entry point of the compiled code with the given request calldata,
+    /// returning the external data requests it issued.
+    pub fn prepare<Q>(
+        &mut self,
+        code: &[u8],
+        calldata: &[u8],
+        gas_limit: u64,
+        querier: Q,
+    ) -> Result<PrepareOutcome, Error>
+    where
+        Q: Querier + 'static,
+    {
+        let querier = CalldataQuerier { querier, calldata: calldata.to_vec() };
+        let owasm_env = Environment::new(querier);
+        self.run(code, gas_limit, true, &owasm_env)?;
+        let outcome = owasm_env.finalize();
+        Ok(PrepareOutcome {
+            gas_used: outcome.gas_used,
+            external_requests: outcome.external_requests,
+        })
+    }
+
+    /// Runs the `execute` entry point of the compiled code, returning the data it saved as
+    /// the result of the request.
+    pub fn execute<Q>(
+        &mut self,
+        code: &[u8],
+        gas_limit: u64,
+        querier: Q,
+    ) -> Result<ExecuteOutcome, Error>
+    where
+        Q: Querier + 'static,
+    {
+        self.execute_keep_partial(code, gas_limit, querier).map_err(|partial| partial.error)
+    }
+
+    /// Same as `execute`, but if the script fails after setting its return data, the data is
+    /// returned along with the error. The VM makes no call on such partial output; whether it
+    /// is accepted as the result of the request is for the chain to decide.
+    pub fn execute_keep_partial<Q>(
+        &mut self,
+        code: &[u8],
+        gas_limit: u64,
+        querier: Q,
+    ) -> Result<ExecuteOutcome, PartialExecution>
+    where
+        Q: Querier + 'static,
+    {
+        let owasm_env = Environment::new(querier);
+        let result = self.run(code, gas_limit, false, &owasm_env);
+        let outcome = owasm_env.finalize();
+        match result {
+            Ok(()) => {
+                Ok(ExecuteOutcome { gas_used: outcome.gas_used, return_data: outcome.return_data })
+            }
+            Err(error) => Err(PartialExecution { error, return_data: outcome.return_data }),
+        }
+    }
+
+    fn run<Q>(
+        &mut self,
+        code: &[u8],
+        gas_limit: u64,
+        is_prepare: bool,
+        owasm_env: &Environment<Q>,
+    ) -> Result<(), Error>
+    where
+        Q: Querier + 'static,
+    {
+        run_and_inspect(
+            &mut self.cache,
+            Some(&self.store),
+            code,
+            gas_limit,
+            is_prepare,
+            owasm_env.clone(),
+            |_| (),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CacheOptions;
+    use crate::compile::compile;
+    use crate::testing::wat_to_wasm;
+
+    pub struct MockQuerier {}
+
+    impl Querier for MockQuerier {
+        fn get_span_size(&self) -> i64 {
+            300
+        }
+        fn get_calldata(&self) -> Result<Vec<u8>, Error> {
+            Ok(vec![1])
+        }
+        fn set_return_data(&self, _: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_ask_count(&self) -> i64 {
+            10
+        }
+        fn get_min_count(&self) -> i64 {
+            8
+        }
+        fn get_prepare_time(&self) -> i64 {
+            100_000
+        }
+        fn get_execute_time(&self) -> Result<i64, Error> {
+            Ok(100_000)
+        }
+        fn get_ans_count(&self) -> Result<i64, Error> {
+            Ok(8)
+        }
+        fn ask_external_data(&self, _: i64, _: i64, _: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+        fn get_external_data_status(&self, _: i64, _: i64) -> Result<i64, Error> {
+            Ok(1)
+        }
+        fn get_external_data(&self, _: i64, _: i64) -> Result<Vec<u8>, Error> {
+            Ok(vec![1])
+        }
+        fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+            Ok(vec![0xab; 32])
+        }
+    }
+
+    #[test]
+    fn test_oracle_runner_phases() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64 i64) (result)))
+            (type (func (param i64 i64) (result)))
+            (type (func (param i64) (result i64)))
+            (import "env" "ask_external_data" (func (type 0)))
+            (import "env" "set_return_data" (func (type 1)))
+            (import "env" "read_calldata" (func (type 2)))
+            (func
+              (call 0 (i64.const 1) (i64.const 2) (i64.const 1024) (call 2 (i64.const 1024)))
+              )
+            (func (;"execute": Resolves with result "beeb";)
+              (call 1 (i64.const 1048576) (i64.const 4))
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beeb") (;str = "beeb";)
+            (export "prepare" (func 3))
+            (export "execute" (func 4)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let mut runner = OracleRunner::new(cache, Backend::default());
+
+        let prepared = runner.prepare(&code, b"calldata", u64::MAX, MockQuerier {}).unwrap();
+        assert_eq!(
+            prepared.external_requests,
+            vec![ExternalRequest { eid: 1, did: 2, calldata: b"calldata".to_vec() }]
+        );
+        assert!(prepared.gas_used > 0);
+
+        let executed = runner.execute(&code, u64::MAX, MockQuerier {}).unwrap();
+        assert_eq!(executed.return_data, Some(b"beeb".to_vec()));
+        assert!(executed.gas_used > 0);
+    }
+
+    #[test]
+    fn test_oracle_runner_error() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func unreachable)
+            (memory (export "memory") 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 0)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let mut runner = OracleRunner::new(cache, Backend::default());
+
+        assert_eq!(runner.prepare(&code, b"", u64::MAX, MockQuerier {}), Err(Error::Unreachable));
+        assert_eq!(runner.execute(&code, u64::MAX, MockQuerier {}), Err(Error::Unreachable));
+        assert_eq!(runner.execute(&code, 0, MockQuerier {}), Err(Error::InsufficientGas));
+    }
+    #[test]
+    fn test_oracle_runner_partial_execution() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64) (result)))
+            (import "env" "set_return_data" (func (type 0)))
+            (func
+              )
+            (func (;"execute": Sets "beeb" then traps;)
+              (call 0 (i64.const 1048576) (i64.const 4))
+              unreachable
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beeb") (;str = "beeb";)
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let mut runner = OracleRunner::new(cache, Backend::default());
+
+        assert_eq!(
+            runner.execute_keep_partial(&code, u64::MAX, MockQuerier {}),
+            Err(PartialExecution {
+                error: Error::Unreachable,
+                return_data: Some(b"beeb".to_vec())
+            })
+        );
+        assert_eq!(runner.execute(&code, u64::MAX, MockQuerier {}), Err(Error::Unreachable));
+    }
+    #[test]
+    fn test_oracle_runner_runs_do_not_share_state() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64 i64 i64) (result)))
+            (type (func (param i64) (result i64)))
+            (import "env" "ask_external_data" (func (type 0)))
+            (import "env" "read_calldata" (func (type 1)))
+            (func
+              (call 0 (i64.const 1) (i64.const 2) (i64.const 1024) (call 1 (i64.const 1024)))
+              )
+            (func
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 2))
+            (export "execute" (func 3)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let mut runner = OracleRunner::new(cache, Backend::default());
+
+        let first = runner.prepare(&code, b"first", u64::MAX, MockQuerier {}).unwrap();
+        let second = runner.prepare(&code, b"second", u64::MAX, MockQuerier {}).unwrap();
+        assert_eq!(
+            first.external_requests,
+            vec![ExternalRequest { eid: 1, did: 2, calldata: b"first".to_vec() }]
+        );
+        assert_eq!(
+            second.external_requests,
+            vec![ExternalRequest { eid: 1, did: 2, calldata: b"second".to_vec() }]
+        );
+        assert_eq!(runner.execute(&code, u64::MAX, MockQuerier {}).unwrap().return_data, None);
+    }
+}
@@ -1,31 +1,241 @@
+use crate::error::Error;
+use crate::instructions::InstructionCounter;
+
 use std::sync::Arc;
 
 use wasmer::wasmparser::Operator;
 use wasmer::{CompilerConfig, Singlepass, Store, Universal};
 use wasmer_middlewares::Metering;
 
-fn cost(operator: &Operator) -> u64 {
-    // A flat fee for each operation
-    // The target is 1 Teragas per millisecond
-    match operator {
-        Operator::Loop { .. } // loop headers are branch targets
-        | Operator::End // block ends are branch targets
-        | Operator::Else // "else" is the "end" of an if branch
-        | Operator::Br { .. } // branch source
-        | Operator::BrTable { .. } // branch source
-        | Operator::BrIf { .. } // branch source
-        | Operator::Call { .. } // function call - branch source
-        | Operator::CallIndirect { .. } // function call - branch source
-        | Operator::Return // end of function - branch source
-        => { 2_500_000 }
-        _ => { 650_000 }
-    }
-}
-
-pub fn make_store() -> Store {
+/// The gas charged for each Wasm operator, by category. Chains can load their own table with
+/// `CostTable::from_config` to change gas economics without a new VM release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CostTable {
+    /// Cost of operators that are branch sources or targets: loops, block ends, branches,
+    /// calls and returns.
+    pub branch: u64,
+    /// Cost of every other operator.
+    pub default: u64,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        // The target is 1 Teragas per millisecond
+        CostTable { branch: 2_500_000, default: 650_000 }
+    }
+}
+
+impl CostTable {
+    /// Parses a cost table from lines of `category = gas`, where the category is `branch` or
+    /// `default`. Blank lines and lines starting with `#` are ignored, and categories left
+    /// out keep their default cost.
+    pub fn from_config(config: &str) -> Result<Self, Error> {
+        let mut table = CostTable::default();
+        for line in config.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (category, gas) = line.split_once('=').ok_or(Error::InvalidCostTable)?;
+            let gas = gas.trim().parse().map_err(|_| Error::InvalidCostTable)?;
+            match category.trim() {
+                "branch" => table.branch = gas,
+                "default" => table.default = gas,
+                _ => return Err(Error::InvalidCostTable),
+            }
+        }
+        Ok(table)
+    }
+
+    fn cost(&self, operator: &Operator) -> u64 {
+        // A flat fee for each operation
+        match operator {
+            Operator::Loop { .. } // loop headers are branch targets
+            | Operator::End // block ends are branch targets
+            | Operator::Else // "else" is the "end" of an if branch
+            | Operator::Br { .. } // branch source
+            | Operator::BrTable { .. } // branch source
+            | Operator::BrIf { .. } // branch source
+            | Operator::Call { .. } // function call - branch source
+            | Operator::CallIndirect { .. } // function call - branch source
+            | Operator::Return // end of function - branch source
+            => { self.branch }
+            _ => { self.default }
+        }
+    }
+}
+
+/// The compiler backend used to turn Wasm into native code.
+///
+/// Singlepass compiles in linear time, so a malicious script cannot blow up compilation,
+/// and it emits the same machine code for the same input on every node. That is what
+/// validators need. Optimising backends compile more slowly and superlinearly, so they are
+/// only suited to tooling. Gas is metered on Wasm operators, so the gas used does not
+/// depend on the backend.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Singlepass,
+}
+
+/// Returns a metered store compiling with the given backend.
+pub fn make_store_with_backend(backend: Backend) -> Store {
+    make_store_with_cost_table(backend, CostTable::default())
+}
+
+/// Returns a metered store compiling with the given backend and charging gas by `cost_table`.
+/// Modules compiled with different tables must not share a cache.
+pub fn make_store_with_cost_table(backend: Backend, cost_table: CostTable) -> Store {
+    make_store_with_initial_points(backend, cost_table, 0)
+}
+
+/// Same as `make_store_with_cost_table`, but every instance of the modules compiled with the
+/// store starts with `initial_points` gas left, instead of none until the gas is set. Modules
+/// compiled with different initial points must not share a cache.
+pub fn make_store_with_initial_points(
+    backend: Backend,
+    cost_table: CostTable,
+    initial_points: u64,
+) -> Store {
+    let metering = Arc::new(Metering::new(initial_points, move |operator: &Operator| {
+        cost_table.cost(operator)
+    }));
+    let engine = match backend {
+        Backend::Singlepass => {
+            let mut compiler = Singlepass::new();
+            compiler.push_middleware(metering);
+            Universal::new(compiler).engine()
+        }
+    };
+    Store::new(&engine)
+}
+
+/// Returns a store whose compiled modules count the operators they execute, metering gas by
+/// the default cost table if `metered` is set. Each store must only compile one module.
+pub(crate) fn make_counting_store(metered: bool) -> Store {
     let mut compiler = Singlepass::new();
-    let metering = Arc::new(Metering::new(0, cost));
-    compiler.push_middleware(metering);
-    let engine = Universal::new(compiler).engine();
+    if metered {
+        let cost_table = CostTable::default();
+        compiler.push_middleware(Arc::new(Metering::new(0, move |operator: &Operator| {
+            cost_table.cost(operator)
+        })));
+    }
+    compiler.push_middleware(Arc::new(InstructionCounter::default()));
+    Store::new(&Universal::new(compiler).engine())
+}
+
+/// Returns a store whose compiled modules do not track gas at all.
+pub fn make_unmetered_store() -> Store {
+    let engine = Universal::new(Singlepass::new()).engine();
     Store::new(&engine)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::wat_to_wasm;
+
+    use crate::compile::compile;
+    use wasmer::{imports, Instance, Module};
+    use wasmer_middlewares::metering::{
+        get_remaining_points, set_remaining_points, MeteringPoints,
+    };
+
+    #[test]
+    fn test_make_store_with_backend() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              )
+            (func (;"execute": Resolves with result "beeb";)
+              (result i64)
+              (i64.load (i32.const 1048576))
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1048576) "beebbeeb")
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+
+        assert_eq!(Backend::default(), Backend::Singlepass);
+        let store = make_store_with_backend(Backend::Singlepass);
+        let module = Module::new(&store, &code).unwrap();
+        let instance = Instance::new(&module, &imports! {}).unwrap();
+        set_remaining_points(&instance, u64::MAX);
+        let execute = instance.exports.get_native_function::<(), i64>("execute").unwrap();
+        assert_eq!(execute.call().unwrap(), i64::from_le_bytes(*b"beebbeeb"));
+    }
+    #[test]
+    fn test_cost_table_from_config() {
+        assert_eq!(CostTable::from_config(""), Ok(CostTable::default()));
+        assert_eq!(
+            CostTable::from_config("# governance proposal 42\nbranch = 10\n\ndefault=1\n"),
+            Ok(CostTable { branch: 10, default: 1 })
+        );
+        assert_eq!(
+            CostTable::from_config("default = 7"),
+            Ok(CostTable { branch: 2_500_000, default: 7 })
+        );
+        assert_eq!(CostTable::from_config("branch"), Err(Error::InvalidCostTable));
+        assert_eq!(CostTable::from_config("branch = -1"), Err(Error::InvalidCostTable));
+        assert_eq!(CostTable::from_config("memory = 1"), Err(Error::InvalidCostTable));
+    }
+
+    #[test]
+    fn test_make_store_with_initial_points() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 0)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+
+        let initial_points = |store: Store| {
+            let module = Module::new(&store, &code).unwrap();
+            let instance = Instance::new(&module, &imports! {}).unwrap();
+            get_remaining_points(&instance)
+        };
+        let store =
+            make_store_with_initial_points(Backend::default(), CostTable::default(), 1_000_000);
+        assert_eq!(initial_points(store), MeteringPoints::Remaining(1_000_000));
+        let store = make_store_with_backend(Backend::default());
+        assert_eq!(initial_points(store), MeteringPoints::Remaining(0));
+    }
+
+    #[test]
+    fn test_make_store_with_cost_table() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              (drop (i32.add (i32.const 1) (i32.const 2)))
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 0)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+
+        let gas_used = |cost_table: CostTable| {
+            let store = make_store_with_cost_table(Backend::default(), cost_table);
+            let module = Module::new(&store, &code).unwrap();
+            let instance = Instance::new(&module, &imports! {}).unwrap();
+            set_remaining_points(&instance, u64::MAX);
+            let prepare = instance.exports.get_native_function::<(), ()>("prepare").unwrap();
+            prepare.call().unwrap();
+            match get_remaining_points(&instance) {
+                MeteringPoints::Remaining(left) => u64::MAX - left,
+                MeteringPoints::Exhausted => panic!("out of gas"),
+            }
+        };
+        let default_gas = gas_used(CostTable::default());
+        let custom_gas = gas_used(CostTable::from_config("branch = 1\ndefault = 2").unwrap());
+        assert!(custom_gas > 0);
+        assert!(custom_gas < default_gas);
+    }
+}
@@ -0,0 +1,107 @@
+//! Helpers for running scripts written as WAT text, shared by the unit tests of this crate
+//! and available to others with the `testing` feature.
+
+use crate::cache::{Cache, CacheOptions};
+use crate::calls::run_with_env;
+use crate::compile::compile;
+use crate::error::Error;
+use crate::vm::{Environment, Querier, RunOutcome};
+
+use std::io::{Read, Write};
+use std::process::Command;
+use tempfile::NamedTempFile;
+
+/// Converts the given WAT text to Wasm bytes with the `wat2wasm` tool from wabt, which must
+/// be on the PATH. This is the only place tests get Wasm from text, so moving to an
+/// in-process parser such as the `wat` crate only needs changing this function.
+pub fn wat_to_wasm(wat: impl AsRef<[u8]>) -> Vec<u8> {
+    let mut input_file = NamedTempFile::new().unwrap();
+    let mut output_file = NamedTempFile::new().unwrap();
+    input_file.write_all(wat.as_ref()).unwrap();
+    let output = Command::new("wat2wasm")
+        .args([input_file.path().to_str().unwrap(), "-o", output_file.path().to_str().unwrap()])
+        .output()
+        .expect("wat2wasm from wabt must be installed to run the tests");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let mut wasm = Vec::new();
+    output_file.read_to_end(&mut wasm).unwrap();
+    wasm
+}
+
+/// Compiles the given WAT text and runs its `prepare` or `execute` entry point without a gas
+/// limit, returning everything the run produced. Panics if the text is not valid WAT.
+///
+/// ```
+/// use owasm_vm::testing::run_wat;
+/// use owasm_vm::vm::Querier;
+/// use owasm_vm::Error;
+///
+/// struct NoQuerier;
+///
+/// impl Querier for NoQuerier {
+///     fn get_span_size(&self) -> i64 { 300 }
+///     fn get_calldata(&self) -> Result<Vec<u8>, Error> { Ok(vec![]) }
+///     fn set_return_data(&self, _: &[u8]) -> Result<(), Error> { Ok(()) }
+///     fn get_ask_count(&self) -> i64 { 0 }
+///     fn get_min_count(&self) -> i64 { 0 }
+///     fn get_prepare_time(&self) -> i64 { 0 }
+///     fn get_execute_time(&self) -> Result<i64, Error> { Ok(0) }
+///     fn get_ans_count(&self) -> Result<i64, Error> { Ok(0) }
+///     fn ask_external_data(&self, _: i64, _: i64, _: &[u8]) -> Result<(), Error> { Ok(()) }
+///     fn get_external_data_status(&self, _: i64, _: i64) -> Result<i64, Error> { Ok(0) }
+///     fn get_external_data(&self, _: i64, _: i64) -> Result<Vec<u8>, Error> { Ok(vec![]) }
+///     fn get_random_seed(&self) -> Result<Vec<u8>, Error> { Ok(vec![]) }
+/// }
+///
+/// let outcome = run_wat(
+///     r#"(module
+///     (import "env" "set_return_data" (func (param i64 i64)))
+///     (func)
+///     (func (call 0 (i64.const 1024) (i64.const 4)))
+///     (memory (export "memory") 17)
+///     (data (i32.const 1024) "beeb")
+///     (export "prepare" (func 1))
+///     (export "execute" (func 2)))"#,
+///     false,
+///     NoQuerier,
+/// )
+/// .unwrap();
+/// assert_eq!(outcome.return_data, Some(b"beeb".to_vec()));
+/// ```
+pub fn run_wat<Q>(wat: &str, is_prepare: bool, querier: Q) -> Result<RunOutcome, Error>
+where
+    Q: Querier + 'static,
+{
+    let code = compile(&wat_to_wasm(wat))?;
+    let mut cache = Cache::new(CacheOptions { cache_size: 1 });
+    let owasm_env = Environment::new(querier);
+    run_with_env(&mut cache, &code, u64::MAX, is_prepare, owasm_env.clone())?;
+    Ok(owasm_env.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::compile;
+
+    #[test]
+    fn test_wat_to_wasm() {
+        let wasm = wat_to_wasm(
+            r#"(module
+            (type (func (param i64 i64) (result)))
+            (import "env" "set_return_data" (func (type 0)))
+            (func
+              )
+            (func
+              (call 0 (i64.const 1024) (i64.const 4))
+              )
+            (memory (export "memory") 17)
+            (data (i32.const 1024) "beeb")
+            (export "prepare" (func 1))
+            (export "execute" (func 2)))
+          "#,
+        );
+        assert_eq!(&wasm[..4], b"\0asm");
+        assert!(compile(&wasm).is_ok());
+    }
+}
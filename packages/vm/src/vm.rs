@@ -1,13 +1,17 @@
 use crate::error::Error;
 
 use std::borrow::{Borrow, BorrowMut};
+use std::collections::{BTreeMap, HashMap};
+use std::marker::PhantomData;
 use std::ptr::NonNull;
-use std::sync::{Arc, RwLock};
-use wasmer::{Instance, Memory, WasmerEnv};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use wasmer::{Instance, Memory, RuntimeError, WasmerEnv};
 use wasmer_middlewares::metering::{get_remaining_points, set_remaining_points, MeteringPoints};
 
 pub trait Querier {
-    /// Returns the maximum span size value.
+    /// Returns the maximum span size value. The environment asks for it once, when it is
+    /// created, and serves every later read of a run from that value.
     fn get_span_size(&self) -> i64;
     /// Returns user calldata, or returns error from VM runner.
     fn get_calldata(&self) -> Result<Vec<u8>, Error>;
@@ -29,17 +33,280 @@ pub trait Querier {
     fn get_external_data_status(&self, eid: i64, vid: i64) -> Result<i64, Error>;
     /// Returns data span with the data id `eid` from validator index `vid`.
     fn get_external_data(&self, eid: i64, vid: i64) -> Result<Vec<u8>, Error>;
+    /// Returns the random seed of the request derived from chain entropy, such as a block hash,
+    /// or error from VM runner. The same request must always get the same seed.
+    fn get_random_seed(&self) -> Result<Vec<u8>, Error>;
+    /// Returns the length of the data span with the data id `eid` from validator index `vid`.
+    /// Runners able to tell the length without loading the data should override this.
+    fn get_external_data_size(&self, eid: i64, vid: i64) -> Result<i64, Error> {
+        self.get_external_data(eid, vid)?.len().try_into().map_err(|_| Error::ConvertTypeOutOfBound)
+    }
+    /// Returns the data reported for data id `eid` by every validator with a zero status, in
+    /// validator index order. Runners holding all reports at once should override this.
+    fn get_all_external_data(&self, eid: i64) -> Result<Vec<Vec<u8>>, Error> {
+        let mut all_data = vec![];
+        for vid in 0..self.get_ask_count() {
+            if self.get_external_data_status(eid, vid)? == 0 {
+                all_data.push(self.get_external_data(eid, vid)?);
+            }
+        }
+        Ok(all_data)
+    }
+    /// Returns the chain's gas price for the request, letting scripts scale down their
+    /// external data requests under congestion. The value must be the same for every
+    /// validator running the request, e.g. the price when the request was submitted. Runners
+    /// not providing a price keep this default, which fails the script calling it.
+    fn get_gas_price(&self) -> Result<i64, Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+    /// Returns the data source IDs the oracle script was configured with for the request, in
+    /// order, letting scripts pick their data sources from the request instead of hardcoding
+    /// them. Runners not providing them keep this default, which fails the script calling it.
+    fn get_requested_data_sources(&self) -> Result<Vec<i64>, Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+    /// Returns the external IDs the request asked external data for, in the order they were
+    /// asked in, letting scripts go over the reports without repeating the IDs they asked in
+    /// prepare. Runners not providing them keep this default, which fails the script calling it.
+    fn get_requested_external_ids(&self) -> Result<Vec<i64>, Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+    /// Returns the value of the given key in the auxiliary chain state the request may read,
+    /// or None if the key is absent. Runners not providing any state keep this default, which
+    /// fails the script calling it.
+    fn get_storage(&self, _key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Err(Error::WrongPeriodActionError)
+    }
+    /// Returns the most bytes of return data the script may set, for chains capping the
+    /// result of a request separately from the span of each read. Defaults to the span size.
+    fn get_max_return_size(&self) -> i64 {
+        self.get_span_size()
+    }
 }
 
+/// An external data request issued by the script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalRequest {
+    pub eid: i64,
+    pub did: i64,
+    pub calldata: Vec<u8>,
+}
+
+/// Everything a run produced, collected by the environment and handed out by `finalize`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunOutcome {
+    /// The return data sent to the querier, if any.
+    pub return_data: Option<Vec<u8>>,
+    pub gas_used: u64,
+    pub external_requests: Vec<ExternalRequest>,
+    /// The error the run failed with, or None if it completed.
+    pub trap: Option<Error>,
+    /// The message the environment's trap formatter gave for the trap, if it has one.
+    pub trap_message: Option<String>,
+    pub peak_memory_pages: u32,
+    /// The gas charged for external data requests that the chain has not refunded yet.
+    pub refundable_gas: u64,
+    /// The failure the script reported with `fail_with`, if any. Unlike a trap, the run still
+    /// completes, with the failure encoded as its return data.
+    pub failure: Option<ScriptFailure>,
+    /// The ID the runner tagged the environment with, if any; see `Environment::set_context_id`.
+    pub context_id: Option<u64>,
+}
+
+/// A failure a script reports with `fail_with` instead of trapping, so that the chain can
+/// record why the request could not be resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptFailure {
+    /// The script-defined reason code.
+    pub code: i64,
+    pub message: Vec<u8>,
+}
+
+impl ScriptFailure {
+    /// Returns the return data the failure is recorded as: the code as an 8-byte
+    /// little-endian integer, followed by the message.
+    pub fn to_return_data(&self) -> Vec<u8> {
+        let mut data = self.code.to_le_bytes().to_vec();
+        data.extend_from_slice(&self.message);
+        data
+    }
+}
+
+/// The gas state of a run at some point, taken by `Environment::snapshot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GasSnapshot {
+    pub gas_left: u64,
+}
+
+/// The gas charged for setting up an instance before its entry point runs, set with
+/// `Environment::set_instantiation_cost`. Free by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InstantiationCost {
+    /// Cost of each page of initial memory, declared or imported.
+    pub per_memory_page: u64,
+    /// Cost of each function the code defines.
+    pub per_function: u64,
+}
+
+impl InstantiationCost {
+    /// Returns the cost of an instance with the given initial memory pages and functions.
+    pub fn gas(&self, memory_pages: u64, functions: u64) -> u64 {
+        self.per_memory_page
+            .saturating_mul(memory_pages)
+            .saturating_add(self.per_function.saturating_mul(functions))
+    }
+}
+
+/// Maps a trap to the message reported with it, e.g. to keep wasmer internals out of what end
+/// users see.
+pub type TrapFormatter = Box<dyn Fn(&RuntimeError) -> String + Send + Sync>;
+
 pub struct ContextData<Q: Querier> {
     querier: Q,
     /// A non-owning link to the wasmer instance
     wasmer_instance: Option<NonNull<Instance>>,
+    /// Whether host functions must avoid handing floating-point values to the script
+    deterministic: bool,
+    /// Whether the run only recomputes a result, so the script must not ask for external data
+    view: bool,
+    /// The ID the runner correlates the run with, e.g. that of the request
+    context_id: Option<u64>,
+    /// Whether runs count the operators they execute
+    count_instructions: bool,
+    /// The operators the last run executed, if it counted them
+    instructions: Option<u64>,
+    /// Turns traps into the messages recorded in the outcome, or None to record no message
+    trap_formatter: Option<TrapFormatter>,
+    /// The largest memory size in pages observed during the run. Atomic so that it can be
+    /// updated while host functions hold the read lock on the context data.
+    peak_memory_pages: AtomicU32,
+    /// How many extra times host functions charge their flat cost, on top of the first.
+    host_call_multiplier: u64,
+    /// How many times the maximum return size the return data may take
+    max_return_spans: u64,
+    /// The most calldata bytes external data requests may carry in total, or None for no limit
+    external_data_budget: Option<u64>,
+    /// The calldata bytes carried by the external data requests issued so far
+    external_data_requested: u64,
+    /// The gas charged for instantiating the code before it runs
+    instantiation_cost: InstantiationCost,
+    /// The most bytes host functions may read from and write to the memory in a run, or None
+    /// for no limit
+    io_budget: Option<u64>,
+    /// The bytes host functions have read from and written to the memory so far in the run.
+    /// Atomic so that it can be updated while host functions hold the read lock.
+    io_used: AtomicU64,
+    /// The most times a script may call `tick` in a run, or None for no limit
+    max_ticks: Option<u64>,
+    /// The `tick` calls so far in the run. Atomic so that it can be updated while host
+    /// functions hold the read lock.
+    ticks: AtomicU64,
+    /// External data already read during the run, by external ID and validator index. Behind
+    /// a mutex so that host functions can fill it while holding the read lock.
+    external_data_cache: Mutex<HashMap<(i64, i64), Vec<u8>>>,
+    /// The entries scripts keep with `map_set` during the run. Behind a mutex so that host
+    /// functions can update it while holding the read lock.
+    map: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    /// The gas each host function has charged in the run, by import name
+    host_gas: Mutex<HashMap<&'static str, u64>>,
+    /// The memory given to the instance, if the script imports its memory from the host
+    imported_memory: Option<Memory>,
+    /// Return data set by `set_return_data` and extended by `append_return_data`, sent to the
+    /// querier when the run ends
+    appended_return_data: Option<Vec<u8>>,
+    /// The number of segments making up the accumulated return data, one per set or append
+    return_segments: usize,
+    /// The most segments the return data may be made of, past which `append_return_data` fails
+    /// with `TooManyReturnSegments`
+    max_return_segments: usize,
+    /// What the run has produced so far
+    outcome: RunOutcome,
 }
 
+// Far more segments than scripts assembling their result piece by piece need, while keeping
+// the per-append bookkeeping bounded.
+const DEFAULT_MAX_RETURN_SEGMENTS: usize = 1024;
+// Enough for the lookup tables scripts build, e.g. one entry per symbol, while bounding the
+// host memory a run holds on to.
+pub const MAX_MAP_ENTRIES: usize = 1024;
+
 impl<Q: Querier> ContextData<Q> {
     pub fn new(querier: Q) -> Self {
-        ContextData::<Q> { wasmer_instance: None, querier }
+        ContextData::<Q> {
+            wasmer_instance: None,
+            querier,
+            deterministic: false,
+            view: false,
+            context_id: None,
+            count_instructions: false,
+            instructions: None,
+            trap_formatter: None,
+            peak_memory_pages: AtomicU32::new(0),
+            host_call_multiplier: 0,
+            max_return_spans: 1,
+            external_data_budget: None,
+            external_data_requested: 0,
+            instantiation_cost: InstantiationCost::default(),
+            io_budget: None,
+            io_used: AtomicU64::new(0),
+            max_ticks: None,
+            ticks: AtomicU64::new(0),
+            external_data_cache: Mutex::new(HashMap::new()),
+            map: Mutex::new(BTreeMap::new()),
+            host_gas: Mutex::new(HashMap::new()),
+            imported_memory: None,
+            appended_return_data: None,
+            return_segments: 0,
+            max_return_segments: DEFAULT_MAX_RETURN_SEGMENTS,
+            outcome: RunOutcome::default(),
+        }
+    }
+}
+
+/// Keeps an environment pointing to an instance; see `Environment::bind_instance`.
+#[must_use = "the environment is unbound from the instance as soon as the guard is dropped"]
+pub struct InstanceGuard<'a, Q>
+where
+    Q: Querier + 'static,
+{
+    env: &'a Environment<Q>,
+    instance: PhantomData<&'a Instance>,
+}
+
+impl<Q> Drop for InstanceGuard<'_, Q>
+where
+    Q: Querier + 'static,
+{
+    fn drop(&mut self) {
+        self.env.set_wasmer_instance(None);
+    }
+}
+
+// The globals the metering middleware adds to every instance it compiles.
+const METERING_GLOBALS: [&str; 2] =
+    ["wasmer_metering_remaining_points", "wasmer_metering_points_exhausted"];
+
+/// Values that stay the same for a whole run. Request values are read from the querier once,
+/// so host getters and gas accounting can use them without locking the context data.
+#[derive(Debug, Clone, Copy)]
+struct StaticContext {
+    /// Whether gas is tracked and charged at all
+    metered: bool,
+    span_size: i64,
+    max_return_size: i64,
+    ask_count: i64,
+    min_count: i64,
+}
+
+impl StaticContext {
+    fn new<Q: Querier>(querier: &Q, metered: bool) -> Self {
+        StaticContext {
+            metered,
+            span_size: querier.get_span_size(),
+            max_return_size: querier.get_max_return_size(),
+            ask_count: querier.get_ask_count(),
+            min_count: querier.get_min_count(),
+        }
     }
 }
 
@@ -49,11 +316,12 @@ where
     Q: Querier + 'static,
 {
     data: Arc<RwLock<ContextData<Q>>>,
+    static_context: Arc<StaticContext>,
 }
 
 impl<Q: Querier + 'static> Clone for Environment<Q> {
     fn clone(&self) -> Self {
-        Self { data: self.data.clone() }
+        Self { data: self.data.clone(), static_context: self.static_context.clone() }
     }
 }
 unsafe impl<Q: Querier> Send for Environment<Q> {}
@@ -64,9 +332,48 @@ where
     Q: Querier + 'static,
 {
     pub fn new(q: Q) -> Self {
-        Self { data: Arc::new(RwLock::new(ContextData::new(q))) }
+        Self::from_context_data(ContextData::new(q), true)
+    }
+
+    /// Creates an environment that never charges gas, for gas estimation and local testing.
+    /// Code run with it is compiled without the metering middleware.
+    pub fn new_unmetered(q: Q) -> Self {
+        Self::from_context_data(ContextData::new(q), false)
+    }
+
+    fn from_context_data(context_data: ContextData<Q>, metered: bool) -> Self {
+        let static_context = Arc::new(StaticContext::new(&context_data.querier, metered));
+        Self { data: Arc::new(RwLock::new(context_data)), static_context }
+    }
+
+    /// Returns the maximum span size value, without locking the context data.
+    pub fn get_span_size(&self) -> i64 {
+        self.static_context.span_size
+    }
+
+    /// Returns the maximum return data size, without locking the context data.
+    pub fn get_max_return_size(&self) -> i64 {
+        self.static_context.max_return_size
+    }
+
+    /// Returns the current "ask count" value, without locking the context data.
+    pub fn get_ask_count(&self) -> i64 {
+        self.static_context.ask_count
+    }
+
+    /// Returns the current "min count" value, without locking the context data.
+    pub fn get_min_count(&self) -> i64 {
+        self.static_context.min_count
+    }
+
+    pub fn is_metered(&self) -> bool {
+        self.static_context.metered
     }
 
+    /// Calls `callback` with the querier the environment was built with. Besides serving
+    /// host functions, this lets callers inspect a custom querier once a run is over, such as
+    /// state it accumulated while answering the script. The callback must not call back into
+    /// the environment's setters, which would deadlock on the context data lock.
     pub fn with_querier_from_context<C, R>(&self, callback: C) -> R
     where
         C: FnOnce(&Q) -> R,
@@ -74,6 +381,88 @@ where
         self.with_context_data(|context_data| callback(&context_data.querier))
     }
 
+    /// Returns the data span with the data id `eid` from validator index `vid`, asking the
+    /// querier only the first time it is read in a run.
+    pub(crate) fn get_external_data(&self, eid: i64, vid: i64) -> Result<Vec<u8>, Error> {
+        self.with_context_data(|context_data| {
+            let mut cache =
+                context_data.external_data_cache.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(data) = cache.get(&(eid, vid)) {
+                return Ok(data.clone());
+            }
+            let data = context_data.querier.get_external_data(eid, vid)?;
+            cache.insert((eid, vid), data.clone());
+            Ok(data)
+        })
+    }
+
+    /// Forgets the external data read so far, so that the next run asks the querier again.
+    pub(crate) fn clear_external_data_cache(&self) {
+        self.with_context_data(|context_data| {
+            context_data.external_data_cache.lock().unwrap_or_else(PoisonError::into_inner).clear()
+        })
+    }
+
+    /// Sets the value of the given key in the run's map, failing if the key is new and the
+    /// map already holds `MAX_MAP_ENTRIES` entries.
+    pub(crate) fn map_set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Error> {
+        self.with_context_data(|context_data| {
+            let mut map = context_data.map.lock().unwrap_or_else(PoisonError::into_inner);
+            if map.len() >= MAX_MAP_ENTRIES && !map.contains_key(&key) {
+                return Err(Error::MapEntriesLimitExceeded);
+            }
+            map.insert(key, value);
+            Ok(())
+        })
+    }
+
+    /// Returns the value of the given key in the run's map, or None if it was never set.
+    pub(crate) fn map_get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.with_context_data(|context_data| {
+            context_data.map.lock().unwrap_or_else(PoisonError::into_inner).get(key).cloned()
+        })
+    }
+
+    /// Empties the run's map, so that the next run starts without entries.
+    pub(crate) fn clear_map(&self) {
+        self.with_context_data(|context_data| {
+            context_data.map.lock().unwrap_or_else(PoisonError::into_inner).clear()
+        })
+    }
+
+    /// Starts counting the I/O budget anew, for the next run.
+    pub(crate) fn reset_io_used(&self) {
+        self.with_context_data(|context_data| context_data.io_used.store(0, Ordering::Relaxed))
+    }
+
+    /// Returns the gas each host function has charged in the last run, by import name, e.g.
+    /// `read_external_data`, to tell which imports dominate its cost. Host functions the run
+    /// never called have no entry, and unmetered runs leave the breakdown empty.
+    pub fn host_gas_breakdown(&self) -> HashMap<&'static str, u64> {
+        self.with_context_data(|context_data| {
+            context_data.host_gas.lock().unwrap_or_else(PoisonError::into_inner).clone()
+        })
+    }
+
+    pub(crate) fn record_host_gas(&self, name: &'static str, gas: u64) {
+        self.with_context_data(|context_data| {
+            let mut host_gas = context_data.host_gas.lock().unwrap_or_else(PoisonError::into_inner);
+            let total = host_gas.entry(name).or_insert(0);
+            *total = total.saturating_add(gas);
+        })
+    }
+
+    pub(crate) fn clear_host_gas(&self) {
+        self.with_context_data(|context_data| {
+            context_data.host_gas.lock().unwrap_or_else(PoisonError::into_inner).clear()
+        })
+    }
+
+    /// Starts counting `tick` calls anew, for the next run.
+    pub(crate) fn reset_ticks(&self) {
+        self.with_context_data(|context_data| context_data.ticks.store(0, Ordering::Relaxed))
+    }
+
     /// Creates a back reference from a contact to its partent instance
     pub fn set_wasmer_instance(&self, instance: Option<NonNull<Instance>>) {
         self.with_context_data_mut(|data| {
@@ -81,6 +470,310 @@ where
         })
     }
 
+    /// Points the environment to the given instance for as long as the returned guard lives.
+    /// Unlike `set_wasmer_instance`, the borrow keeps the instance alive while the environment
+    /// points to it, and dropping the guard clears the pointer, so the environment can never
+    /// hand a dropped instance to host functions.
+    pub fn bind_instance<'a>(&'a self, instance: &'a Instance) -> InstanceGuard<'a, Q> {
+        self.set_wasmer_instance(Some(NonNull::from(instance)));
+        InstanceGuard { env: self, instance: PhantomData }
+    }
+
+    /// Forbids host functions from returning floats, making them return fixed-point numbers instead.
+    pub fn set_deterministic(&self, deterministic: bool) {
+        self.with_context_data_mut(|data| {
+            data.deterministic = deterministic;
+        })
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.with_context_data(|data| data.deterministic)
+    }
+
+    /// Makes the run a view, which recomputes the result of `execute` from external data that
+    /// was already fetched. Asking for more external data then fails the script with
+    /// `RequestsNotAllowedInView`.
+    pub fn set_view(&self, view: bool) {
+        self.with_context_data_mut(|data| {
+            data.view = view;
+        })
+    }
+
+    pub fn is_view(&self) -> bool {
+        self.with_context_data(|data| data.view)
+    }
+
+    /// Tags the environment with an ID, e.g. that of the request it runs, which the outcome
+    /// carries so that runners handling many requests can correlate what each run produced.
+    pub fn set_context_id(&self, context_id: u64) {
+        self.with_context_data_mut(|data| {
+            data.context_id = Some(context_id);
+        })
+    }
+
+    pub fn context_id(&self) -> Option<u64> {
+        self.with_context_data(|data| data.context_id)
+    }
+
+    /// Makes runs count the Wasm operators they execute, reported by `instruction_count`.
+    /// Counting compiles the code with an extra middleware, bypassing the module cache, so it
+    /// is meant for tooling rather than validators. Off by default.
+    pub fn set_count_instructions(&self, count_instructions: bool) {
+        self.with_context_data_mut(|data| {
+            data.count_instructions = count_instructions;
+        })
+    }
+
+    pub(crate) fn counts_instructions(&self) -> bool {
+        self.with_context_data(|data| data.count_instructions)
+    }
+
+    /// Returns the Wasm operators the last run executed, or None if it did not count them.
+    pub fn instruction_count(&self) -> Option<u64> {
+        self.with_context_data(|data| data.instructions)
+    }
+
+    pub(crate) fn record_instructions(&self, instructions: Option<u64>) {
+        self.with_context_data_mut(|data| data.instructions = instructions)
+    }
+
+    /// Makes runs that trap record the message `formatter` gives for the trap in the outcome's
+    /// `trap_message`. Without a formatter, no message is recorded.
+    pub fn set_trap_formatter(&self, formatter: TrapFormatter) {
+        self.with_context_data_mut(|data| {
+            data.trap_formatter = Some(formatter);
+        })
+    }
+
+    pub(crate) fn format_trap(&self, trap: &RuntimeError) -> Option<String> {
+        self.with_context_data(|data| data.trap_formatter.as_ref().map(|formatter| formatter(trap)))
+    }
+
+    /// Makes every host function, except the `gas` counter, additionally charge its flat cost
+    /// `multiplier` times. Host calls cost the chain more than the same time spent in pure
+    /// compute, and this prices them accordingly. Zero by default.
+    pub fn set_host_call_multiplier(&self, multiplier: u64) {
+        self.with_context_data_mut(|data| {
+            data.host_call_multiplier = multiplier;
+        })
+    }
+
+    pub fn get_host_call_multiplier(&self) -> u64 {
+        self.with_context_data(|data| data.host_call_multiplier)
+    }
+
+    /// Lets the return data take up to `spans` times the maximum return size, which is the span
+    /// size unless the querier caps return data separately, for chains storing larger
+    /// results. Reads are still bounded by a single span. One by default.
+    pub fn set_max_return_spans(&self, spans: u64) {
+        self.with_context_data_mut(|data| {
+            data.max_return_spans = spans;
+        })
+    }
+
+    pub fn get_max_return_spans(&self) -> u64 {
+        self.with_context_data(|data| data.max_return_spans)
+    }
+
+    /// Caps the total calldata bytes of the external data requests a script may issue, bounding
+    /// what the chain has to fetch for it. Unlimited by default.
+    pub fn set_external_data_budget(&self, budget: u64) {
+        self.with_context_data_mut(|data| {
+            data.external_data_budget = Some(budget);
+        })
+    }
+
+    /// Caps the total bytes host functions may copy between the memory and the host in a run,
+    /// bounding the I/O work of scripts on top of the gas they pay for it. Unlimited by default.
+    pub fn set_io_budget(&self, budget: u64) {
+        self.with_context_data_mut(|data| {
+            data.io_budget = Some(budget);
+        })
+    }
+
+    /// Counts `len` bytes read from or written to the memory against the I/O budget.
+    pub(crate) fn spend_io_budget(&self, len: u64) -> Result<(), Error> {
+        self.with_context_data(|data| {
+            let used = data.io_used.fetch_add(len, Ordering::Relaxed).saturating_add(len);
+            if data.io_budget.is_some_and(|budget| used > budget) {
+                return Err(Error::IoBudgetExceeded);
+            }
+            Ok(())
+        })
+    }
+
+    /// Caps the times a script may call `tick` in a run, for scripts enforcing an iteration
+    /// limit on their loops. Unlimited by default.
+    pub fn set_max_ticks(&self, max_ticks: u64) {
+        self.with_context_data_mut(|data| {
+            data.max_ticks = Some(max_ticks);
+        })
+    }
+
+    /// Counts a `tick` call, returning the calls so far in the run including this one.
+    pub(crate) fn tick(&self) -> Result<u64, Error> {
+        self.with_context_data(|data| {
+            let ticks = data.ticks.fetch_add(1, Ordering::Relaxed).saturating_add(1);
+            if data.max_ticks.is_some_and(|max_ticks| ticks > max_ticks) {
+                return Err(Error::TickLimitExceeded);
+            }
+            Ok(ticks)
+        })
+    }
+
+    /// Charges the given cost for instantiating the code, once per instance, before its entry
+    /// point runs. A run whose gas limit does not cover it fails with `OutOfGasError`.
+    pub fn set_instantiation_cost(&self, cost: InstantiationCost) {
+        self.with_context_data_mut(|data| {
+            data.instantiation_cost = cost;
+        })
+    }
+
+    pub fn get_instantiation_cost(&self) -> InstantiationCost {
+        self.with_context_data(|data| data.instantiation_cost)
+    }
+
+    /// Counts `len` calldata bytes of a new external data request against the budget.
+    pub(crate) fn spend_external_data_budget(&self, len: u64) -> Result<(), Error> {
+        self.with_context_data_mut(|data| {
+            let requested = data.external_data_requested.saturating_add(len);
+            if data.external_data_budget.is_some_and(|budget| requested > budget) {
+                return Err(Error::DataBudgetExceeded);
+            }
+            data.external_data_requested = requested;
+            Ok(())
+        })
+    }
+
+    /// Limits how many `append_return_data` calls may make up the return data, so a script
+    /// cannot force excessive bookkeeping with many tiny segments. 1024 by default.
+    pub fn set_max_return_segments(&self, max_return_segments: usize) {
+        self.with_context_data_mut(|context_data| {
+            context_data.max_return_segments = max_return_segments;
+        })
+    }
+
+    /// Appends `data` to the accumulated return data, failing if the total would exceed `limit` bytes.
+    pub fn append_return_data(&self, data: &[u8], limit: usize) -> Result<(), Error> {
+        self.with_context_data_mut(|context_data| {
+            if context_data.return_segments >= context_data.max_return_segments {
+                return Err(Error::TooManyReturnSegments);
+            }
+            let buffer = context_data.appended_return_data.get_or_insert_with(Vec::new);
+            if buffer.len().saturating_add(data.len()) > limit {
+                return Err(Error::SpanTooSmallError);
+            }
+            buffer.extend_from_slice(data);
+            context_data.return_segments += 1;
+            Ok(())
+        })
+    }
+
+    /// Replaces the accumulated return data with `data`, which later appends extend.
+    pub fn replace_return_data(&self, data: Vec<u8>) {
+        self.with_context_data_mut(|context_data| {
+            context_data.appended_return_data = Some(data);
+            context_data.return_segments = 1;
+        })
+    }
+
+    /// Discards any accumulated return data.
+    pub fn clear_return_data(&self) {
+        self.with_context_data_mut(|context_data| {
+            context_data.appended_return_data = None;
+            context_data.return_segments = 0;
+        })
+    }
+
+    /// Takes the accumulated return data, or None if nothing was appended since the last clear.
+    pub fn take_return_data(&self) -> Option<Vec<u8>> {
+        self.with_context_data_mut(|context_data| {
+            context_data.return_segments = 0;
+            context_data.appended_return_data.take()
+        })
+    }
+
+    pub(crate) fn record_return_data(&self, data: Vec<u8>) {
+        self.with_context_data_mut(|context_data| context_data.outcome.return_data = Some(data))
+    }
+
+    pub(crate) fn record_failure(&self, failure: ScriptFailure) {
+        self.with_context_data_mut(|context_data| context_data.outcome.failure = Some(failure))
+    }
+
+    /// Whether the script has reported a failure in the run, which its return data may no
+    /// longer replace nor extend.
+    pub(crate) fn has_failure(&self) -> bool {
+        self.with_context_data(|context_data| context_data.outcome.failure.is_some())
+    }
+
+    /// Records an external data request together with the gas charged for it, which stays
+    /// provisional until the chain has fetched the data. Returns the index of the request
+    /// among those of the run, counting from zero.
+    pub(crate) fn record_external_request(
+        &self,
+        eid: i64,
+        did: i64,
+        calldata: Vec<u8>,
+        gas: u64,
+    ) -> usize {
+        self.with_context_data_mut(|context_data| {
+            let requests = &mut context_data.outcome.external_requests;
+            requests.push(ExternalRequest { eid, did, calldata });
+            context_data.outcome.refundable_gas =
+                context_data.outcome.refundable_gas.saturating_add(gas);
+            requests.len() - 1
+        })
+    }
+
+    /// Gives back `amount` gas charged for external data requests, returning the gas used by
+    /// the run after the refund.
+    ///
+    /// The chain calls this after a run, for the requests it ends up not fetching, e.g. because
+    /// they duplicate a request it already serves or are cancelled. A request is charged the
+    /// flat host call cost plus the per-byte cost of its calldata, and the chain may refund any
+    /// part of that. Refunding more than `RunOutcome::refundable_gas` fails with
+    /// `InvalidRefund`, leaving the gas used unchanged.
+    pub fn apply_refund(&self, amount: u64) -> Result<u64, Error> {
+        self.with_context_data_mut(|context_data| {
+            let outcome = &mut context_data.outcome;
+            if amount > outcome.refundable_gas {
+                return Err(Error::InvalidRefund);
+            }
+            outcome.refundable_gas -= amount;
+            outcome.gas_used = outcome.gas_used.saturating_sub(amount);
+            Ok(outcome.gas_used)
+        })
+    }
+
+    pub(crate) fn record_run(
+        &self,
+        gas_used: u64,
+        trap: Option<Error>,
+        trap_message: Option<String>,
+    ) {
+        self.with_context_data_mut(|context_data| {
+            context_data.outcome.gas_used = gas_used;
+            context_data.outcome.trap = trap;
+            context_data.outcome.trap_message = trap_message;
+        })
+    }
+
+    /// Consumes the environment after a run, returning everything the run produced.
+    pub fn finalize(self) -> RunOutcome {
+        let peak_memory_pages = self.peak_memory_pages();
+        let context_id = self.context_id();
+        let outcome =
+            self.with_context_data_mut(|context_data| std::mem::take(&mut context_data.outcome));
+        RunOutcome { peak_memory_pages, context_id, ..outcome }
+    }
+
+    /// Sets the memory the instance imports from the host, which `memory` falls back to when
+    /// the script exports none.
+    pub(crate) fn set_imported_memory(&self, memory: Option<Memory>) {
+        self.with_context_data_mut(|context_data| context_data.imported_memory = memory)
+    }
+
     pub fn with_wasmer_instance<C, R>(&self, callback: C) -> Result<R, Error>
     where
         C: FnOnce(&Instance) -> Result<R, Error>,
@@ -94,11 +787,13 @@ where
         })
     }
 
+    // A lock poisoned by an earlier panic still guards usable data: every write to it is a
+    // plain field assignment, so recover the guard rather than panicking again.
     fn with_context_data<C, R>(&self, callback: C) -> R
     where
         C: FnOnce(&ContextData<Q>) -> R,
     {
-        let guard = self.data.as_ref().read().unwrap();
+        let guard = self.data.as_ref().read().unwrap_or_else(PoisonError::into_inner);
         let context_data = guard.borrow();
         callback(context_data)
     }
@@ -107,41 +802,84 @@ where
     where
         C: FnOnce(&mut ContextData<Q>) -> R,
     {
-        let mut guard = self.data.as_ref().write().unwrap();
+        let mut guard = self.data.as_ref().write().unwrap_or_else(PoisonError::into_inner);
         let context_data = guard.borrow_mut();
         callback(context_data)
     }
 
-    pub fn get_gas_left(&self) -> u64 {
+    // Calls `callback` with the instance, failing if it was compiled without the metering
+    // middleware and so lacks the globals holding the gas left.
+    fn with_metered_instance<C, R>(&self, callback: C) -> Result<R, Error>
+    where
+        C: FnOnce(&Instance) -> R,
+    {
         self.with_wasmer_instance(|instance| {
-            Ok(match get_remaining_points(instance) {
-                MeteringPoints::Remaining(count) => count,
-                MeteringPoints::Exhausted => 0,
-            })
+            for global in METERING_GLOBALS {
+                if instance.exports.get_global(global).is_err() {
+                    return Err(Error::MeteringNotConfigured);
+                }
+            }
+            Ok(callback(instance))
         })
-        .expect("Wasmer instance is not set. This is a bug in the lifecycle.")
     }
 
-    pub fn set_gas_left(&self, new_value: u64) {
-        self.with_wasmer_instance(|instance| {
-            set_remaining_points(instance, new_value);
-            Ok(())
+    /// Same as `get_gas_left`, but fails instead of panicking if no instance is set or if the
+    /// instance is not metered.
+    pub fn try_get_gas_left(&self) -> Result<u64, Error> {
+        if !self.is_metered() {
+            return Ok(u64::MAX);
+        }
+        self.with_metered_instance(|instance| match get_remaining_points(instance) {
+            MeteringPoints::Remaining(count) => count,
+            MeteringPoints::Exhausted => 0,
         })
-        .expect("Wasmer instance is not set. This is a bug in the lifecycle.")
+    }
+
+    /// Same as `set_gas_left`, but fails instead of panicking if no instance is set or if the
+    /// instance is not metered.
+    pub fn try_set_gas_left(&self, new_value: u64) -> Result<(), Error> {
+        if !self.is_metered() {
+            return Ok(());
+        }
+        self.with_metered_instance(|instance| set_remaining_points(instance, new_value))
+    }
+
+    pub fn get_gas_left(&self) -> u64 {
+        self.try_get_gas_left()
+            .expect("Metered wasmer instance is not set. This is a bug in the lifecycle.")
+    }
+
+    pub fn set_gas_left(&self, new_value: u64) {
+        self.try_set_gas_left(new_value)
+            .expect("Metered wasmer instance is not set. This is a bug in the lifecycle.")
     }
 
     pub fn decrease_gas_left(&self, gas: u64) -> Result<(), Error> {
-        let gas_left = self.get_gas_left();
+        if !self.is_metered() {
+            return Ok(());
+        }
+        let gas_left = self.try_get_gas_left()?;
         if gas > gas_left {
             Err(Error::OutOfGasError)
         } else {
-            self.set_gas_left(gas_left.saturating_sub(gas));
-            Ok(())
+            self.try_set_gas_left(gas_left.saturating_sub(gas))
         }
     }
 
+    /// Captures the gas left of the current instance, for tooling that tries a branch of
+    /// execution and rolls back its gas cost with `restore`. Only gas is captured; memory and
+    /// everything the querier saw stay as they are.
+    pub fn snapshot(&self) -> Result<GasSnapshot, Error> {
+        Ok(GasSnapshot { gas_left: self.try_get_gas_left()? })
+    }
+
+    /// Sets the gas left back to what it was when `snapshot` was taken.
+    pub fn restore(&self, snapshot: GasSnapshot) -> Result<(), Error> {
+        self.try_set_gas_left(snapshot.gas_left)
+    }
+
     pub fn memory(&self) -> Result<Memory, Error> {
-        self.with_context_data(|data| match data.wasmer_instance {
+        let memory = self.with_context_data(|data| match data.wasmer_instance {
             Some(instance_ptr) => {
                 let instance_ref = unsafe { instance_ptr.as_ref() };
                 let mut memories: Vec<Memory> =
@@ -149,27 +887,32 @@ where
 
                 match memories.pop() {
                     Some(m) => Ok(m),
-                    None => Err(Error::MemoryOutOfBoundError),
+                    None => data.imported_memory.clone().ok_or(Error::BadMemorySectionError),
                 }
             }
             _ => Err(Error::BadMemorySectionError),
-        })
+        })?;
+        let pages = memory.size().0;
+        self.with_context_data(|data| data.peak_memory_pages.fetch_max(pages, Ordering::Relaxed));
+        Ok(memory)
+    }
+
+    /// Returns the largest memory size in Wasm pages observed so far.
+    pub fn peak_memory_pages(&self) -> u32 {
+        self.with_context_data(|data| data.peak_memory_pages.load(Ordering::Relaxed))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        io::{Read, Write},
-        process::Command,
-    };
-
-    use tempfile::NamedTempFile;
+    use crate::testing::wat_to_wasm;
     use wasmer::{imports, Singlepass, Store, Universal};
 
     use crate::{
         cache::{Cache, CacheOptions},
-        store::make_store,
+        calls::run_safe,
+        compile::compile,
+        store::{make_store_with_backend, make_unmetered_store, Backend},
     };
 
     use super::*;
@@ -210,23 +953,9 @@ mod tests {
         fn get_external_data(&self, _: i64, _: i64) -> Result<Vec<u8>, Error> {
             Ok(vec![1])
         }
-    }
-
-    fn wat2wasm(wat: impl AsRef<[u8]>) -> Vec<u8> {
-        let mut input_file = NamedTempFile::new().unwrap();
-        let mut output_file = NamedTempFile::new().unwrap();
-        input_file.write_all(wat.as_ref()).unwrap();
-        Command::new("wat2wasm")
-            .args(&[
-                input_file.path().to_str().unwrap(),
-                "-o",
-                output_file.path().to_str().unwrap(),
-            ])
-            .output()
-            .unwrap();
-        let mut wasm = Vec::new();
-        output_file.read_to_end(&mut wasm).unwrap();
-        wasm
+        fn get_random_seed(&self) -> Result<Vec<u8>, Error> {
+            Ok(vec![0xab; 32])
+        }
     }
 
     #[test]
@@ -235,6 +964,45 @@ mod tests {
         assert_eq!(300, env.with_querier_from_context(|querier| querier.get_span_size()));
     }
 
+    #[test]
+    fn test_env_static_getters() {
+        for env in [Environment::new(MockQuerier {}), Environment::new_unmetered(MockQuerier {})] {
+            assert_eq!(300, env.get_span_size());
+            assert_eq!(10, env.get_ask_count());
+            assert_eq!(8, env.get_min_count());
+            assert_eq!(env.get_span_size(), env.clone().get_span_size());
+        }
+        assert!(Environment::new(MockQuerier {}).is_metered());
+        assert!(!Environment::new_unmetered(MockQuerier {}).is_metered());
+    }
+
+    #[test]
+    fn test_env_poisoned_context_data() {
+        let env = Environment::new(MockQuerier {});
+        let _ = std::panic::catch_unwind(|| {
+            let _guard = env.data.write().unwrap();
+            panic!("panic while holding the context data");
+        });
+        assert!(env.data.is_poisoned());
+
+        env.set_deterministic(true);
+        assert!(env.is_deterministic());
+        let wasm = wat_to_wasm(
+            r#"(module
+            (func
+              )
+            (func
+              )
+            (memory (export "memory") 17)
+            (export "prepare" (func 0))
+            (export "execute" (func 1)))
+          "#,
+        );
+        let code = compile(&wasm).unwrap();
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        assert!(run_safe(&mut cache, &code, u64::MAX, true, env).is_ok());
+    }
+
     #[test]
     fn test_env_wasmer_instance() {
         let env = Environment::new(MockQuerier {});
@@ -243,7 +1011,7 @@ mod tests {
             env.with_wasmer_instance(|_| { Ok(()) }).unwrap_err()
         );
 
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
                 (func $execute (export "execute"))
                 (func $prepare (export "prepare"))
@@ -258,16 +1026,41 @@ mod tests {
         assert_eq!(Ok(()), env.with_wasmer_instance(|_| { Ok(()) }));
     }
 
+    #[test]
+    fn test_env_bind_instance() {
+        let env = Environment::new(MockQuerier {});
+        let wasm = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+                (memory (export "memory") 1)
+              )"#,
+        );
+        let store = make_store_with_backend(Backend::default());
+        {
+            let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+            let (instance, _) = cache.get_instance(&wasm, &store, &imports! {}).unwrap();
+            let _bound = env.bind_instance(&instance);
+            assert_eq!(Ok(1), env.memory().map(|memory| memory.size().0));
+            assert_eq!(Ok(()), env.try_set_gas_left(10));
+            assert_eq!(Ok(10), env.try_get_gas_left());
+        }
+        // The instance is gone, and so is the environment's pointer to it.
+        assert_eq!(Err(Error::BadMemorySectionError), env.memory().map(|_| ()));
+        assert_eq!(Err(Error::UninitializedContextData), env.try_get_gas_left());
+        assert_eq!(Err(Error::UninitializedContextData), env.try_set_gas_left(10));
+    }
+
     #[test]
     fn test_env_gas() {
         let env = Environment::new(MockQuerier {});
-        let wasm = wat2wasm(
+        let wasm = wat_to_wasm(
             r#"(module
                 (func $execute (export "execute"))
                 (func $prepare (export "prepare"))
               )"#,
         );
-        let store = make_store();
+        let store = make_store_with_backend(Backend::default());
         let import_object = imports! {};
         let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
         let (instance, _) = cache.get_instance(&wasm, &store, &import_object).unwrap();
@@ -282,4 +1075,52 @@ mod tests {
         assert_eq!(Ok(()), env.decrease_gas_left(3));
         assert_eq!(7, env.get_gas_left());
     }
+
+    #[test]
+    fn test_env_gas_snapshot() {
+        let env = Environment::new(MockQuerier {});
+        let wasm = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+              )"#,
+        );
+        let store = make_store_with_backend(Backend::default());
+        let mut cache = Cache::new(CacheOptions { cache_size: 10000 });
+        let (instance, _) = cache.get_instance(&wasm, &store, &imports! {}).unwrap();
+        env.set_wasmer_instance(Some(NonNull::from(&instance)));
+        env.set_gas_left(100);
+
+        assert_eq!(Ok(()), env.decrease_gas_left(10));
+        let snapshot = env.snapshot().unwrap();
+        assert_eq!(snapshot, GasSnapshot { gas_left: 90 });
+        assert_eq!(Ok(()), env.decrease_gas_left(50));
+        assert_eq!(40, env.get_gas_left());
+        assert_eq!(Ok(()), env.restore(snapshot));
+        assert_eq!(90, env.get_gas_left());
+
+        env.set_wasmer_instance(None);
+        assert_eq!(Err(Error::UninitializedContextData), env.snapshot());
+    }
+
+    #[test]
+    fn test_env_gas_without_metering() {
+        let env = Environment::new(MockQuerier {});
+        assert_eq!(Err(Error::UninitializedContextData), env.try_get_gas_left());
+
+        let wasm = wat_to_wasm(
+            r#"(module
+                (func $execute (export "execute"))
+                (func $prepare (export "prepare"))
+              )"#,
+        );
+        let store = make_unmetered_store();
+        let module = wasmer::Module::new(&store, &wasm).unwrap();
+        let instance = Instance::new(&module, &imports! {}).unwrap();
+        env.set_wasmer_instance(Some(NonNull::from(&instance)));
+
+        assert_eq!(Err(Error::MeteringNotConfigured), env.try_get_gas_left());
+        assert_eq!(Err(Error::MeteringNotConfigured), env.try_set_gas_left(10));
+        assert_eq!(Err(Error::MeteringNotConfigured), env.decrease_gas_left(1));
+    }
 }